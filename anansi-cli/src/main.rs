@@ -55,6 +55,9 @@ pub fn main() {
             "admin" => {
                 cargo_run(&mut args);
             }
+            "collect-static" => {
+                cargo_run(&mut args);
+            }
             "--version" => println!("ananc {}", VERSION),
             _ => usage(),
         }
@@ -100,7 +103,7 @@ fn make_view(args: &Vec<String>) {
 }
 
 fn usage() {
-    eprintln!("Anansi's project manager\n\nUSAGE:\n    ananc [OPTIONS] [SUBCOMMAND]\n\nOPTIONS:\n    --version\tPrint version info and exit\n\nIn addition to Cargo's commands, some others are:\n    app\t\t\tCreate an app\n    sql-migrate\t\tView SQL for migration files\n    make-migrations\tCreate migration files for the project\n    migrate\t\tApply migrations");
+    eprintln!("Anansi's project manager\n\nUSAGE:\n    ananc [OPTIONS] [SUBCOMMAND]\n\nOPTIONS:\n    --version\tPrint version info and exit\n\nIn addition to Cargo's commands, some others are:\n    app\t\t\tCreate an app\n    sql-migrate\t\tView SQL for migration files\n    make-migrations\tCreate migration files for the project\n    migrate\t\tApply migrations\n    collect-static\tCopy static files with hashed names into static_root");
 }
 
 fn new(args: &Vec<String>) {
@@ -263,7 +266,7 @@ impl Parser {
         } else {
             let mut view = String::from("{let mut _c = String::new();");
             view.push_str(&self.process(content));
-            view.push_str("Ok(anansi::web::Response::new(\"HTTP/1.1 200 OK\", _c.into_bytes()))}");
+            view.push_str("Ok(anansi::web::Response::ok(_c.into_bytes()))}");
             view
         };
         let out = &temp[..temp.find('.').unwrap()];
@@ -327,7 +330,7 @@ impl Parser {
             }
         }
         let args = format!("{}::Args", basename);
-        let mut ext = format!("{}::base(req, {}{{", basename, args);
+        let mut ext = format!("{}::base(&req, {}{{", basename, args);
         let mut s = String::from("{");
         for (name, src) in blocks {
             s.push_str(&format!("let _{} = {{{}}};", name, src));
@@ -551,6 +554,14 @@ impl Parser {
                 variable(&extra, &mut s, chars, view);
                 return;
             },
+            "static" => {
+                if extra != "\\\"" {
+                    skip(chars, '"');
+                }
+                let name = collect(chars, '"');
+                view.push_str(&format!("_c.push_str(&anansi::web::html_escape(&anansi::static_files::static_url({:?})));_c.push_str(\"", name));
+                return;
+            },
             _ => {
                 let mut c = s.chars();
                 if c.next().unwrap() == '{' {
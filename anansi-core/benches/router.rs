@@ -0,0 +1,97 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+use anansi_core::router::{RouteTree, get_capture, split_url};
+use anansi_core::web::{LEFT_BRACE, Parameters, SLASH, percent_decode};
+
+fn parse_query_string(qs: &str) -> Option<Vec<(String, String)>> {
+    let qv: Vec<&str> = qs.split('&').collect();
+    let mut queries = vec![];
+    for q in qv {
+        if let Some((key, value)) = q.split_once('=') {
+            if let Ok(k) = percent_decode(key) {
+                if let Ok(val) = percent_decode(value) {
+                    queries.push((k, val));
+                } else {
+                    return None;
+                }
+            } else {
+                return None;
+            }
+        } else {
+            return None;
+        }
+    }
+    Some(queries)
+}
+
+/// The linear `route_request` the tree replaced, as it was before it, except that
+/// it returns the index of the view and its parameters instead of calling it.
+fn route_request(dirs: &[&str], routes: &[(Vec<String>, usize)]) -> Option<(usize, Parameters)> {
+    for (patterns, view) in routes {
+        if patterns.len() == dirs.len() {
+            let mut b = true;
+            let mut params = Parameters::new();
+            for (pattern, dir) in patterns.iter().zip(dirs.iter()) {
+                match pattern.as_bytes()[0] {
+                    SLASH => if pattern == dir {
+                        continue;
+                    } else if let Some((d, qs)) = dir.split_once('?') {
+                        if pattern == d {
+                            if let Some(qv) = parse_query_string(qs) {
+                                for (k, v) in qv {
+                                    params.insert(k, v);
+                                }
+                                break;
+                            }
+                        }
+                        b = false;
+                        break;
+                    } else {
+                        b = false;
+                        break;
+                    },
+                    LEFT_BRACE => {
+                        params.insert(pattern[1..].to_string(), dir[1..].to_string());
+                    },
+                    _ => return None,
+                }
+            }
+            if b {
+                return Some((*view, params));
+            }
+        }
+    }
+    None
+}
+
+fn urls() -> Vec<String> {
+    let mut urls = vec![];
+    for n in 0..100 {
+        urls.push(format!("/app{}", n));
+        urls.push(format!("/app{}/{{id:int}}", n));
+        urls.push(format!("/app{}/{{id:int}}/edit", n));
+        urls.push(format!("/app{}/{{id:int}}/comments/{{comment:int}}", n));
+    }
+    urls
+}
+
+fn router(c: &mut Criterion) {
+    let urls = urls();
+    let linear: Vec<(Vec<String>, usize)> = urls.iter().enumerate().map(|(n, url)| (get_capture(url).unwrap(), n)).collect();
+    let mut tree = RouteTree::new();
+    for (n, url) in urls.iter().enumerate() {
+        *tree.entry(url).unwrap() = n;
+    }
+    for path in ["/app0", "/app50/42/edit", "/app99/42/comments/7", "/missing/path"] {
+        let dirs = split_url(path).unwrap();
+        c.bench_function(&format!("linear {}", path), |b| b.iter(|| {
+            route_request(black_box(&dirs), &linear)
+        }));
+        c.bench_function(&format!("tree {}", path), |b| b.iter(|| {
+            tree.find(black_box(&dirs))
+        }));
+    }
+}
+
+criterion_group!(benches, router);
+criterion_main!(benches);
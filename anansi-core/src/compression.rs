@@ -0,0 +1,134 @@
+use std::io::Write;
+
+use flate2::Compression;
+use flate2::write::GzEncoder;
+
+use crate::web::{Body, Response, Status};
+
+/// Brotli quality used for responses, trading some size for speed.
+const BROTLI_QUALITY: u32 = 5;
+
+/// Whether responses of a content type are worth compressing, leaving out
+/// images, archives and other types that are already compressed.
+pub fn is_compressible(ty: &str) -> bool {
+    let ty = ty.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+    ty.starts_with("text/")
+        || ty.ends_with("+json")
+        || ty.ends_with("+xml")
+        || matches!(ty.as_str(), "application/json" | "application/javascript" | "application/xml" | "image/svg+xml")
+}
+
+/// Compresses the body of a response with the first of the accepted encodings,
+/// if it has a compressible type and is at least `min_size` bytes long.
+///
+/// Static files are left alone, since they are served from their precompressed variants.
+pub fn compress(response: &mut Response, accepted: &[&str], min_size: usize) {
+    let status = response.status();
+    if status.is_bodiless() || status == Status::PartialContent {
+        return;
+    }
+    let headers = response.headers();
+    if headers.get("Content-Encoding").is_some() || headers.get("Accept-Ranges").is_some() {
+        return;
+    }
+    if headers.get("Cache-Control").is_some_and(|c| c.to_ascii_lowercase().contains("no-transform")) {
+        return;
+    }
+    if !headers.get("Content-Type").is_some_and(|ty| is_compressible(ty)) {
+        return;
+    }
+    let body = match response.body() {
+        Some(body) if body.as_slice().len() >= min_size => body.as_slice(),
+        _ => return,
+    };
+    let compressed = accepted.iter().find_map(|encoding| {
+        let compressed = match *encoding {
+            "br" => brotli(body),
+            "gzip" => gzip(body),
+            _ => return None,
+        };
+        compressed.ok().map(|compressed| (*encoding, compressed))
+    });
+    add_vary(response);
+    let (encoding, compressed) = match compressed {
+        Some(compressed) => compressed,
+        None => return,
+    };
+    let headers = response.headers_mut();
+    if let Some(etag) = headers.get("ETag").cloned() {
+        let etag = match etag.strip_suffix('"') {
+            Some(etag) => format!("{}-{}\"", etag, encoding),
+            None => etag,
+        };
+        headers.insert("ETag".to_string(), etag);
+    }
+    headers.insert("Content-Encoding".to_string(), encoding.to_string());
+    *response.body_mut() = Some(Body::new(compressed));
+}
+
+/// Adds `Accept-Encoding` to the `Vary` header, keeping the fields already listed.
+fn add_vary(response: &mut Response) {
+    let headers = response.headers_mut();
+    let vary = match headers.get("Vary") {
+        Some(vary) if vary.split(',').any(|f| f.trim() == "*" || f.trim().eq_ignore_ascii_case("Accept-Encoding")) => return,
+        Some(vary) => format!("{}, Accept-Encoding", vary),
+        None => "Accept-Encoding".to_string(),
+    };
+    headers.insert("Vary".to_string(), vary);
+}
+
+fn gzip(body: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::with_capacity(body.len() / 2), Compression::default());
+    encoder.write_all(body)?;
+    encoder.finish()
+}
+
+fn brotli(body: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut compressed = Vec::with_capacity(body.len() / 2);
+    {
+        let mut encoder = brotli::CompressorWriter::new(&mut compressed, 4096, BROTLI_QUALITY, 22);
+        encoder.write_all(body)?;
+    }
+    Ok(compressed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::GzDecoder;
+
+    use super::{compress, is_compressible};
+    use crate::web::{Response, Status};
+
+    #[test]
+    fn compressed_responses() {
+        assert!(is_compressible("text/html; charset=utf-8"));
+        assert!(is_compressible("application/ld+json"));
+        assert!(!is_compressible("image/png"));
+        assert!(!is_compressible("application/zip"));
+
+        let html = "<p>anansi</p>".repeat(100).into_bytes();
+        let mut response = Response::ok(html.clone());
+        compress(&mut response, &["gzip"], 1024);
+        assert_eq!(response.headers().get("Content-Encoding").unwrap(), "gzip");
+        assert_eq!(response.headers().get("Vary").unwrap(), "Accept-Encoding");
+        let mut decoded = vec![];
+        GzDecoder::new(response.body().as_ref().unwrap().as_slice()).read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, html);
+
+        let mut response = Response::json(b"{}".to_vec());
+        compress(&mut response, &["br"], 1024);
+        assert!(response.headers().get("Content-Encoding").is_none());
+
+        let mut response = Response::content(Status::Ok, "image/png", html.clone());
+        compress(&mut response, &["gzip"], 0);
+        assert!(response.headers().get("Vary").is_none());
+
+        let mut response = Response::ok(html.clone());
+        response.headers_mut().insert("Vary".to_string(), "Cookie".to_string());
+        compress(&mut response, &[], 1024);
+        assert!(response.headers().get("Content-Encoding").is_none());
+        assert_eq!(response.headers().get("Vary").unwrap(), "Cookie, Accept-Encoding");
+    }
+}
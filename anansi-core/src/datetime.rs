@@ -3,7 +3,7 @@ use std::time::{SystemTime, UNIX_EPOCH};
 use std::result;
 use std::error::Error;
 
-use crate::db::{invalid, Db, DbTypeInfo};
+use crate::db::{invalid, Arg, Db, DbTypeInfo};
 use crate::web::Result;
 use crate::records::{DataType, RecordField, ToSql};
 use sqlx::{Type, Decode, Database, database::HasValueRef};
@@ -143,9 +143,14 @@ impl DateTime {
     }
     pub fn to_gmt(&self) -> String {
         let months = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
-        format!("{}, {} {} {} {} GMT", self.date.day_of_week(), self.date.day, months[self.date.month as usize - 1], self.date.year, self.time)
+        format!("{}, {:02} {} {} {} GMT", self.date.day_of_week(), self.date.day, months[self.date.month as usize - 1], self.date.year, self.time)
     }
-    fn from_secs(mut s: u64) -> Self {
+    /// Formats the time as in the common log format, `10/Oct/2000:13:55:36 +0000`.
+    pub fn to_clf(&self) -> String {
+        let months = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+        format!("{:02}/{}/{}:{} +0000", self.date.day, months[self.date.month as usize - 1], self.date.year, self.time)
+    }
+    pub(crate) fn from_secs(mut s: u64) -> Self {
         let mut days = 365;
         let mut year = 0;
         while s > days * 86400 {
@@ -213,6 +218,9 @@ impl ToSql for DateTime {
     fn to_sql(&self) -> String {
         format!("'{} {}'", self.date, self.time)
     }
+    fn to_arg(&self) -> Arg {
+        Arg::Text(self.to_string())
+    }
 }
 
 impl fmt::Display for DateTime {
@@ -1,34 +1,105 @@
 use std::{str, thread};
+use std::sync::Arc;
 use std::io::{self, Read, ErrorKind};
 use std::marker::PhantomData;
 use std::borrow::Cow;
 use std::future::Future;
-use tokio::process::Command;
 
 use sqlx::{Decode, Type};
+use tracing::Instrument;
 
-use crate::records::{Record, DataType, BigInt, Objects, ToSql};
+use crate::records::{Record, DataType, BigInt, Objects, ToSql, Related};
 use crate::web::{Result, BaseRequest, BASE_DIR};
+use crate::dialect::{Dialect, DIALECT};
 
+// SQLite is the default backend, so it gives way to either of the others.
+#[cfg(all(feature = "postgres", feature = "mysql"))]
+compile_error!("only one of the \"postgres\" and \"mysql\" features can be enabled");
+#[cfg(not(any(feature = "sqlite", feature = "postgres", feature = "mysql")))]
+compile_error!("one of the \"sqlite\", \"postgres\" and \"mysql\" features must be enabled");
+
+#[cfg(all(feature = "sqlite", not(any(feature = "postgres", feature = "mysql"))))]
 pub type Db = sqlx::Sqlite;
+#[cfg(all(feature = "sqlite", not(any(feature = "postgres", feature = "mysql"))))]
 pub type DbTypeInfo = sqlx::sqlite::SqliteTypeInfo;
+#[cfg(all(feature = "sqlite", not(any(feature = "postgres", feature = "mysql"))))]
 type RawRow = sqlx::sqlite::SqliteRow;
 
+#[cfg(feature = "postgres")]
+pub type Db = sqlx::Postgres;
+#[cfg(feature = "postgres")]
+pub type DbTypeInfo = sqlx::postgres::PgTypeInfo;
+#[cfg(feature = "postgres")]
+type RawRow = sqlx::postgres::PgRow;
+
+#[cfg(feature = "mysql")]
+pub type Db = sqlx::MySql;
+#[cfg(feature = "mysql")]
+pub type DbTypeInfo = sqlx::mysql::MySqlTypeInfo;
+#[cfg(feature = "mysql")]
+type RawRow = sqlx::mysql::MySqlRow;
+
+/// Marks where a bound argument goes until the query is built, when it is
+/// replaced with the placeholder of the backend.
+const PARAM: char = '\u{1}';
+
+/// A value bound to a query parameter.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Arg {
+    Null,
+    Int(i64),
+    Bool(bool),
+    Text(String),
+}
+
+type Query<'q> = sqlx::query::Query<'q, Db, <Db as sqlx::database::HasArguments<'q>>::Arguments>;
+
+fn bind_args(mut query: Query<'_>, args: Vec<Arg>) -> Query<'_> {
+    for arg in args {
+        query = match arg {
+            Arg::Null => query.bind(None::<String>),
+            Arg::Int(n) => query.bind(n),
+            Arg::Bool(b) => query.bind(b),
+            Arg::Text(s) => query.bind(s),
+        };
+    }
+    query
+}
+
+/// Span around a query, so it shows up inside the request that ran it.
+fn sql_span(query: &str) -> tracing::Span {
+    tracing::debug_span!("sql", query)
+}
+
+#[derive(Clone)]
 pub struct DbRow {
-    row: sqlx::sqlite::SqliteRow,
+    row: Arc<RawRow>,
+    prefix: String,
 }
 
 impl DbRow {
+    fn new(row: RawRow) -> Self {
+        Self {row: Arc::new(row), prefix: String::new()}
+    }
+    /// The same row, reading the columns whose names start with `prefix`, such as
+    /// the ones of a record loaded with a join.
+    pub fn prefixed(&self, prefix: &str) -> Self {
+        Self {row: self.row.clone(), prefix: prefix.to_string()}
+    }
     pub fn try_get<'r, T>(&'r self, index: &str) -> Result<T>
         where T: Decode<'r, Db> + Type<Db>
     {
         use sqlx::Row;
-        self.row.try_get(index).or(Err(invalid()))
+        if self.prefix.is_empty() {
+            self.row.try_get(index).or(Err(invalid()))
+        } else {
+            self.row.try_get(format!("{}{}", self.prefix, index).as_str()).or(Err(invalid()))
+        }
     }
 }
 
 pub struct DbRowVec {
-    rows: Vec<sqlx::sqlite::SqliteRow>,
+    rows: Vec<RawRow>,
 }
 
 impl IntoIterator for DbRowVec {
@@ -50,7 +121,7 @@ impl<'a> Iterator for DbRowIntoIter {
     type Item = DbRow;
     fn next(&mut self) -> Option<DbRow> {
         match self.row_into_iter.next() {
-            Some(row) => Some(DbRow {row}),
+            Some(row) => Some(DbRow::new(row)),
             None => None,
         }
     }
@@ -60,29 +131,43 @@ impl<'a> Iterator for DbRowIntoIter {
 pub struct DbPool(pub(in crate) sqlx::Pool<Db>);
 
 impl DbPool {
+    /// Connects to `database.db` in the project directory, which requires the sqlite backend.
     pub async fn new() -> Result<Self> {
+        if DIALECT != Dialect::Sqlite {
+            return Err("database_url must be set for this database backend".into());
+        }
         let mut dir = String::new();
         BASE_DIR.with(|base| dir = format!("{}/{}", base, "database.db"));
-        match Self::connect(&dir).await {
-            Ok(p) => Ok(Self {0: p}),
-            Err(e) => {
-                Self::init_db(&dir).await;
-                Err(e)
-            },
+        Self::from_url(&dir).await
+    }
+    /// Connects to a database URL such as `postgres://user@localhost/app` or
+    /// `mysql://user@localhost/app`. SQLite databases are created if they are missing.
+    pub async fn from_url(url: &str) -> Result<Self> {
+        Ok(Self(Self::connect(url).await?))
+    }
+    async fn connect(url: &str) -> Result<sqlx::Pool<Db>> {
+        let options: <<Db as sqlx::Database>::Connection as sqlx::Connection>::Options = url.parse()?;
+        #[cfg(all(feature = "sqlite", not(any(feature = "postgres", feature = "mysql"))))]
+        let options = options.create_if_missing(true);
+        let mut pool_options = sqlx::pool::PoolOptions::<Db>::new()
+            .max_connections(thread::available_parallelism().map_or(4, |n| n.get()) as u32);
+        if let Some(setup) = DIALECT.session_setup() {
+            pool_options = pool_options.after_connect(move |conn| Box::pin(async move {
+                sqlx::Executor::execute(conn, setup).await?;
+                Ok(())
+            }));
         }
+        Ok(pool_options.connect_with(options).await?)
     }
-    async fn connect(dir: &str) -> Result<sqlx::Pool<Db>> {
-        sqlx::sqlite::SqlitePoolOptions::new()
-            .max_connections(thread::available_parallelism().unwrap().get() as u32)
-            .connect(&dir).await.or(Err(invalid()))
-    }
-    async fn init_db(dir: &str) {
-        let mut cmd = Command::new("sqlite3");
-        cmd.arg(dir);
-        cmd.arg("CREATE TABLE \"anansi_records\"(\n\t\"name\" text NOT NULL,\n\t\"schema\" text NOT NULL\n);\nCREATE TABLE anansi_migrations(\n\t\"id\" INT PRIMARY KEY,\n\t\"app\" TEXT NOT NULL,\n\t\"name\" TEXT NOT NULL,\n\t\"applied\" DATETIME NOT NULL\n);\n");
-        let mut child = cmd.spawn().expect("Failed to start cargo");
-        child.wait().await.expect("failed to wait on child");
-        println!("Initialized database");
+    /// Creates the tables anansi keeps track of records and migrations in if they
+    /// are missing, returning whether it did, in which case migrations should be applied.
+    pub async fn bootstrap(&self) -> Result<bool> {
+        if sqlx::query("SELECT 1 FROM anansi_migrations LIMIT 1").fetch_optional(&self.0).await.is_ok() {
+            return Ok(false);
+        }
+        sqlx::Executor::execute(&self.0, DIALECT.bootstrap().as_str()).await?;
+        tracing::info!("Initialized database");
+        Ok(true)
     }
     pub async fn transact<F: Future<Output = Result<O>>, O>(&self, future: F) -> F::Output {
         let tran = self.0.begin().await?;
@@ -92,8 +177,15 @@ impl DbPool {
         }
         res
     }
+    pub async fn close(&self) {
+        self.0.close().await;
+    }
     pub async fn query(&self, val: &str) -> Result<DbRowVec> {
-        Ok(DbRowVec {rows: sqlx::query(val).fetch_all(&self.0).await?})
+        Ok(DbRowVec {rows: sqlx::query(val).fetch_all(&self.0).instrument(sql_span(val)).await?})
+    }
+    /// Runs a query with the arguments bound to its placeholders, in order.
+    pub async fn query_with(&self, val: &str, args: Vec<Arg>) -> Result<DbRowVec> {
+        Ok(DbRowVec {rows: bind_args(sqlx::query(val), args).fetch_all(&self.0).instrument(sql_span(val)).await?})
     }
 }
 
@@ -213,6 +305,22 @@ pub fn percent_escape(s: &str) -> String {
     val
 }
 
+/// Quotes an identifier such as `table.column` for the database backend.
+pub fn quote(ident: &str) -> String {
+    DIALECT.quote(ident)
+}
+
+/// Turns a text argument into a `LIKE` pattern, escaping its wildcards.
+fn like_pattern(arg: Arg, prefix: &str, suffix: &str) -> Arg {
+    match arg {
+        Arg::Text(s) => {
+            let inner = s.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_");
+            Arg::Text(format!("{}{}{}", prefix, inner, suffix))
+        },
+        arg => arg,
+    }
+}
+
 pub fn escape(s: &str) -> String {
     let mut val = String::from("'");
     inner_escape(s, &mut val);
@@ -251,57 +359,109 @@ struct Statement<S: Record> {
     val: Builder<S>,
 }
 
+/// Caches a record loaded with a join on the object of the row.
+type Loader<M> = Arc<dyn Fn(&mut M, &DbRow) -> Result<()> + Send + Sync>;
+
+/// The loaders added with `select_related`.
+struct Loaders<M>(Vec<Loader<M>>);
+
+impl<M> Clone for Loaders<M> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<M> std::fmt::Debug for Loaders<M> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Loaders({})", self.0.len())
+    }
+}
+
+impl<M: Record> Loaders<M> {
+    /// Reads the object of a row along with the records joined to it.
+    fn load(&self, row: DbRow) -> Result<M> {
+        let mut m = M::get(row.clone())?;
+        for load in &self.0 {
+            load(&mut m, &row)?;
+        }
+        Ok(m)
+    }
+}
+
 #[derive(Debug)]
 pub struct Builder<B: Record> {
     start: String,
+    from: String,
     join: String,
     val: String,
+    args: Vec<Arg>,
+    related: Loaders<B>,
     m: PhantomData<B>,
 }
 
 impl<B: Record> Clone for Builder<B> {
     fn clone(&self) -> Self {
-        Self {start: self.start.clone(), join: self.join.clone(), val: self.val.clone(), m: PhantomData.clone()}
+        Self {start: self.start.clone(), from: self.from.clone(), join: self.join.clone(), val: self.val.clone(), args: self.args.clone(), related: self.related.clone(), m: PhantomData.clone()}
     }
 }
 
 impl<B: Record> Builder<B> {
     pub fn new() -> Self {
-        Self {start: String::new(), join: String::new(), val: String::new(), m: PhantomData}
+        Self::from(String::new())
     }
     fn from(start: String) -> Self {
-        Self {start, join: String::new(), val: String::new(), m: PhantomData}
+        Self {start, from: String::new(), join: String::new(), val: String::new(), args: vec![], related: Loaders(vec![]), m: PhantomData}
     }
     pub fn count(database: &str) -> Self {
-        let start = format!("SELECT COUNT(*) as count FROM {}", database);
+        let start = format!("SELECT COUNT(*) as count FROM {}", quote(database));
         Self::from(start)
     }
     pub fn select(columns: &[&str], database: &str) -> Self {
-        let mut start = format!("SELECT {}.{}", database, columns[0]);
+        let database = quote(database);
+        let mut start = format!("SELECT {}.{}", database, quote(columns[0]));
         if columns.len() > 1 {
             for column in &columns[1..] {
-                start.push_str(&format!(", {}.{}", database, column));
+                start.push_str(&format!(", {}.{}", database, quote(column)));
             }
         }
-        start.push_str(&format!(" FROM {}", database));
-        Self::from(start)
+        let mut b = Self::from(start);
+        b.from = format!(" FROM {}", database);
+        b
+    }
+    /// Adds an already quoted column to the selected ones, under another name.
+    pub fn select_as(mut self, column: &str, name: &str) -> Self {
+        self.start.push_str(&format!(", {} AS {}", column, quote(name)));
+        self
+    }
+    /// Joins the records a foreign key column refers to, selecting their columns
+    /// under a prefix and caching them on the field returned by `f`.
+    pub fn select_related<D: DataType, F: Related + 'static>(self, column: Column<B, D>, f: for<'a> fn(&'a mut B) -> &'a mut F) -> Self
+    where B: 'static {
+        let alias = format!("related{}", self.related.0.len());
+        let prefix = format!("{}__", alias);
+        let mut b = self.left_join_as(&<F::Record as Record>::table_name(), &alias, <F::Record as Record>::PK_NAME, &column.b.val);
+        for c in <F::Record as Record>::COLUMNS {
+            b = b.select_as(&quote(&format!("{}.{}", alias, c)), &format!("{}{}", prefix, c));
+        }
+        b.related.0.push(Arc::new(move |m, row| f(m).cache_row(row.prefixed(&prefix))));
+        b
     }
     pub fn insert_into(database: &str, columns: &[&str])  -> Self {
-        let mut start = format!("INSERT INTO {} ({}", database, columns[0]);
+        let mut start = format!("INSERT INTO {} ({}", quote(database), quote(columns[0]));
         if columns.len() > 1 {
             for column in &columns[1..] {
-                start.push_str(&format!(", {}", column));
+                start.push_str(&format!(", {}", quote(column)));
             }
         }
         start.push_str(") VALUES (");
         Self::from(start)
     }
     pub fn delete(database: &str)  -> Self {
-        let start = format!("DELETE FROM {}", database);
+        let start = format!("DELETE FROM {}", quote(database));
         Self::from(start)
     }
     pub fn update(database: &str)  -> Self {
-        let start = format!("UPDATE {} SET", database);
+        let start = format!("UPDATE {} SET", quote(database));
         Self::from(start)
     }
     pub fn push(&mut self, s: &str) {
@@ -311,9 +471,38 @@ impl<B: Record> Builder<B> {
         self.val.push_str(s);
         self
     }
+    /// Pushes the placeholder of an argument, which is bound when the query runs.
+    pub fn push_arg(&mut self, arg: Arg) {
+        if arg == Arg::Null {
+            self.val.push_str("NULL");
+        } else {
+            self.val.push(PARAM);
+            self.args.push(arg);
+        }
+    }
+    pub fn bind(mut self, arg: Arg) -> Self {
+        self.push_arg(arg);
+        self
+    }
+    /// Compares with `arg`, as `IS NULL` or `IS NOT NULL` for a null `=` or `<>`,
+    /// since `= NULL` matches no row.
+    pub fn compare(self, op: &str, arg: Arg) -> Self {
+        match (op, arg) {
+            ("=", Arg::Null) => self.push_str(" IS NULL"),
+            ("<>", Arg::Null) => self.push_str(" IS NOT NULL"),
+            (op, arg) => self.push_str(&format!(" {} ", op)).bind(arg),
+        }
+    }
     pub fn append(mut self, other: Self) -> Self {
         self.val.push_str(&other.val);
         self.join.push_str(&other.join);
+        self.args.extend(other.args);
+        self
+    }
+    /// Appends the clause of another builder and its arguments, without its joins.
+    fn append_clause(mut self, other: Self) -> Self {
+        self.val.push_str(&other.val);
+        self.args.extend(other.args);
         self
     }
     pub fn and(mut self) -> Self {
@@ -333,7 +522,13 @@ impl<B: Record> Builder<B> {
         self
     }
     pub fn inner_join(mut self, t1: &str, t2: &str, c: &str, d: &str) -> Self {
-        let s = format!(" INNER JOIN {} ON {1}.{2} = {0}.{3}", t1, t2, c, d);
+        let s = format!(" INNER JOIN {} ON {1}.{2} = {0}.{3}", quote(t1), quote(t2), quote(c), quote(d));
+        self.join.push_str(&s);
+        self
+    }
+    /// Joins a table under an alias on one of its columns being equal to an already quoted column.
+    pub fn left_join_as(mut self, table: &str, alias: &str, column: &str, on: &str) -> Self {
+        let s = format!(" LEFT JOIN {} AS {1} ON {1}.{2} = {3}", quote(table), quote(alias), quote(column), on);
         self.join.push_str(&s);
         self
     }
@@ -346,7 +541,21 @@ impl<B: Record> Builder<B> {
         self
     }
     pub fn val(self) -> String {
-        self.start + &self.join + &self.val
+        self.build().0
+    }
+    /// The statement with the placeholders of the backend, and the arguments bound to them.
+    pub fn build(self) -> (String, Vec<Arg>) {
+        let mut val = self.start + &self.from + &self.join;
+        let mut n = 0;
+        for c in self.val.chars() {
+            if c == PARAM {
+                n += 1;
+                val.push_str(&DIALECT.placeholder(n));
+            } else {
+                val.push(c);
+            }
+        }
+        (val, self.args)
     }
 }
 
@@ -428,10 +637,10 @@ impl<M: Record> DeleteWhose<M>  {
         if !req.raw().valid_token() {
             return Err(invalid());
         }
-        let mut val = self.stmt.val.val();
+        let (mut val, args) = self.stmt.val.build();
         val.push_str(";\n");
        
-        match sqlx::query(&val).execute(&req.raw().pool().0).await {
+        match bind_args(sqlx::query(&val), args).execute(&req.raw().pool().0).instrument(sql_span(&val)).await {
             Ok(_) => Ok(()),
             Err(e) => Err(Box::new(e)),
         }
@@ -455,6 +664,11 @@ impl<M: Record> Whose<M>  {
     pub fn or(self, arg: WhoseArg<M>) -> Self {
         Self{stmt: self.stmt.or(arg.b)}
     }
+    /// Loads the records a foreign key column refers to in the same query, like `Limit::select_related`.
+    pub fn select_related<D: DataType, F: Related + 'static>(self, column: Column<M, D>, f: for<'a> fn(&'a mut M) -> &'a mut F) -> Self
+    where M: 'static {
+        Self::from(self.stmt.val.select_related(column, f))
+    }
     pub async fn raw_get(self, pool: &DbPool) -> Result<M> {
         self.stmt.raw_get(pool).await
     }
@@ -487,6 +701,11 @@ impl<M: Record> OrderBy<M> {
     pub fn from(b: Builder<M>) -> Self {
         Self {stmt: Statement::from(b)}
     }
+    /// Loads the records a foreign key column refers to in the same query, like `Limit::select_related`.
+    pub fn select_related<D: DataType, F: Related + 'static>(self, column: Column<M, D>, f: for<'a> fn(&'a mut M) -> &'a mut F) -> Self
+    where M: 'static {
+        Self::from(self.stmt.val.select_related(column, f))
+    }
     pub async fn get<B: BaseRequest>(self, req: &B) -> Result<M> {
         self.stmt.get(req).await
     }
@@ -535,33 +754,27 @@ impl<S: Record> Statement<S> {
         Self {val}
     }
     fn order_by(self, arg: OrderByArg<S>) -> OrderBy<S> {
-        OrderBy::from(self.val.push_str(&format!(" ORDER BY {}", arg.b.val())))
+        OrderBy::from(self.val.push_str(" ORDER BY ").append_clause(arg.b))
     }
     fn order_by_count(self, arg: OrderByArg<S>) -> OrderByCount<S> {
-        OrderByCount::from(self.val.push_str(&format!(" ORDER BY {}", arg.b.val())))
+        OrderByCount::from(self.val.push_str(" ORDER BY ").append_clause(arg.b))
     }
     fn group_by_count<D: DataType>(self, arg: Column<S, D>) -> GroupByCount<S> {
-        GroupByCount::from(self.val.push_str(&format!(" GROUP BY {}", arg.b.val)))
+        GroupByCount::from(self.val.push_str(" GROUP BY ").append_clause(arg.b))
     }
-    fn and(mut self, val: Builder<S>) -> Self {
-        let s = format!(" AND {}", val.val);
-        self.val.val.push_str(&s);
-        self.val.join.push_str(&val.join);
-        self
+    fn and(self, val: Builder<S>) -> Self {
+        Self {val: self.val.push_str(" AND ").append(val)}
     }
-    fn or(mut self, val: Builder<S>) -> Self {
-        let s = format!(" OR {}", val.val);
-        self.val.val.push_str(&s);
-        self.val.join.push_str(&val.join);
-        self
+    fn or(self, val: Builder<S>) -> Self {
+        Self {val: self.val.push_str(" OR ").append(val)}
     }
     pub async fn raw_get_count(self, pool: &DbPool) -> Result<u32> {
         use sqlx::Row;
-        let mut val = self.val.val();
+        let (mut val, args) = self.val.build();
         val.push_str(";\n");
 
-        match sqlx::query(&val).fetch_one(&pool.0).await {
-            Ok(row) => Ok(row.try_get("count")?),
+        match bind_args(sqlx::query(&val), args).fetch_one(&pool.0).instrument(sql_span(&val)).await {
+            Ok(row) => Ok(row.try_get::<i64, _>("count")?.try_into()?),
             Err(_) => Err(invalid()),
         }
     }
@@ -569,11 +782,12 @@ impl<S: Record> Statement<S> {
         self.raw_get_count(req.raw().pool()).await
     }
     async fn raw_get(self, pool: &DbPool) -> Result<S> {
-        let mut val = self.val.val();
+        let related = self.val.related.clone();
+        let (mut val, args) = self.val.build();
         val.push_str(";\n");
 
-        match sqlx::query(&val).fetch_one(&pool.0).await {
-            Ok(row) => S::get(DbRow{row}),
+        match bind_args(sqlx::query(&val), args).fetch_one(&pool.0).instrument(sql_span(&val)).await {
+            Ok(row) => related.load(DbRow::new(row)),
             Err(_) => Err(invalid()),
         }
     }
@@ -604,59 +818,72 @@ impl<M: Record, T: ToSql> Clone for Column<M, T> {
 
 impl<M: Record, D: DataType<T = String>> Column<M, D> {
     pub fn contains<'a, U: ToSql + PartialEq<&'a str>>(self, u: U) -> WhoseArg<M> {
-        WhoseArg::from(self.b.push_str(&format!(" LIKE '%{}%'", percent_escape(&u.to_sql()))))
+        WhoseArg::from(self.b.push_str(" LIKE ").bind(like_pattern(u.to_arg(), "%", "%")).push_str(" ESCAPE '\\'"))
     }
     pub fn icontains<'a, U: ToSql + std::fmt::Display + PartialEq<&'a str>>(mut self, u: U) -> WhoseArg<M> {
-        self.b.val = format!("LOWER({}) LIKE LOWER('%{}%')", self.b.val, percent_escape(&u.to_string()));
+        self.b.val = DIALECT.ilike(&self.b.val, &PARAM.to_string());
+        self.b.args.push(like_pattern(u.to_arg(), "%", "%"));
         WhoseArg::from(self.b)
     }
     pub fn iexact<'a, U: ToSql + PartialEq<&'a str>>(mut self, u: U) -> WhoseArg<M> {
-        self.b.val = format!("LOWER({}) = LOWER({})", self.b.val, u.to_sql());
+        self.b.val = format!("LOWER({}) = LOWER({})", self.b.val, PARAM);
+        self.b.args.push(u.to_arg());
         WhoseArg::from(self.b)
     }
     pub fn starts_with<'a, U: ToSql + PartialEq<&'a str>>(self, u: U) -> WhoseArg<M> {
-        WhoseArg::from(self.b.push_str(&format!(" LIKE \"{}%\"", percent_escape(&u.to_sql()))))
+        WhoseArg::from(self.b.push_str(" LIKE ").bind(like_pattern(u.to_arg(), "", "%")).push_str(" ESCAPE '\\'"))
     }
     pub fn ends_with<'a, U: ToSql + PartialEq<&'a str>>(self, u: U) -> WhoseArg<M> {
-        WhoseArg::from(self.b.push_str(&format!(" LIKE \"%{}\"", percent_escape(&u.to_sql()))))
+        WhoseArg::from(self.b.push_str(" LIKE ").bind(like_pattern(u.to_arg(), "%", "")).push_str(" ESCAPE '\\'"))
     }
 }
 
 impl<M: Record, D: DataType> Column<M, D> {
     pub fn new(s: &str) -> Self {
-        let b = Builder::new().push_str(s);
+        let b = Builder::new().push_str(&quote(s));
         Self {b, t: PhantomData}
     }
     pub fn from(b: Builder<M>) -> Self {
         Self {b, t: PhantomData}
     }
+    /// The quoted name of the column.
+    pub fn name(&self) -> &str {
+        &self.b.val
+    }
     pub fn eq<U: ToSql + PartialEq<D::T>>(self, u: U) -> WhoseArg<M> {
-        WhoseArg::from(self.b.push_str(&format!(" = {}", u.to_sql())))
+        WhoseArg::from(self.b.compare("=", u.to_arg()))
     }
     pub fn neq<U: ToSql + PartialEq<D::T>>(self, u: U) -> WhoseArg<M> {
-        WhoseArg::from(self.b.push_str(&format!(" <> {}", u.to_sql())))
+        WhoseArg::from(self.b.compare("<>", u.to_arg()))
+    }
+    pub fn is_null(self) -> WhoseArg<M> {
+        WhoseArg::from(self.b.compare("=", Arg::Null))
+    }
+    pub fn is_not_null(self) -> WhoseArg<M> {
+        WhoseArg::from(self.b.compare("<>", Arg::Null))
     }
     pub fn gt<U: ToSql + PartialEq<D::T>>(self, u: U) -> WhoseArg<M> {
-        WhoseArg::from(self.b.push_str(&format!(" > {}", u.to_sql())))
+        WhoseArg::from(self.b.push_str(" > ").bind(u.to_arg()))
     }
     pub fn lt<U: ToSql + PartialEq<D::T>>(self, u: U) -> WhoseArg<M> {
-        WhoseArg::from(self.b.push_str(&format!(" < {}", u.to_sql())))
+        WhoseArg::from(self.b.push_str(" < ").bind(u.to_arg()))
     }
     pub fn gte<U: ToSql + PartialEq<D::T>>(self, u: U) -> WhoseArg<M> {
-        WhoseArg::from(self.b.push_str(&format!(" >= {}", u.to_sql())))
+        WhoseArg::from(self.b.push_str(" >= ").bind(u.to_arg()))
     }
     pub fn lte<U: ToSql + PartialEq<D::T>>(self, u: U) -> WhoseArg<M> {
-        WhoseArg::from(self.b.push_str(&format!(" <= {}", u.to_sql())))
+        WhoseArg::from(self.b.push_str(" <= ").bind(u.to_arg()))
     }
     pub fn is_in<U: ToSql + PartialEq<D::T>>(mut self, v: &Vec<U>) -> WhoseArg<M> {
         self.b.push(" IN(");
         let mut i = 0;
         for t in v {
             if i > 0 {
-                self.b.push(&format!(", {}", t.to_sql()));
+                self.b.push(", ");
             } else {
-                self.b.push(&format!(" {}", t.to_sql()));
+                self.b.push(" ");
             }
+            self.b.push_arg(t.to_arg());
             i += 1;
         }
         WhoseArg::from(self.b.push_str(")"))
@@ -672,7 +899,9 @@ impl<M: Record, D: DataType> Column<M, D> {
         b.push(&format!("CASE {}", self.b.val));
         let mut i = 0;
         for d in v {
-            b.push(&format!(" WHEN {} THEN {i}", d.to_sql()));
+            b.push(" WHEN ");
+            b.push_arg(d.to_arg());
+            b.push(&format!(" THEN {i}"));
             i += 1;
         }
         OrderByArg {b: b.push_str(" END")}
@@ -691,19 +920,26 @@ impl<U: Record> Update<U> {
         Self {val, count}
     }
     pub fn set<D: DataType>(mut self, name: &str, data: &D) -> Self {
-        let s = data.to_sql();
         self.count += 1;
         let val = if self.count > 1 {
-            self.val.push_str(&format!(", {} = {}", name, s))
+            self.val.push_str(&format!(", {} = ", quote(name)))
         } else {
-            self.val.push_str(&format!(" {} = {}", name, s))
-        };
+            self.val.push_str(&format!(" {} = ", quote(name)))
+        }.bind(data.to_arg());
         Self {val, count: self.count}
     }
     pub fn pk<D: DataType + std::fmt::Display>(self, name: &str, id: D) -> Self {
-        let val = self.val.push_str(&format!(" WHERE {} = {}", name, id));
+        let val = self.val.push_str(&format!(" WHERE {} = ", quote(name))).bind(id.to_arg());
         Self {val, count: self.count}
     }
+    /// Updates the row and returns the given columns of it.
+    pub async fn raw_update_returning(self, columns: &[&str], pool: &DbPool) -> Result<DbRow> {
+        let (mut val, args) = self.val.build();
+        val.push_str(&DIALECT.returning(columns)?);
+        val.push_str(";\n");
+        let row = bind_args(sqlx::query(&val), args).fetch_one(&pool.0).instrument(sql_span(&val)).await?;
+        Ok(DbRow::new(row))
+    }
     pub async fn update<B: BaseRequest>(self, req: &B) -> Result<()> {
         if !req.raw().valid_token() {
             return Err(invalid());
@@ -711,9 +947,9 @@ impl<U: Record> Update<U> {
         self.raw_update(req.raw().pool()).await
     }
     pub async fn raw_update(self, pool: &DbPool) -> Result<()> {
-        let mut val = self.val.val();
+        let (mut val, args) = self.val.build();
         val.push_str(";\n");
-        match sqlx::query(&val).execute(&pool.0).await {
+        match bind_args(sqlx::query(&val), args).execute(&pool.0).instrument(sql_span(&val)).await {
             Ok(_) => Ok(()),
             Err(e) => Err(Box::new(e)),
         }
@@ -731,12 +967,11 @@ impl<I: Record> Insert<I> {
         Self {val, n: 0}
     }
     pub fn value<D: DataType>(mut self, data: &D) -> Self {
-        let s = data.to_sql();
         let val = if self.n > 0 {
-            self.val.push_str(&format!(", {}", s))
+            self.val.push_str(", ")
         } else {
-            self.val.push_str(&format!(" {}", s))
-        };
+            self.val.push_str(" ")
+        }.bind(data.to_arg());
         self.n += 1;
         Self {val, n: self.n}
     }
@@ -747,10 +982,19 @@ impl<I: Record> Insert<I> {
             Err(invalid())
         }
     }
+    /// Inserts the row and returns the given columns of it, such as ones with defaults.
+    pub async fn raw_save_returning(self, columns: &[&str], pool: &DbPool) -> Result<DbRow> {
+        let (mut val, args) = self.val.build();
+        val.push(')');
+        val.push_str(&DIALECT.returning(columns)?);
+        val.push_str(";\n");
+        let row = bind_args(sqlx::query(&val), args).fetch_one(&pool.0).instrument(sql_span(&val)).await?;
+        Ok(DbRow::new(row))
+    }
     pub async fn raw_save(self, pool: &DbPool) -> Result<()> {
-        let mut val = self.val.val();
+        let (mut val, args) = self.val.build();
         val.push_str(");\n");
-        match sqlx::query(&val).execute(&pool.0).await {
+        match bind_args(sqlx::query(&val), args).execute(&pool.0).instrument(sql_span(&val)).await {
             Ok(_) => {
                 Ok(())
             },
@@ -765,9 +1009,9 @@ pub async fn delete_from<B: BaseRequest>(table: &str, table_id: &str, id: BigInt
     if !req.raw().valid_token() {
         return Err(invalid());
     }
-    let val = format!("DELETE FROM {} WHERE {} = {};\n", table, table_id, id);
+    let val = format!("DELETE FROM {} WHERE {} = {};\n", quote(table), quote(table_id), DIALECT.placeholder(1));
    
-    match sqlx::query(&val).execute(&req.raw().pool().0).await {
+    match sqlx::query(&val).bind(id.as_i64()).execute(&req.raw().pool().0).instrument(sql_span(&val)).await {
         Ok(_) => Ok(()),
         Err(e) => Err(Box::new(e)),
     }
@@ -781,19 +1025,37 @@ impl<M: Record> Limit<M> {
     pub fn from(val: Builder<M>) -> Self {
         Self {val}
     }
+    /// Loads the records a foreign key column refers to in the same query, caching
+    /// them on the field returned by `f`, which can also be a nullable foreign key.
+    ///
+    /// ```ignore
+    /// let posts = Post::limit(10).select_related(post::author(), |p| &mut p.author).query(&req).await?;
+    /// ```
+    pub fn select_related<D: DataType, F: Related + 'static>(self, column: Column<M, D>, f: for<'a> fn(&'a mut M) -> &'a mut F) -> Self
+    where M: 'static {
+        Self {val: self.val.select_related(column, f)}
+    }
     pub async fn query<B: BaseRequest>(self, req: &B) -> Result<Objects<M>> {
         self.raw_query(req.raw().pool()).await
     }
-    async fn raw_query(self, pool: &DbPool) -> Result<Objects<M>> {
-        let mut val = self.val.val();
+    pub async fn raw_query(self, pool: &DbPool) -> Result<Objects<M>> {
+        let related = self.val.related.clone();
+        let (mut val, args) = self.val.build();
         val.push_str(";\n");
 
-        match sqlx::query(&val).fetch_all(&pool.0).await {
+        match bind_args(sqlx::query(&val), args).fetch_all(&pool.0).instrument(sql_span(&val)).await {
             Ok(rows) => {
-                M::from(DbRowVec {rows})
+                if related.0.is_empty() {
+                    return M::from(DbRowVec {rows});
+                }
+                let mut objects = Objects::new();
+                for row in rows {
+                    objects.push(related.load(DbRow::new(row))?);
+                }
+                Ok(objects)
             },
             Err(e) => {
-                println!("{}", e);
+                tracing::error!("{}", e);
                 Err(invalid())
             },
         }
@@ -813,13 +1075,13 @@ impl<M: Record> LimitCount<M> {
     }
     async fn raw_query(self, pool: &DbPool) -> Result<Vec<u32>> {
         use sqlx::Row;
-        let mut val = self.val.val();
+        let (mut val, args) = self.val.build();
         val.push_str(";\n");
         let mut v = vec![];
-        match sqlx::query(&val).fetch_all(&pool.0).await {
+        match bind_args(sqlx::query(&val), args).fetch_all(&pool.0).instrument(sql_span(&val)).await {
             Ok(rows) => {
                 for row in rows {
-                    v.push(row.try_get("count")?);
+                    v.push(row.try_get::<i64, _>("count")?.try_into()?);
                 }
                 Ok(v)
             },
@@ -827,3 +1089,67 @@ impl<M: Record> LimitCount<M> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    mod init {
+        pub const APP_NAME: &str = "test";
+    }
+
+    mod records {
+        use crate::records::{BigInt, ForeignKey, VarChar};
+        use crate::Record;
+
+        #[derive(Clone, Debug, Record)]
+        pub struct Author {
+            #[field(primary_key = "true", default_fn = "anansi::records::generate_id")]
+            pub id: BigInt,
+            pub nickname: Option<VarChar<40>>,
+        }
+
+        impl<B: crate::web::BaseRequest> crate::records::Relate<B> for Author {}
+
+        #[derive(Record)]
+        pub struct Book {
+            #[field(primary_key = "true", default_fn = "anansi::records::generate_id")]
+            pub id: BigInt,
+            pub author: Option<ForeignKey<Author>>,
+        }
+
+        impl<B: crate::web::BaseRequest> crate::records::Relate<B> for Book {}
+    }
+
+    use records::{author, book, Author, Book};
+    use crate::records::{BigInt, Record};
+    use super::{quote, Arg, Builder, DIALECT};
+
+    #[test]
+    fn null_comparisons() {
+        let nickname = quote("nickname");
+        let (sql, args) = Builder::<Author>::new().push_str(&nickname).compare("=", Arg::Null).build();
+        assert_eq!(sql, format!("{} IS NULL", nickname));
+        assert!(args.is_empty());
+        let (sql, args) = Builder::<Author>::new().push_str(&nickname).compare("<>", Arg::Null).build();
+        assert_eq!(sql, format!("{} IS NOT NULL", nickname));
+        assert!(args.is_empty());
+
+        assert_eq!(author::nickname().is_null().builder().val(), format!("{} IS NULL", quote("test_author.nickname")));
+        let (sql, args) = author::nickname().eq("ann").builder().build();
+        assert_eq!(sql, format!("{} = {}", quote("test_author.nickname"), DIALECT.placeholder(1)));
+        assert!(args == vec![Arg::Text("ann".to_string())]);
+    }
+
+    #[test]
+    fn select_related_aliases() {
+        let join = format!("LEFT JOIN {} AS {} ON {} = {}", quote("test_author"), quote("related0"), quote("related0.id"), quote("test_book.author"));
+        let columns = format!("{} AS {}, {} AS {}", quote("related0.id"), quote("related0__id"), quote("related0.nickname"), quote("related0__nickname"));
+        let sql = Book::whose(book::id().eq(BigInt::new(1))).select_related(book::author(), |b| &mut b.author).stmt.val.val();
+        assert!(sql.contains(&join), "{}", sql);
+        assert!(sql.contains(&columns), "{}", sql);
+        let sql = Book::order_by(book::id().asc()).select_related(book::author(), |b| &mut b.author).stmt.val.val();
+        assert!(sql.contains(&join), "{}", sql);
+        let sql = Book::limit(5).select_related(book::author(), |b| &mut b.author).val.val();
+        assert!(sql.contains(&join), "{}", sql);
+        assert!(book::author().name().ends_with(&quote("author")));
+    }
+}
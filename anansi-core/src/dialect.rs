@@ -0,0 +1,122 @@
+use crate::web::Result;
+
+/// The SQL that differs between database backends.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Dialect {
+    Sqlite,
+    Postgres,
+    Mysql,
+}
+
+/// The dialect of the backend selected with a cargo feature.
+#[cfg(all(feature = "sqlite", not(any(feature = "postgres", feature = "mysql"))))]
+pub const DIALECT: Dialect = Dialect::Sqlite;
+#[cfg(feature = "postgres")]
+pub const DIALECT: Dialect = Dialect::Postgres;
+#[cfg(feature = "mysql")]
+pub const DIALECT: Dialect = Dialect::Mysql;
+
+impl Dialect {
+    /// Quotes an identifier, quoting each part of a name such as `table.column` separately.
+    pub fn quote(self, ident: &str) -> String {
+        let parts: Vec<String> = match self {
+            Self::Mysql => ident.split('.').map(|part| format!("`{}`", part.replace('`', "``"))).collect(),
+            _ => ident.split('.').map(|part| format!("\"{}\"", part.replace('"', "\"\""))).collect(),
+        };
+        parts.join(".")
+    }
+    /// The placeholder of the `n`th bound parameter, counting from 1.
+    pub fn placeholder(self, n: usize) -> String {
+        match self {
+            Self::Sqlite | Self::Mysql => "?".to_string(),
+            Self::Postgres => format!("${}", n),
+        }
+    }
+    /// A clause that makes `INSERT` and `UPDATE` statements return columns of the changed rows.
+    pub fn returning(self, columns: &[&str]) -> Result<String> {
+        if self == Self::Mysql {
+            return Err("RETURNING is not supported by mysql".into());
+        }
+        let columns: Vec<String> = columns.iter().map(|c| self.quote(c)).collect();
+        Ok(format!(" RETURNING {}", columns.join(", ")))
+    }
+    /// A case-insensitive `LIKE` of a column and a pattern literal.
+    pub fn ilike(self, column: &str, pattern: &str) -> String {
+        match self {
+            Self::Sqlite | Self::Mysql => format!("LOWER({}) LIKE LOWER({}) ESCAPE '\\'", column, pattern),
+            Self::Postgres => format!("{} ILIKE {} ESCAPE '\\'", column, pattern),
+        }
+    }
+    /// The column type of a record field type.
+    ///
+    /// Postgres and MySQL keep `DateTime` as text in the format it is read from.
+    pub fn column_type(self, ty: &str) -> String {
+        match (self, ty) {
+            (Self::Postgres, "datetime") => "text".to_string(),
+            (Self::Mysql, "datetime") => "varchar(19)".to_string(),
+            _ => ty.to_string(),
+        }
+    }
+    /// The current UTC time in the format of `DateTime`.
+    pub fn now(self) -> &'static str {
+        match self {
+            Self::Sqlite => "strftime('%Y-%m-%d %H:%M:%S', 'now')",
+            Self::Postgres => "to_char(now() AT TIME ZONE 'UTC', 'YYYY-MM-DD HH24:MI:SS')",
+            Self::Mysql => "DATE_FORMAT(UTC_TIMESTAMP(), '%Y-%m-%d %H:%i:%s')",
+        }
+    }
+    /// Options ending a `CREATE TABLE` statement.
+    ///
+    /// MySQL tables use InnoDB, the engine that enforces foreign keys.
+    pub fn table_options(self) -> &'static str {
+        match self {
+            Self::Mysql => " ENGINE=InnoDB",
+            _ => "",
+        }
+    }
+    /// A statement run on each new connection.
+    ///
+    /// MySQL is made to read backslashes in string literals as they are, like the other backends.
+    pub fn session_setup(self) -> Option<&'static str> {
+        match self {
+            Self::Mysql => Some("SET SESSION sql_mode = CONCAT(@@sql_mode, ',NO_BACKSLASH_ESCAPES')"),
+            _ => None,
+        }
+    }
+    /// Creates the tables anansi keeps track of records and migrations in.
+    pub fn bootstrap(self) -> String {
+        let id = match self {
+            Self::Sqlite => "INT PRIMARY KEY",
+            Self::Postgres => "BIGSERIAL PRIMARY KEY",
+            Self::Mysql => "BIGINT NOT NULL AUTO_INCREMENT PRIMARY KEY",
+        };
+        let q = |ident| self.quote(ident);
+        let options = self.table_options();
+        format!("CREATE TABLE {}(\n\t{} text NOT NULL,\n\t{} text NOT NULL\n){};\nCREATE TABLE anansi_migrations(\n\t{} {},\n\t{} TEXT NOT NULL,\n\t{} TEXT NOT NULL,\n\t{} {} NOT NULL\n){};\n", q("anansi_records"), q("name"), q("schema"), options, q("id"), id, q("app"), q("name"), q("applied"), self.column_type("datetime").to_uppercase(), options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Dialect;
+
+    #[test]
+    fn dialects() {
+        let (sqlite, postgres, mysql) = (Dialect::Sqlite, Dialect::Postgres, Dialect::Mysql);
+        assert_eq!(sqlite.quote("auth_user.user"), "\"auth_user\".\"user\"");
+        assert_eq!(postgres.quote("a\"b"), "\"a\"\"b\"");
+        assert_eq!(sqlite.placeholder(2), "?");
+        assert_eq!(postgres.placeholder(2), "$2");
+        assert_eq!(postgres.returning(&["id", "name"]).unwrap(), " RETURNING \"id\", \"name\"");
+        assert_eq!(postgres.ilike("\"t\".\"c\"", "'%a%'"), "\"t\".\"c\" ILIKE '%a%' ESCAPE '\\'");
+        assert_eq!(sqlite.column_type("datetime"), "datetime");
+        assert_eq!(postgres.column_type("datetime"), "text");
+        assert!(postgres.bootstrap().contains("\"id\" BIGSERIAL PRIMARY KEY"));
+        assert!(sqlite.bootstrap().contains("\"applied\" DATETIME NOT NULL"));
+        assert_eq!(mysql.quote("auth_user.user"), "`auth_user`.`user`");
+        assert_eq!(mysql.placeholder(2), "?");
+        assert!(mysql.returning(&["id"]).is_err());
+        assert_eq!(mysql.column_type("datetime"), "varchar(19)");
+        assert!(mysql.bootstrap().contains("`applied` VARCHAR(19) NOT NULL\n) ENGINE=InnoDB;"));
+    }
+}
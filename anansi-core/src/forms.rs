@@ -85,7 +85,7 @@ macro_rules! _handle {
 macro_rules! _handle_or_404 {
     ($form:ty, $trait:ty, $req:ident, $post:ident, $b:expr) => {
         match *$req.method() {
-            anansi::web::GET => {
+            anansi::web::GET | anansi::web::HEAD => {
                 use anansi::forms::Form;
                 let mut form = match <$form as $trait>::on_get(&$req).await {
                     Ok(form) => form,
@@ -120,6 +120,7 @@ macro_rules! _handle_or_404 {
                     res
                 }
             },
+            _ => return Ok(anansi::web::Response::method_not_allowed(&[anansi::web::GET, anansi::web::POST])),
         }
     }
 }
@@ -129,7 +130,7 @@ macro_rules! base_handle {
     ($form:ty, $trait:ty, $req:ident, $post:ident, $b:expr, $on_get:block, $on_post:block) => {
         {
             match *$req.method() {
-                anansi::web::GET => {
+                anansi::web::GET | anansi::web::HEAD => {
                     async {
                         $on_get
                     }.await
@@ -137,6 +138,7 @@ macro_rules! base_handle {
                 anansi::web::POST => {
                     $on_post
                 },
+                _ => return Ok(anansi::web::Response::method_not_allowed(&[anansi::web::GET, anansi::web::POST])),
             }
         }
     }
@@ -190,8 +192,8 @@ pub trait Form {
         }
         tag
     }
-    fn action<B: BaseRequest + Reverse>(self, req: &B, action: View<B>) -> Self where Self: Sized {
-        self.insert_attr("action", &anansi::url!(req, action))
+    fn action<B: BaseRequest + Reverse>(self, req: &B, action: View<B>) -> Result<Self> where Self: Sized {
+        Ok(self.insert_attr("action", &anansi::url!(req, action)))
     }
     fn class(self, class: &str) -> Self where Self: Sized {
         self.insert_attr("class", class)
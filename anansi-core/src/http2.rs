@@ -0,0 +1,291 @@
+use std::{fmt, io, str};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::future::poll_fn;
+use std::task::{Context, Poll};
+
+use bytes::Bytes;
+use h2::{Reason, RecvStream, SendStream};
+use h2::server::{self, SendResponse};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use tokio::sync::watch;
+use tokio::time;
+use tracing::Instrument;
+
+use crate::db::invalid;
+use crate::log::Access;
+use crate::reader::{Message, merge_headers};
+use crate::server::{ServerConfig, Shared, respond};
+use crate::web::{Result, BaseRequest, Body, Chunks, Method, RequestLine, Response, Status};
+
+/// The client connection preface, sent first by clients using h2c with prior knowledge.
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// Headers that only apply to a single HTTP/1.1 connection and are not allowed in HTTP/2.
+const CONNECTION_HEADERS: [&str; 5] = ["Connection", "Keep-Alive", "Proxy-Connection", "Transfer-Encoding", "Upgrade"];
+
+/// Reads as much of the HTTP/2 preface as a plain TCP client sends, returning
+/// whether it opened the connection with it and the stream with the bytes read
+/// put back.
+pub async fn read_preface<S: AsyncRead + Unpin>(mut stream: S, timeout: time::Duration) -> (bool, Rewind<S>) {
+    let mut buf = vec![0; PREFACE.len()];
+    let mut n = 0;
+    let read = async {
+        while n < PREFACE.len() {
+            match stream.read(&mut buf[n..]).await {
+                Ok(0) | Err(_) => return false,
+                Ok(m) => n += m,
+            }
+            if !PREFACE.starts_with(&buf[..n]) {
+                return false;
+            }
+        }
+        true
+    };
+    let preface = matches!(time::timeout(timeout, read).await, Ok(true));
+    buf.truncate(n);
+    (preface, Rewind {prefix: buf, inner: stream})
+}
+
+/// A stream that yields the bytes already read from it before reading more.
+pub struct Rewind<S> {
+    prefix: Vec<u8>,
+    inner: S,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Rewind<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        if self.prefix.is_empty() {
+            return Pin::new(&mut self.inner).poll_read(cx, buf);
+        }
+        let n = self.prefix.len().min(buf.remaining());
+        buf.put_slice(&self.prefix[..n]);
+        self.prefix.drain(..n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rewind<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Serves an HTTP/2 connection, handling each stream concurrently with the same
+/// views as HTTP/1.1, up to `max_concurrent_streams` at a time.
+pub async fn handle_connection<B: BaseRequest + 'static + fmt::Debug, S: AsyncRead + AsyncWrite + Unpin>(stream: S, peer: Option<SocketAddr>, shared: Arc<Shared<B>>) -> Result<()> {
+    let read_timeout = time::Duration::from_secs(shared.config.read_timeout);
+    let mut connection = time::timeout(read_timeout, builder(&shared.config).handshake(stream)).await??;
+    let mut shutdown = shared.shutdown.clone();
+    let mut closing = false;
+    loop {
+        let accepted = tokio::select! {
+            accepted = connection.accept() => accepted,
+            _ = shutdown.wait_for(|stop| *stop), if !closing => {
+                connection.graceful_shutdown();
+                closing = true;
+                continue;
+            },
+        };
+        match accepted {
+            Some(Ok((request, send))) => {
+                let shared = shared.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = handle_stream(request, send, peer, shared).await {
+                        tracing::warn!("Stream error: {}", e);
+                    }
+                });
+            },
+            Some(Err(e)) => return Err(e.into()),
+            None => break,
+        }
+    }
+    Ok(())
+}
+
+fn builder(config: &ServerConfig) -> server::Builder {
+    let mut builder = server::Builder::new();
+    builder.max_concurrent_streams(config.max_concurrent_streams);
+    builder
+}
+
+async fn handle_stream<B: BaseRequest + 'static + fmt::Debug>(request: http::Request<RecvStream>, mut send: SendResponse<Bytes>, peer: Option<SocketAddr>, shared: Arc<Shared<B>>) -> Result<()> {
+    let start = time::Instant::now();
+    let head = request.method() == http::Method::HEAD;
+    let method = Method::from_bytes(request.method().as_str().as_bytes()).unwrap_or(Method::Get);
+    let path = request.uri().path_and_query().map(|p| p.as_str()).unwrap_or("/").to_string();
+    let (mut response, user_id) = match read_message(request, shared.config.max_body_size).await {
+        Ok(message) => {
+            let span = tracing::info_span!("request", method = method.as_str(), path = path.as_str());
+            match tokio::spawn(respond(shared.clone(), message).instrument(span)).await {
+                Ok(reply) => reply,
+                Err(_) => (shared.router.internal_error.clone(), None),
+            }
+        },
+        Err(status) => (Response::from_status(status), None),
+    };
+    {
+        let timer = shared.timer.lock().unwrap();
+        response.headers_mut().insert("Date".to_string(), timer.to_gmt());
+    }
+    let chunks = response.body_stream().filter(|_| !head && !response.status().is_bodiless()).map(|body| (body.take(), body.content_length(), body.ends_on_shutdown()));
+    let (status, headers, body) = response.into_parts();
+    let mut builder = http::Response::builder().status(status.code());
+    for (key, value) in headers.iter() {
+        if !CONNECTION_HEADERS.iter().any(|h| h.eq_ignore_ascii_case(key)) && !key.eq_ignore_ascii_case("Content-Length") {
+            builder = builder.header(key.to_ascii_lowercase(), value);
+        }
+    }
+    if let Some((_, len, _)) = &chunks {
+        if let Some(len) = len {
+            builder = builder.header("content-length", *len);
+        }
+    } else if !status.is_bodiless() {
+        builder = builder.header("content-length", body.len());
+    }
+    let end = head || (body.is_empty() && chunks.is_none());
+    let mut bytes = if end { 0 } else { body.len() };
+    let mut stream = send.send_response(builder.body(())?, end)?;
+    if let Some((chunks, _, ends_on_shutdown)) = chunks {
+        let mut cutoff = shared.cutoff.clone();
+        bytes = tokio::select! {
+            bytes = send_chunks(&mut stream, chunks, ends_on_shutdown, shared.shutdown.clone()) => bytes?,
+            _ = cutoff.wait_for(|stop| *stop) => {
+                stream.send_reset(Reason::CANCEL);
+                return Err("server shut down before the body was sent".into());
+            },
+        };
+    } else if !end {
+        stream.send_data(Bytes::from(body), true)?;
+    }
+    Access {peer, method, path: &path, protocol: "HTTP/2.0", status: status.code(), bytes, latency: start.elapsed(), user_id}.log();
+    Ok(())
+}
+
+/// Sends a streaming body as it is produced, waiting for the peer's flow control window.
+/// Bodies that end on shutdown stop once the server starts shutting down.
+async fn send_chunks(stream: &mut SendStream<Bytes>, chunks: Option<Chunks>, ends_on_shutdown: bool, mut shutdown: watch::Receiver<bool>) -> Result<usize> {
+    let mut written = 0;
+    if let Some(mut chunks) = chunks {
+        loop {
+            let mut chunk = tokio::select! {
+                chunk = chunks.next() => match chunk {
+                    Some(chunk) => chunk?,
+                    None => break,
+                },
+                _ = shutdown.wait_for(|stop| *stop), if ends_on_shutdown => break,
+            };
+            written += chunk.len();
+            while !chunk.is_empty() {
+                stream.reserve_capacity(chunk.len());
+                let capacity = match poll_fn(|cx| stream.poll_capacity(cx)).await {
+                    Some(capacity) => capacity?,
+                    None => return Err(invalid()),
+                };
+                let data = chunk.split_to(capacity.min(chunk.len()));
+                stream.send_data(data, false)?;
+            }
+        }
+    }
+    stream.send_data(Bytes::new(), true)?;
+    Ok(written)
+}
+
+/// Converts an HTTP/2 request into the message read by the HTTP/1.1 reader.
+async fn read_message(request: http::Request<RecvStream>, max_body: usize) -> std::result::Result<Message, Status> {
+    let (parts, mut recv) = request.into_parts();
+    let method = Method::from_bytes(parts.method.as_str().as_bytes()).ok_or(Status::NotImplemented)?;
+    let url = parts.uri.path_and_query().map(|p| p.as_str()).unwrap_or("/").to_string();
+    if !url.starts_with('/') {
+        return Err(Status::BadRequest);
+    }
+    let mut fields = vec![];
+    if let Some(authority) = parts.uri.authority() {
+        fields.push(("Host", authority.as_str()));
+    }
+    for (key, value) in parts.headers.iter() {
+        let value = value.to_str().or(Err(Status::BadRequest))?;
+        if key == http::header::HOST && parts.uri.authority().is_some() {
+            continue;
+        }
+        fields.push((key.as_str(), value));
+    }
+    let headers = merge_headers(fields);
+
+    let mut body = vec![];
+    while let Some(data) = recv.data().await {
+        let data = data.or(Err(Status::BadRequest))?;
+        if body.len() + data.len() > max_body {
+            return Err(Status::PayloadTooLarge);
+        }
+        let _ = recv.flow_control().release_capacity(data.len());
+        body.extend_from_slice(&data);
+    }
+    let body = if body.is_empty() {
+        None
+    } else {
+        Some(Body::new(body))
+    };
+    Ok(Message {request_line: RequestLine {method, url}, headers, body})
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::time::Duration;
+    use crate::server::ServerConfig;
+    use super::{builder, read_preface};
+
+    #[tokio::test]
+    async fn h2c_round_trip() {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let server = tokio::spawn(async move {
+            let (preface, stream) = read_preface(server, Duration::from_secs(1)).await;
+            assert!(preface);
+            let config = ServerConfig {max_concurrent_streams: 8, ..ServerConfig::default()};
+            let mut connection = builder(&config).handshake::<_, Bytes>(stream).await.unwrap();
+            let (request, mut send) = connection.accept().await.unwrap().unwrap();
+            assert_eq!(request.uri().path(), "/hello");
+            let response = http::Response::builder().status(200).body(()).unwrap();
+            send.send_response(response, false).unwrap().send_data(Bytes::from_static(b"hi"), true).unwrap();
+            while let Some(result) = connection.accept().await {
+                result.unwrap();
+            }
+        });
+
+        let (client, connection) = h2::client::handshake(client).await.unwrap();
+        tokio::spawn(connection);
+        let mut client = client.ready().await.unwrap();
+        let request = http::Request::builder().uri("http://localhost/hello").body(()).unwrap();
+        let (response, _) = client.send_request(request, true).unwrap();
+        let response = response.await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(client.current_max_send_streams(), 8);
+        let mut body = response.into_body();
+        let data = body.data().await.unwrap().unwrap();
+        assert_eq!(&data[..], b"hi");
+        drop(body);
+        drop(client);
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn http1_fallback() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        let (preface, mut stream) = read_preface(server, Duration::from_secs(1)).await;
+        assert!(!preface);
+        drop(client);
+        let mut read = String::new();
+        stream.read_to_string(&mut read).await.unwrap();
+        assert_eq!(read, "GET / HTTP/1.1\r\n\r\n");
+    }
+}
@@ -4,14 +4,26 @@ pub use anansi_macros::*;
 
 pub mod server;
 pub mod web;
+pub mod reader;
+pub mod query;
+pub mod middleware;
+pub mod log;
 pub mod db;
+pub mod dialect;
 pub mod records;
 pub mod humanize;
 mod datetime;
 pub mod router;
+pub mod static_files;
+pub mod compression;
+pub mod sse;
 pub mod forms;
 pub mod migrations;
 pub mod admin_site;
+#[cfg(feature = "tls")]
+pub mod tls;
+#[cfg(feature = "http2")]
+pub mod http2;
 
 #[cfg(test)]
 mod tests {
@@ -0,0 +1,235 @@
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{self, IsTerminal, Write};
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::Duration;
+
+use tracing::{Event, Subscriber};
+use tracing::field::{Field, Visit};
+use tracing_appender::non_blocking::{NonBlocking, WorkerGuard};
+use tracing_subscriber::{Layer, Registry};
+use tracing_subscriber::filter::{LevelFilter, filter_fn};
+use tracing_subscriber::layer::{Context, SubscriberExt};
+
+use crate::datetime::DateTime;
+use crate::web::{Result, Method};
+
+/// Target of the access log events, which are kept out of the server log.
+pub const ACCESS_TARGET: &str = "anansi::access";
+
+/// How access log lines are written.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    /// Common log format.
+    Common,
+    /// One JSON object per line, including the latency in microseconds.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = Box<dyn std::error::Error + Send + Sync>;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "common" => Ok(Self::Common),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("unknown access log format \"{}\"", s).into()),
+        }
+    }
+}
+
+/// Where access log lines are written.
+#[derive(Clone, Debug, PartialEq)]
+pub enum LogOutput {
+    Off,
+    Stdout,
+    File(String),
+}
+
+impl FromStr for LogOutput {
+    type Err = Box<dyn std::error::Error + Send + Sync>;
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "off" => Self::Off,
+            "stdout" => Self::Stdout,
+            path => Self::File(path.to_string()),
+        })
+    }
+}
+
+/// Installs the global subscriber, sending server messages to stderr at
+/// `level` and access entries to `output`. Access entries are written from a
+/// background thread, which flushes the remaining ones when the returned guard
+/// is dropped.
+pub fn init(level: &str, format: LogFormat, output: &LogOutput) -> Result<Option<WorkerGuard>> {
+    let level = LevelFilter::from_str(level)?;
+    let server = tracing_subscriber::fmt::layer()
+        .with_writer(io::stderr)
+        .with_ansi(io::stderr().is_terminal())
+        .with_target(false)
+        .with_filter(filter_fn(|meta| meta.target() != ACCESS_TARGET))
+        .with_filter(level);
+    let (access, guard) = match output {
+        LogOutput::Off => (None, None),
+        LogOutput::Stdout => {
+            let (writer, guard) = tracing_appender::non_blocking(io::stdout());
+            (Some(AccessLayer {format, writer}), Some(guard))
+        },
+        LogOutput::File(path) => {
+            let (writer, guard) = tracing_appender::non_blocking(OpenOptions::new().create(true).append(true).open(path)?);
+            (Some(AccessLayer {format, writer}), Some(guard))
+        },
+    };
+    let subscriber = Registry::default().with(server).with(access);
+    tracing::subscriber::set_global_default(subscriber)?;
+    Ok(guard)
+}
+
+/// What the access log records about a finished request.
+pub struct Access<'a> {
+    pub peer: Option<SocketAddr>,
+    pub method: Method,
+    pub path: &'a str,
+    pub protocol: &'static str,
+    pub status: u16,
+    pub bytes: usize,
+    pub latency: Duration,
+    pub user_id: Option<i64>,
+}
+
+impl<'a> Access<'a> {
+    pub fn log(&self) {
+        tracing::info!(
+            target: ACCESS_TARGET,
+            remote = self.peer.map(|p| p.ip().to_string()),
+            method = self.method.as_str(),
+            path = self.path,
+            protocol = self.protocol,
+            status = self.status,
+            bytes = self.bytes as u64,
+            latency_us = self.latency.as_micros() as u64,
+            user_id = self.user_id,
+        );
+    }
+}
+
+struct AccessLayer {
+    format: LogFormat,
+    writer: NonBlocking,
+}
+
+impl<S: Subscriber> Layer<S> for AccessLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        if event.metadata().target() != ACCESS_TARGET {
+            return;
+        }
+        let mut entry = Entry::default();
+        event.record(&mut entry);
+        let mut line = match self.format {
+            LogFormat::Common => entry.common(),
+            LogFormat::Json => entry.json(),
+        };
+        line.push('\n');
+        let _ = self.writer.clone().write_all(line.as_bytes());
+    }
+}
+
+#[derive(Default)]
+struct Entry {
+    remote: Option<String>,
+    method: String,
+    path: String,
+    protocol: String,
+    status: u64,
+    bytes: u64,
+    latency_us: u64,
+    user_id: Option<i64>,
+}
+
+impl Entry {
+    fn common(&self) -> String {
+        let user = self.user_id.map(|id| id.to_string());
+        let bytes = if self.bytes == 0 { "-".to_string() } else { self.bytes.to_string() };
+        format!(
+            "{} - {} [{}] \"{} {} {}\" {} {}",
+            self.remote.as_deref().unwrap_or("-"),
+            user.as_deref().unwrap_or("-"),
+            DateTime::now().to_clf(),
+            self.method,
+            escape(&self.path),
+            self.protocol,
+            self.status,
+            bytes,
+        )
+    }
+    fn json(&self) -> String {
+        serde_json::json!({
+            "time": DateTime::now().to_string(),
+            "remote": self.remote,
+            "method": self.method,
+            "path": self.path,
+            "protocol": self.protocol,
+            "status": self.status,
+            "bytes": self.bytes,
+            "latency_us": self.latency_us,
+            "user_id": self.user_id,
+        }).to_string()
+    }
+}
+
+/// Escapes a field quoted in the common log format, so that a request target
+/// cannot end the quotes or the line.
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_ascii_control() => escaped.push_str(&format!("\\x{:02x}", c as u8)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+impl Visit for Entry {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "remote" => self.remote = Some(value.to_string()),
+            "method" => self.method = value.to_string(),
+            "path" => self.path = value.to_string(),
+            "protocol" => self.protocol = value.to_string(),
+            _ => {},
+        }
+    }
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        match field.name() {
+            "status" => self.status = value,
+            "bytes" => self.bytes = value,
+            "latency_us" => self.latency_us = value,
+            "user_id" => self.user_id = Some(value as i64),
+            _ => {},
+        }
+    }
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        if field.name() == "user_id" {
+            self.user_id = Some(value);
+        }
+    }
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.record_str(field, &format!("{:?}", value));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Entry;
+
+    #[test]
+    fn common_format() {
+        let entry = Entry {method: "GET".to_string(), path: "/a\"b\\c\n\x7fé".to_string(), protocol: "HTTP/1.1".to_string(), status: 404, ..Entry::default()};
+        let line = entry.common();
+        assert!(line.starts_with("- - - ["), "{}", line);
+        assert!(line.ends_with("] \"GET /a\\\"b\\\\c\\x0a\\x7fé HTTP/1.1\" 404 -"), "{}", line);
+    }
+}
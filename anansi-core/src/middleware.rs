@@ -0,0 +1,138 @@
+use async_trait::async_trait;
+
+use crate::records::Record;
+use crate::web::{Result, BaseRequest, BaseUser, CsrfDefense, Extensions, Headers, Method, Response};
+
+/// A layer that runs around every view.
+///
+/// Middleware is registered in order in `main!`. The `before` hooks run in
+/// that order before the view, and the `after` hooks run in reverse order on
+/// the response, with a snapshot of the request taken before the view. Returning
+/// a response from `before` skips the view and the remaining middleware, and
+/// only the `after` hooks of the middleware that already ran are applied to it.
+#[async_trait]
+pub trait Middleware<B: BaseRequest>: Send + Sync {
+    async fn before(&self, _req: &mut B) -> Result<Option<Response>> {
+        Ok(None)
+    }
+    async fn after(&self, _req: &Snapshot, response: Response) -> Result<Response> {
+        Ok(response)
+    }
+}
+
+/// What the `after` hooks see of a request, recorded once the `before` hooks
+/// have run, since the view takes the request itself.
+#[derive(Debug)]
+pub struct Snapshot {
+    pub method: Method,
+    /// The path and query string.
+    pub url: String,
+    pub headers: Headers,
+    /// The id of the logged in user, if any.
+    pub user_id: Option<i64>,
+    /// The request's extensions, where a `before` hook can leave state for its
+    /// `after` hook, such as when the request started.
+    pub extensions: Extensions,
+}
+
+impl Snapshot {
+    pub fn record<B: BaseRequest>(req: &B) -> Self {
+        let user = req.user();
+        let user_id = if user.is_auth() { Some(user.pk().as_i64()) } else { None };
+        Self {method: *req.method(), url: req.url().clone(), headers: req.headers().clone(), user_id, extensions: req.raw().extensions().clone()}
+    }
+}
+
+/// The registered middleware, in the order their `before` hooks run.
+pub type Chain<B> = Vec<Box<dyn Middleware<B>>>;
+
+/// Rejects unsafe requests without a valid CSRF token with `403 Forbidden`.
+///
+/// The token is read from the `X-CSRF-Token` header or the `csrf_token` form
+/// field, and must run after the session middleware. Every unsafe request is
+/// checked, including ones without a body, unless its `Content-Type` is one a
+/// browser has to preflight before sending it across origins, such as
+/// `application/json`. Those are left to CORS and the view, as are requests to
+/// exempt paths.
+#[derive(Clone, Debug, Default)]
+pub struct CsrfMiddleware {
+    exempt: Vec<String>,
+}
+
+impl CsrfMiddleware {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Skips the check for paths starting with `prefix`, such as `/api/`.
+    pub fn exempt(mut self, prefix: &str) -> Self {
+        self.exempt.push(prefix.to_string());
+        self
+    }
+    /// Whether a request has to carry a valid token.
+    pub fn checks(&self, method: &Method, path: &str, headers: &Headers) -> bool {
+        if matches!(method, Method::Get | Method::Head | Method::Options) {
+            return false;
+        }
+        if self.exempt.iter().any(|prefix| path.starts_with(prefix.as_str())) {
+            return false;
+        }
+        headers.get("X-CSRF-Token").is_some() || !headers.get("Content-Type").is_some_and(|ty| needs_preflight(ty))
+    }
+}
+
+/// Whether a browser has to ask CORS before sending a body of this type to
+/// another origin, which holds for all but the types a `<form>` can submit.
+fn needs_preflight(ty: &str) -> bool {
+    let ty = ty.split(';').next().unwrap_or_default().trim();
+    !["application/x-www-form-urlencoded", "multipart/form-data", "text/plain"].iter().any(|simple| ty.eq_ignore_ascii_case(simple))
+}
+
+#[async_trait]
+impl<B: BaseRequest + CsrfDefense + 'static> Middleware<B> for CsrfMiddleware {
+    async fn before(&self, req: &mut B) -> Result<Option<Response>> {
+        if !self.checks(req.method(), req.raw().path(), req.headers()) {
+            return Ok(None);
+        }
+        let submitted = match req.headers().get("X-CSRF-Token") {
+            Some(token) => Some(token.clone()),
+            None => req.to_form_map().ok().and_then(|form| form.get("csrf_token").ok().cloned()),
+        };
+        let valid = match (submitted, req.token()) {
+            (Some(submitted), Ok(token)) => token.check(&submitted),
+            _ => false,
+        };
+        if valid {
+            *req.raw_mut().valid_token_mut() = true;
+            Ok(None)
+        } else {
+            Ok(Some(Response::forbidden()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CsrfMiddleware;
+    use crate::web::{Headers, Method};
+
+    #[test]
+    fn csrf_checks() {
+        let csrf = CsrfMiddleware::new().exempt("/hooks/");
+        let headers = |ty: &str| {
+            let mut headers = Headers::new();
+            headers.insert("Content-Type".to_string(), ty.to_string());
+            headers
+        };
+        assert!(!csrf.checks(&Method::Post, "/api/1", &headers("application/json")));
+        assert!(csrf.checks(&Method::Post, "/login", &headers("application/x-www-form-urlencoded")));
+        assert!(csrf.checks(&Method::Put, "/upload", &headers("multipart/form-data; boundary=x")));
+        assert!(!csrf.checks(&Method::Get, "/login", &headers("application/x-www-form-urlencoded")));
+        assert!(!csrf.checks(&Method::Post, "/hooks/push", &headers("application/x-www-form-urlencoded")));
+        assert!(csrf.checks(&Method::Post, "/post/5/delete", &headers("text/plain;charset=UTF-8")));
+        assert!(csrf.checks(&Method::Post, "/post/5/delete", &Headers::new()));
+
+        let mut json = headers("application/json");
+        json.insert("X-CSRF-Token".to_string(), "t".to_string());
+        assert!(csrf.checks(&Method::Delete, "/api/1", &json));
+    }
+}
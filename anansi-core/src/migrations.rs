@@ -7,9 +7,11 @@ use syn::Field;
 use syn::Item::Struct;
 use syn::Fields::Named;
 use syn::Attribute;
-use sqlx::Row;
-use crate::db::{DbPool, unescape};
+use sqlx::{Executor, Row};
+use crate::db::{DbPool, invalid, quote, unescape};
+use crate::dialect::DIALECT;
 use crate::records::RecordField;
+use crate::web::Result;
 
 #[macro_export]
 macro_rules! apps {
@@ -72,11 +74,11 @@ pub struct CreateRecord {
 
 impl fmt::Display for CreateRecord {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let mut s = format!("CREATE TABLE \"{}_{}\" (", self.prefix, self.name);
+        let mut s = format!("CREATE TABLE {} (", quote(&format!("{}_{}", self.prefix, self.name)));
         let mut v = vec![];
         for (field_name, field) in &self.fields {
             let (syn, con) = field.to_syntax();
-            s.push_str(&format!("\n\t\"{}\" {},", field_name, syn));
+            s.push_str(&format!("\n\t{} {},", quote(field_name), syn));
             if !con.is_empty() {
                 v.push(con);
             }
@@ -88,7 +90,7 @@ impl fmt::Display for CreateRecord {
             s.push(',');
         }
         s.pop().unwrap();
-        write!(f, "{}\n);\n\n", s)
+        write!(f, "{}\n){};\n\n", s, DIALECT.table_options())
     }
 }
 
@@ -105,7 +107,7 @@ fn to_migration(v: &Vec<Box<dyn fmt::Display>>) -> String {
     s
 }
 
-pub async fn migrate(app_migrations: &'static [LocalKey<AppMigration>], pool: &DbPool) {
+pub async fn migrate(app_migrations: &'static [LocalKey<AppMigration>], pool: &DbPool) -> Result<()> {
     for app_migration in app_migrations {
         let mut app = "";
         let mut migrations = vec![];
@@ -116,20 +118,22 @@ pub async fn migrate(app_migrations: &'static [LocalKey<AppMigration>], pool: &D
             }
         });
         println!("Checking {}", app);
-        let rows = sqlx::query("SELECT name FROM anansi_migrations WHERE app = ?").bind(app).fetch_all(&pool.0).await.unwrap();
+        let rows = sqlx::query(&format!("SELECT name FROM anansi_migrations WHERE app = {}", DIALECT.placeholder(1))).bind(app).fetch_all(&pool.0).await?;
         let mut names = vec![];
         for row in rows {
-            let name: String = row.try_get("name").unwrap();
+            let name: String = row.try_get("name")?;
             names.push(name);
         }
         for (n, q) in migrations {
             if !names.contains(&n.to_string()) {
                 println!("    Applying migration \"{}\"", n);
-                sqlx::query(&q).execute(&pool.0).await.unwrap();
-                sqlx::query("INSERT INTO anansi_migrations (app, name, applied) VALUES(?, ?, strftime('%Y-%m-%d %H-%M-%f','now'))").bind(app).bind(n).execute(&pool.0).await.unwrap();
+                pool.0.execute(q.as_str()).await?;
+                let insert = format!("INSERT INTO anansi_migrations (app, name, applied) VALUES({}, {}, {})", DIALECT.placeholder(1), DIALECT.placeholder(2), DIALECT.now());
+                sqlx::query(&insert).bind(app).bind(n).execute(&pool.0).await?;
             }
         }
     }
+    Ok(())
 }
 
 pub async fn sql_migrate(app_migrations: &'static [LocalKey<AppMigration>], app_name: &str, migration_name: &str) {
@@ -147,7 +151,7 @@ pub async fn sql_migrate(app_migrations: &'static [LocalKey<AppMigration>], app_
     }
 }
 
-pub async fn make_migrations(app_dir: &str, pool: &DbPool) {
+pub async fn make_migrations(app_dir: &str, pool: &DbPool) -> Result<()> {
     let app_dir = if app_dir.chars().last().unwrap() == '/' {
         app_dir.to_string()
     } else {
@@ -157,15 +161,15 @@ pub async fn make_migrations(app_dir: &str, pool: &DbPool) {
     let s: Vec<&str> = app_dir.split('/').collect();
     let app_name = &s[s.len()-2];
     let mfile = format!("{}records.rs", app_dir);
-    let content = fs::read_to_string(&mfile).expect(&format!("could not open {}", mfile));
+    let content = fs::read_to_string(&mfile).map_err(|e| format!("could not open {}: {}", mfile, e))?;
     process_syntax(app_name, content, &mut v);
 
     let mut syntaxes = Vec::new();
     let mut new_records = Vec::new();
     for (prefix, name, syntax) in v {
-        let val = String::from(format!("SELECT schema FROM records WHERE name = '{}';\n", name));
-        if let Ok(row) = sqlx::query(&val).fetch_one(&pool.0).await {
-            let s: &str = row.try_get("schema").unwrap();
+        let val = format!("SELECT {} FROM {} WHERE {} = {}", quote("schema"), quote("anansi_records"), quote("name"), DIALECT.placeholder(1));
+        if let Ok(row) = sqlx::query(&val).bind(&name).fetch_one(&pool.0).await {
+            let s: &str = row.try_get("schema")?;
             if unescape(&s) == syntax {
                 continue;
             } else {
@@ -181,22 +185,23 @@ pub async fn make_migrations(app_dir: &str, pool: &DbPool) {
         add_syntax(&mut sql, syntaxes);
         
         sql.push_str("}");
-        let row = sqlx::query("SELECT COUNT(*) as count FROM anansi_migrations WHERE app = ?").bind(app_name).fetch_one(&pool.0).await.unwrap();
-        let n: u16 = row.try_get("count").unwrap();
+        let row = sqlx::query(&format!("SELECT COUNT(*) as count FROM anansi_migrations WHERE app = {}", DIALECT.placeholder(1))).bind(app_name).fetch_one(&pool.0).await?;
+        let n: i64 = row.try_get("count")?;
         let mname = format!("{:04}", n+1);
         let mdir = format!("{}migrations/", app_dir);
         let s = format!("{}{}", mdir, mname);
-        fs::write(&s, sql).unwrap();
+        fs::write(&s, sql)?;
         println!("Created \"{}\"", s);
         let idir = format!("{}init.rs", mdir);
-        let original = fs::read_to_string(&idir).unwrap();
+        let original = fs::read_to_string(&idir)?;
         if original.trim() == "use anansi::migrations::prelude::*;\n\nlocal_migrations! {}" {
-            fs::write(idir, format!("use anansi::migrations::prelude::*;\n\nlocal_migrations! {{\n    \"{}\",\n}}", mname)).unwrap();
+            fs::write(idir, format!("use anansi::migrations::prelude::*;\n\nlocal_migrations! {{\n    \"{}\",\n}}", mname))?;
         } else {
-            let split = original.rsplit_once('}').unwrap();
-            fs::write(idir, format!("{}    \"{}\",\n}}", split.0, mname)).unwrap();
+            let split = original.rsplit_once('}').ok_or_else(invalid)?;
+            fs::write(idir, format!("{}    \"{}\",\n}}", split.0, mname))?;
         }
     }
+    Ok(())
 }
 
 pub fn new_syntax(sql: &mut String, new_records: Vec<(String, String, String)>) {
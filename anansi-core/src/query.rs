@@ -0,0 +1,217 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::de::{self, DeserializeOwned, DeserializeSeed, Deserializer, IntoDeserializer, MapAccess, SeqAccess, Visitor};
+
+use crate::db::invalid;
+use crate::web::{Result, percent_decode};
+
+/// The decoded query string of a request, keeping repeated keys in order.
+#[derive(Debug, Clone, Default)]
+pub struct Query(Vec<(String, String)>);
+
+impl Query {
+    pub fn new() -> Self {
+        Self(vec![])
+    }
+    /// Parses `a=1&b=x+y&a=2`, accepting keys without a value.
+    pub fn parse(qs: &str) -> Result<Self> {
+        let mut pairs = vec![];
+        for pair in qs.split('&') {
+            if pair.is_empty() {
+                continue;
+            }
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            pairs.push((percent_decode(key)?, percent_decode(value)?));
+        }
+        Ok(Self(pairs))
+    }
+    /// Parses the first value of `key`.
+    pub fn get<T: FromStr>(&self, key: &str) -> Result<T> {
+        self.get_str(key).ok_or_else(invalid)?.parse().or(Err(invalid()))
+    }
+    pub fn get_str(&self, key: &str) -> Option<&str> {
+        self.0.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+    }
+    /// Every value of `key`, in the order they appear.
+    pub fn get_all<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.0.iter().filter(move |(k, _)| k == key).map(|(_, v)| v.as_str())
+    }
+    pub fn contains(&self, key: &str) -> bool {
+        self.0.iter().any(|(k, _)| k == key)
+    }
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+    pub fn iter(&self) -> std::slice::Iter<'_, (String, String)> {
+        self.0.iter()
+    }
+    /// Deserializes the query into a struct, where repeated keys can fill
+    /// `Vec` fields and missing keys `Option` fields.
+    pub fn deserialize<T: DeserializeOwned>(&self) -> Result<T> {
+        let mut keys: Vec<&str> = vec![];
+        for (key, _) in &self.0 {
+            if !keys.contains(&key.as_str()) {
+                keys.push(key);
+            }
+        }
+        let entries = keys.into_iter().map(|key| (key, self.get_all(key).collect())).collect();
+        Ok(T::deserialize(QueryDeserializer {entries})?)
+    }
+}
+
+#[derive(Debug)]
+pub struct QueryError(String);
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+impl de::Error for QueryError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Self(msg.to_string())
+    }
+}
+
+struct QueryDeserializer<'a> {
+    entries: Vec<(&'a str, Vec<&'a str>)>,
+}
+
+impl<'de, 'a> Deserializer<'de> for QueryDeserializer<'a> {
+    type Error = QueryError;
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> std::result::Result<V::Value, QueryError> {
+        visitor.visit_map(Entries {entries: self.entries.into_iter(), values: None})
+    }
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+struct Entries<'a> {
+    entries: std::vec::IntoIter<(&'a str, Vec<&'a str>)>,
+    values: Option<Vec<&'a str>>,
+}
+
+impl<'de, 'a> MapAccess<'de> for Entries<'a> {
+    type Error = QueryError;
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> std::result::Result<Option<K::Value>, QueryError> {
+        match self.entries.next() {
+            Some((key, values)) => {
+                self.values = Some(values);
+                seed.deserialize(key.into_deserializer()).map(Some)
+            },
+            None => Ok(None),
+        }
+    }
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> std::result::Result<V::Value, QueryError> {
+        let values = self.values.take().ok_or_else(|| de::Error::custom("value without a key"))?;
+        seed.deserialize(Values(values))
+    }
+}
+
+/// The values of one key, read as a single value or as a sequence.
+struct Values<'a>(Vec<&'a str>);
+
+impl<'a> Values<'a> {
+    fn first(&self) -> &'a str {
+        self.0.first().copied().unwrap_or_default()
+    }
+    fn parse<T: FromStr>(&self) -> std::result::Result<T, QueryError> {
+        self.first().parse().map_err(|_| de::Error::custom(format!("invalid value \"{}\"", self.first())))
+    }
+}
+
+macro_rules! deserialize_parsed {
+    ($($method:ident => $visit:ident,)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> std::result::Result<V::Value, QueryError> {
+                visitor.$visit(self.parse()?)
+            }
+        )*
+    };
+}
+
+impl<'de, 'a> Deserializer<'de> for Values<'a> {
+    type Error = QueryError;
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> std::result::Result<V::Value, QueryError> {
+        visitor.visit_str(self.first())
+    }
+    deserialize_parsed! {
+        deserialize_bool => visit_bool,
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+        deserialize_f32 => visit_f32,
+        deserialize_f64 => visit_f64,
+        deserialize_char => visit_char,
+    }
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> std::result::Result<V::Value, QueryError> {
+        visitor.visit_some(self)
+    }
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> std::result::Result<V::Value, QueryError> {
+        visitor.visit_seq(Items(self.0.into_iter()))
+    }
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> std::result::Result<V::Value, QueryError> {
+        visitor.visit_newtype_struct(self)
+    }
+    fn deserialize_enum<V: Visitor<'de>>(self, _name: &'static str, _variants: &'static [&'static str], visitor: V) -> std::result::Result<V::Value, QueryError> {
+        visitor.visit_enum(self.first().into_deserializer())
+    }
+    serde::forward_to_deserialize_any! {
+        i128 u128 str string bytes byte_buf unit unit_struct tuple
+        tuple_struct map struct identifier ignored_any
+    }
+}
+
+struct Items<'a>(std::vec::IntoIter<&'a str>);
+
+impl<'de, 'a> SeqAccess<'de> for Items<'a> {
+    type Error = QueryError;
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> std::result::Result<Option<T::Value>, QueryError> {
+        match self.0.next() {
+            Some(value) => seed.deserialize(Values(vec![value])).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+    use super::Query;
+
+    #[derive(Deserialize)]
+    struct Search {
+        q: String,
+        page: Option<u32>,
+        tag: Vec<String>,
+        exact: bool,
+    }
+
+    #[test]
+    fn query() {
+        let query = Query::parse("q=caf%C3%A9+au+lait&tag=a&tag=b&exact=true&flag").unwrap();
+        assert_eq!(query.get_str("q"), Some("café au lait"));
+        assert_eq!(query.get_all("tag").collect::<Vec<_>>(), vec!["a", "b"]);
+        assert!(query.contains("flag"));
+        assert!(query.get::<i64>("q").is_err());
+        let search: Search = query.deserialize().unwrap();
+        assert_eq!(search.q, "café au lait");
+        assert_eq!(search.page, None);
+        assert_eq!(search.tag, vec!["a", "b"]);
+        assert!(search.exact);
+        assert_eq!(Query::parse("page=3").unwrap().get::<i64>("page").unwrap(), 3);
+        assert!(Query::parse("q=%zz").is_err());
+    }
+}
@@ -0,0 +1,337 @@
+use std::{fmt, io, str};
+use std::error::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::web::{Body, Headers, Method, RawRequest, RequestLine};
+
+const MAX_HEAD: usize = 64 * 1024;
+const READ_SIZE: usize = 8 * 1024;
+
+/// A request read off the wire, before it is turned into a `RawRequest`.
+pub struct Message {
+    pub request_line: RequestLine,
+    pub headers: Headers,
+    pub body: Option<Body>,
+}
+
+impl Message {
+    /// Whether the connection should stay open after the response.
+    pub fn keep_alive(&self) -> bool {
+        match self.headers.get("Connection") {
+            Some(c) => !c.split(',').any(|t| t.trim().eq_ignore_ascii_case("close")),
+            None => true,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ReadError {
+    /// The client closed the connection between requests.
+    Closed,
+    /// The request could not be parsed.
+    Malformed,
+    /// The request line names a method the server does not implement.
+    UnknownMethod,
+    /// The headers or body exceeded the configured limits.
+    TooLarge,
+    Io(io::Error),
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Closed => write!(f, "connection closed"),
+            Self::Malformed => write!(f, "malformed request"),
+            Self::UnknownMethod => write!(f, "unknown method"),
+            Self::TooLarge => write!(f, "request too large"),
+            Self::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for ReadError {}
+
+impl From<io::Error> for ReadError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+type ReadResult<T> = std::result::Result<T, ReadError>;
+
+/// Reads HTTP/1.1 requests from a stream, keeping any bytes that belong to the
+/// next pipelined request for the following call.
+pub struct RequestReader {
+    buf: Vec<u8>,
+    max_body: usize,
+    partial: bool,
+}
+
+impl RequestReader {
+    pub fn new(max_body: usize) -> Self {
+        Self {buf: vec![], max_body, partial: false}
+    }
+    /// Whether no part of the next request has been received yet.
+    pub fn is_idle(&self) -> bool {
+        !self.partial
+    }
+    pub async fn read_request<S: AsyncRead + AsyncWrite + Unpin>(&mut self, stream: &mut S) -> ReadResult<Message> {
+        let mut scanned = 0;
+        let end = loop {
+            if let Some(n) = find(&self.buf, b"\r\n\r\n", &mut scanned) {
+                break n;
+            }
+            if self.buf.len() > MAX_HEAD {
+                return Err(ReadError::TooLarge);
+            }
+            if self.fill(stream).await? == 0 {
+                return if self.buf.is_empty() {
+                    Err(ReadError::Closed)
+                } else {
+                    Err(ReadError::Malformed)
+                };
+            }
+        };
+        let head: Vec<u8> = self.buf.drain(..end + 4).collect();
+        let head = str::from_utf8(&head[..end]).or(Err(ReadError::Malformed))?;
+        let (line, fields) = head.split_once("\r\n").unwrap_or((head, ""));
+        let request_line = match RawRequest::get_request_line(line.as_bytes()) {
+            Ok(request_line) => request_line,
+            Err(_) if unknown_method(line) => return Err(ReadError::UnknownMethod),
+            Err(_) => return Err(ReadError::Malformed),
+        };
+        let headers = parse_headers(fields)?;
+
+        let chunked = match headers.get("Transfer-Encoding") {
+            Some(te) => {
+                if headers.get("Content-Length").is_some() || !te.rsplit(',').next().unwrap().trim().eq_ignore_ascii_case("chunked") {
+                    return Err(ReadError::Malformed);
+                }
+                true
+            },
+            None => false,
+        };
+        let len = match headers.get("Content-Length") {
+            Some(len) if !chunked => {
+                let len: usize = len.trim().parse().or(Err(ReadError::Malformed))?;
+                if len > self.max_body {
+                    return Err(ReadError::TooLarge);
+                }
+                len
+            },
+            _ => 0,
+        };
+        if headers.get("Expect").is_some_and(|e| e.eq_ignore_ascii_case("100-continue")) && self.buf.is_empty() {
+            stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await?;
+        }
+        let body = if chunked {
+            self.read_chunked(stream).await?
+        } else {
+            self.read_exact(stream, len).await?
+        };
+        let body = if body.is_empty() {
+            None
+        } else {
+            Some(Body::new(body))
+        };
+        self.partial = !self.buf.is_empty();
+        Ok(Message {request_line, headers, body})
+    }
+    async fn fill<S: AsyncRead + Unpin>(&mut self, stream: &mut S) -> ReadResult<usize> {
+        let mut chunk = [0; READ_SIZE];
+        let n = stream.read(&mut chunk).await?;
+        if n > 0 {
+            self.partial = true;
+        }
+        self.buf.extend_from_slice(&chunk[..n]);
+        Ok(n)
+    }
+    async fn read_exact<S: AsyncRead + Unpin>(&mut self, stream: &mut S, len: usize) -> ReadResult<Vec<u8>> {
+        while self.buf.len() < len {
+            if self.fill(stream).await? == 0 {
+                return Err(ReadError::Malformed);
+            }
+        }
+        Ok(self.buf.drain(..len).collect())
+    }
+    async fn read_line<S: AsyncRead + Unpin>(&mut self, stream: &mut S) -> ReadResult<String> {
+        let mut scanned = 0;
+        loop {
+            if let Some(n) = find(&self.buf, b"\r\n", &mut scanned) {
+                let line: Vec<u8> = self.buf.drain(..n + 2).collect();
+                return String::from_utf8(line[..n].to_vec()).or(Err(ReadError::Malformed));
+            }
+            if self.buf.len() > MAX_HEAD {
+                return Err(ReadError::TooLarge);
+            }
+            if self.fill(stream).await? == 0 {
+                return Err(ReadError::Malformed);
+            }
+        }
+    }
+    async fn read_chunked<S: AsyncRead + Unpin>(&mut self, stream: &mut S) -> ReadResult<Vec<u8>> {
+        let mut body = vec![];
+        loop {
+            let line = self.read_line(stream).await?;
+            let size = line.split(';').next().unwrap().trim();
+            let size = usize::from_str_radix(size, 16).or(Err(ReadError::Malformed))?;
+            if size == 0 {
+                break;
+            }
+            if body.len().checked_add(size).is_none_or(|n| n > self.max_body) {
+                return Err(ReadError::TooLarge);
+            }
+            let mut chunk = self.read_exact(stream, size + 2).await?;
+            if !chunk.ends_with(b"\r\n") {
+                return Err(ReadError::Malformed);
+            }
+            chunk.truncate(size);
+            body.append(&mut chunk);
+        }
+        let mut trailers = 0;
+        loop {
+            let line = self.read_line(stream).await?;
+            if line.is_empty() {
+                break;
+            }
+            trailers += line.len() + 2;
+            if trailers > MAX_HEAD {
+                return Err(ReadError::TooLarge);
+            }
+        }
+        Ok(body)
+    }
+}
+
+/// Finds `bytes` in `buffer`, starting where the previous call on the same
+/// buffer left off so that each fill is only scanned once.
+fn find(buffer: &[u8], bytes: &[u8], scanned: &mut usize) -> Option<usize> {
+    let start = scanned.saturating_sub(bytes.len() - 1);
+    *scanned = buffer.len();
+    buffer[start..].windows(bytes.len()).position(|w| w == bytes).map(|n| start + n)
+}
+
+/// Whether a request line that could not be parsed is only wrong in naming a
+/// method that is not implemented, such as `BREW / HTTP/1.1`.
+fn unknown_method(line: &str) -> bool {
+    let mut parts = line.split(' ');
+    match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(url), Some("HTTP/1.1"), None) => {
+            !method.is_empty() && method.bytes().all(|b| b.is_ascii_graphic()) && Method::from_bytes(method.as_bytes()).is_none() && url.starts_with('/')
+        },
+        _ => false,
+    }
+}
+
+fn parse_headers(fields: &str) -> ReadResult<Headers> {
+    let mut parsed = vec![];
+    for line in fields.split("\r\n") {
+        if line.is_empty() {
+            continue;
+        }
+        let (key, value) = line.split_once(':').ok_or(ReadError::Malformed)?;
+        if key.is_empty() || key.bytes().any(|b| b.is_ascii_whitespace() || b.is_ascii_control()) {
+            return Err(ReadError::Malformed);
+        }
+        parsed.push((key, value.trim()));
+    }
+    Ok(merge_headers(parsed))
+}
+
+/// Combines repeated fields into one header, joining cookies with `; ` and
+/// other values with `, `.
+pub fn merge_headers<'a>(fields: impl IntoIterator<Item = (&'a str, &'a str)>) -> Headers {
+    let mut merged: Vec<(String, String)> = vec![];
+    for (key, value) in fields {
+        match merged.iter_mut().find(|(k, _)| k.eq_ignore_ascii_case(key)) {
+            Some((k, prev)) => {
+                let sep = if k.eq_ignore_ascii_case("Cookie") { "; " } else { ", " };
+                prev.push_str(sep);
+                prev.push_str(value);
+            },
+            None => merged.push((key.to_string(), value.to_string())),
+        }
+    }
+    let mut headers = Headers::new();
+    for (key, value) in merged {
+        headers.insert(key, value);
+    }
+    headers
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+    use super::{RequestReader, ReadError};
+
+    async fn reader_for(input: &[u8], max_body: usize) -> (RequestReader, tokio::io::DuplexStream) {
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        client.write_all(input).await.unwrap();
+        client.shutdown().await.unwrap();
+        (RequestReader::new(max_body), server)
+    }
+
+    #[tokio::test]
+    async fn pipelined() {
+        let input = b"POST /a HTTP/1.1\r\nContent-Length: 5\r\n\r\nhelloGET /b HTTP/1.1\r\nhost: x\r\n\r\n";
+        let (mut reader, mut stream) = reader_for(input, 1024).await;
+        let first = reader.read_request(&mut stream).await.unwrap();
+        assert_eq!(first.request_line.url, "/a");
+        assert_eq!(first.body.unwrap().as_slice(), b"hello");
+        let second = reader.read_request(&mut stream).await.unwrap();
+        assert_eq!(second.request_line.url, "/b");
+        assert_eq!(second.headers.get("Host").unwrap(), "x");
+        assert!(second.body.is_none());
+        assert!(matches!(reader.read_request(&mut stream).await, Err(ReadError::Closed)));
+    }
+
+    #[tokio::test]
+    async fn chunked() {
+        let input = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nWiki\r\n6;ext=1\r\npedia \r\n0\r\nTrailer: x\r\n\r\n";
+        let (mut reader, mut stream) = reader_for(input, 1024).await;
+        let message = reader.read_request(&mut stream).await.unwrap();
+        assert_eq!(message.body.unwrap().as_slice(), b"Wikipedia ");
+    }
+
+    #[tokio::test]
+    async fn too_large() {
+        let input = b"POST / HTTP/1.1\r\nContent-Length: 2048\r\n\r\n";
+        let (mut reader, mut stream) = reader_for(input, 1024).await;
+        assert!(matches!(reader.read_request(&mut stream).await, Err(ReadError::TooLarge)));
+        let input = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n800\r\n";
+        let (mut reader, mut stream) = reader_for(input, 1024).await;
+        assert!(matches!(reader.read_request(&mut stream).await, Err(ReadError::TooLarge)));
+        let input = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nWiki\r\nffffffffffffffff\r\n";
+        let (mut reader, mut stream) = reader_for(input, 1024).await;
+        assert!(matches!(reader.read_request(&mut stream).await, Err(ReadError::TooLarge)));
+    }
+
+    #[tokio::test]
+    async fn trailers_too_large() {
+        let mut input = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nWiki\r\n0\r\n".to_vec();
+        for _ in 0..super::MAX_HEAD / 8 + 1 {
+            input.extend_from_slice(b"X: abc\r\n");
+        }
+        input.extend_from_slice(b"\r\n");
+        let (mut client, mut stream) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(async move {
+            let _ = client.write_all(&input).await;
+        });
+        let mut reader = RequestReader::new(1024);
+        assert!(matches!(reader.read_request(&mut stream).await, Err(ReadError::TooLarge)));
+    }
+
+    #[tokio::test]
+    async fn head_split_across_reads() {
+        let (mut client, mut server) = tokio::io::duplex(64 * 1024);
+        let mut reader = RequestReader::new(1024);
+        let read = tokio::spawn(async move {
+            reader.read_request(&mut server).await.map(|message| message.request_line.url)
+        });
+        for part in [&b"GET /split HTTP/1.1\r"[..], b"\nHost: x\r\n\r", b"\n"] {
+            client.write_all(part).await.unwrap();
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(read.await.unwrap().unwrap(), "/split");
+    }
+}
@@ -1,4 +1,7 @@
 use std::ops::DerefMut;
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::sync::Arc;
 use std::marker::PhantomData;
 use std::num::ParseIntError;
 use std::result;
@@ -12,7 +15,8 @@ use sqlx::{Type, Decode, Database, database::HasValueRef};
 use rand::Rng;
 
 use crate::web::{BaseRequest, Parameters, Result};
-use crate::db::{Db, DbRow, DbRowVec, DbPool, DbTypeInfo, invalid, escape, Count, Whose, WhoseArg, DeleteWhose, OrderBy, OrderByArg, Limit};
+use crate::dialect::DIALECT;
+use crate::db::{Arg, Db, DbRow, DbRowVec, DbPool, DbTypeInfo, invalid, escape, quote, Builder, Column, Count, Whose, WhoseArg, DeleteWhose, OrderBy, OrderByArg, Limit};
 use crate::admin_site::AdminField;
 pub use crate::datetime::DateTime;
 
@@ -105,6 +109,9 @@ impl ToSql for Boolean {
     fn to_sql(&self) -> String {
         format!("{}", self.b)
     }
+    fn to_arg(&self) -> Arg {
+        Arg::Bool(self.b)
+    }
 }
 
 impl_decode!(Boolean, bool);
@@ -153,6 +160,9 @@ impl ToSql for BigInt {
     fn to_sql(&self) -> String {
         format!("{}", self.n)
     }
+    fn to_arg(&self) -> Arg {
+        Arg::Int(self.n)
+    }
 }
 
 impl Type<Db> for BigInt {
@@ -224,6 +234,9 @@ impl ToSql for Text {
     fn to_sql(&self) -> String {
         escape(&self.s)
     }
+    fn to_arg(&self) -> Arg {
+        Arg::Text(self.s.clone())
+    }
 }
 
 impl fmt::Display for Text {
@@ -319,12 +332,18 @@ impl<const N: u16> ToSql for VarChar<N> {
     fn to_sql(&self) -> String {
         escape(&self.s)
     }
+    fn to_arg(&self) -> Arg {
+        Arg::Text(self.s.clone())
+    }
 }
 
 impl<'a, const N: u16> ToSql for &'a VarChar<N> {
     fn to_sql(&self) -> String {
         escape(&self.s)
     }
+    fn to_arg(&self) -> Arg {
+        Arg::Text(self.s.clone())
+    }
 }
 
 impl<const N: u16> fmt::Display for VarChar<N> {
@@ -360,6 +379,7 @@ where String: Decode<'r, DB> {
 pub struct ForeignKey<M: Record, O: OnDelete = Cascade> {
     pk: M::Pk,
     o: PhantomData<O>,
+    cached: Option<Arc<M>>,
 }
 
 impl<M: Record, O: OnDelete> Clone for ForeignKey<M, O> {
@@ -367,16 +387,24 @@ impl<M: Record, O: OnDelete> Clone for ForeignKey<M, O> {
         Self {
             pk: self.pk.clone(),
             o: PhantomData,
+            cached: self.cached.clone(),
         }
     }
 }
 
 impl<M: Record, O: OnDelete> ForeignKey<M, O> {
     pub fn new(m: &M) -> Self {
-        Self {pk: m.pk().clone(), o: PhantomData}
+        Self {pk: m.pk().clone(), o: PhantomData, cached: None}
     }
     pub fn from_data(t: <M as Record>::Pk) -> Result<Self> {
-        Ok(Self {pk: t, o: PhantomData})
+        Ok(Self {pk: t, o: PhantomData, cached: None})
+    }
+    /// The record loaded with `select_related`, if it was.
+    pub fn cached(&self) -> Option<&M> {
+        self.cached.as_deref()
+    }
+    pub fn cache(&mut self, m: M) {
+        self.cached = Some(Arc::new(m));
     }
     pub fn pk(&self) -> M::Pk {
         self.pk.clone()
@@ -396,7 +424,7 @@ impl<M: Record, O: OnDelete> DataType for ForeignKey<M, O> where M::Pk: std::fmt
     type T = <<M as Record>::Pk as DataType>::T;
 
     fn from_val(t: <<M as Record>::Pk as DataType>::T) -> Result<Self> {
-        Ok(Self {pk: M::Pk::from_val(t)?, o: PhantomData})
+        Ok(Self {pk: M::Pk::from_val(t)?, o: PhantomData, cached: None})
     }
 }
 
@@ -404,6 +432,9 @@ impl<M: Record, O: OnDelete> ToSql for ForeignKey<M, O> where M::Pk: std::fmt::D
     fn to_sql(&self) -> String {
         self.pk.to_sql()
     }
+    fn to_arg(&self) -> Arg {
+        self.pk.to_arg()
+    }
 }
 
 impl<M: Record, O: OnDelete> DataType for Option<ForeignKey<M, O>> where M::Pk: std::fmt::Display {
@@ -426,6 +457,37 @@ impl<M: Record, O: OnDelete> ToSql for Option<ForeignKey<M, O>> where M::Pk: std
             "NULL".to_string()
         }
     }
+    fn to_arg(&self) -> Arg {
+        match self {
+            Some(s) => s.to_arg(),
+            None => Arg::Null,
+        }
+    }
+}
+
+/// A foreign key field that `select_related` can cache the record it refers to on.
+pub trait Related {
+    type Record: Record;
+    /// Caches the record read from a row of the join, unless the key is null.
+    fn cache_row(&mut self, row: DbRow) -> Result<()>;
+}
+
+impl<M: Record, O: OnDelete> Related for ForeignKey<M, O> {
+    type Record = M;
+    fn cache_row(&mut self, row: DbRow) -> Result<()> {
+        self.cache(M::get(row)?);
+        Ok(())
+    }
+}
+
+impl<M: Record, O: OnDelete> Related for Option<ForeignKey<M, O>> {
+    type Record = M;
+    fn cache_row(&mut self, row: DbRow) -> Result<()> {
+        if let Some(fk) = self {
+            fk.cache_row(row)?;
+        }
+        Ok(())
+    }
 }
 
 impl<M: Record, O: OnDelete> fmt::Display for ForeignKey<M, O> where M::Pk: std::fmt::Display {
@@ -452,51 +514,128 @@ where <<M as Record>::Pk as DataType>::T: Decode<'r, DB>, <M as Record>::Pk: std
 #[derive(Debug, Clone)]
 pub struct ManyToMany<M: Record> {
     m: PhantomData<M>,
+    cached: Option<Arc<Vec<M>>>,
 }
 
 impl<M: Record> ManyToMany<M> {
     pub fn new() -> Self {
-        Self {m: PhantomData}
+        Self {m: PhantomData, cached: None}
+    }
+    /// The records loaded with `prefetch_related`, if they were.
+    pub fn cached(&self) -> Option<&[M]> {
+        self.cached.as_ref().map(|v| v.as_slice())
+    }
+    pub fn cache(&mut self, v: Vec<M>) {
+        self.cached = Some(Arc::new(v));
     }
 }
 
-pub struct Objects<M>(Vec<M>);
+/// Column the prefetched records of a many-to-many field are grouped by.
+const PREFETCH_OWNER: &str = "prefetch_owner";
+
+pub struct Objects<M> {
+    v: Vec<M>,
+    children: HashMap<(TypeId, String), Box<dyn Any + Send + Sync>>,
+}
 
 impl<M: Record> Objects<M> {
     pub fn pks(&self) -> Vec<M::Pk> {
         let mut v = vec![];
-        for d in &self.0 {
+        for d in &self.v {
             v.push(d.pk());
         }
         v
     }
     pub async fn parents<P: Record, O: OnDelete, B: BaseRequest>(&self, req: &B, f: for<'a> fn(&'a M) -> &'a ForeignKey<P, O>) -> Result<Objects<P>> {
-        if self.0.is_empty() {
+        if self.v.is_empty() {
             return Ok(Objects::<P>::new());
         }
         let mut v = vec![];
-        for d in &self.0 {
+        for d in &self.v {
             v.push(f(d).pk());
         }
         P::find_in(&v).query(req).await
     }
+    /// Loads the records of a many-to-many field of all the objects in one query,
+    /// caching them on the field returned by `f`.
+    pub async fn prefetch_related<P: Record, B: BaseRequest>(&mut self, req: &B, f: for<'a> fn(&'a mut M) -> &'a mut ManyToMany<P>) -> Result<()>
+    where M: Record<Pk = BigInt> {
+        self.raw_prefetch_related(req.raw().pool(), f).await
+    }
+    pub async fn raw_prefetch_related<P: Record>(&mut self, pool: &DbPool, f: for<'a> fn(&'a mut M) -> &'a mut ManyToMany<P>) -> Result<()>
+    where M: Record<Pk = BigInt> {
+        let mut related: HashMap<i64, Vec<P>> = HashMap::new();
+        if !self.v.is_empty() {
+            let (owner, other) = (M::NAME.to_lowercase(), P::NAME.to_lowercase());
+            let join = format!("{}_{}", M::table_name(), other);
+            let table = P::table_name();
+            let owner_column = format!("{}.{}", join, owner);
+            let b = Builder::<P>::select(P::COLUMNS, &table)
+                .select_as(&quote(&owner_column), PREFETCH_OWNER)
+                .inner_join(&join, &table, P::PK_NAME, &other)
+                .whose()
+                .append(Column::<P, BigInt>::new(&owner_column).is_in(&self.pks()).builder());
+            let (val, args) = b.build();
+            for row in pool.query_with(&val, args).await? {
+                let owner: i64 = row.try_get(PREFETCH_OWNER)?;
+                related.entry(owner).or_default().push(P::get(row)?);
+            }
+        }
+        for m in &mut self.v {
+            let v = related.remove(&m.pk().as_i64()).unwrap_or_default();
+            f(m).cache(v);
+        }
+        Ok(())
+    }
+    /// Loads the records whose foreign key column refers to one of the objects in one
+    /// query, caching them for `children`.
+    ///
+    /// ```ignore
+    /// posts.prefetch_children(&req, comment::post(), |c| &c.post).await?;
+    /// for post in posts.iter() {
+    ///     let comments: &[Comment] = posts.children(comment::post(), post);
+    /// }
+    /// ```
+    pub async fn prefetch_children<C: Record + Send + Sync + 'static, O: OnDelete, B: BaseRequest>(&mut self, req: &B, column: Column<C, BigInt>, f: for<'a> fn(&'a C) -> &'a ForeignKey<M, O>) -> Result<()>
+    where M: Record<Pk = BigInt> {
+        self.raw_prefetch_children(req.raw().pool(), column, f).await
+    }
+    pub async fn raw_prefetch_children<C: Record + Send + Sync + 'static, O: OnDelete>(&mut self, pool: &DbPool, column: Column<C, BigInt>, f: for<'a> fn(&'a C) -> &'a ForeignKey<M, O>) -> Result<()>
+    where M: Record<Pk = BigInt> {
+        let key = (TypeId::of::<C>(), column.name().to_string());
+        let mut children: HashMap<i64, Vec<C>> = HashMap::new();
+        if !self.v.is_empty() {
+            for child in C::whose(column.is_in(&self.pks())).get_all().raw_query(pool).await? {
+                children.entry(f(&child).pk().as_i64()).or_default().push(child);
+            }
+        }
+        self.children.insert(key, Box::new(children));
+        Ok(())
+    }
+    /// The records whose `column` refers to an object, loaded with `prefetch_children`.
+    pub fn children<C: Record + 'static>(&self, column: Column<C, BigInt>, m: &M) -> &[C] where M: Record<Pk = BigInt> {
+        self.children.get(&(TypeId::of::<C>(), column.name().to_string()))
+            .and_then(|children| children.downcast_ref::<HashMap<i64, Vec<C>>>())
+            .and_then(|children| children.get(&m.pk().as_i64()))
+            .map_or(&[], |v| v.as_slice())
+    }
 }
 
 impl<M: Record> Objects<M> {
     pub fn new() -> Self {
-        Self {0: vec![]}
+        Self {v: vec![], children: HashMap::new()}
     }
     pub fn len(&self) -> usize {
-        self.0.len()
+        self.v.len()
     }
     pub fn push(&mut self, value: M) {
-        self.0.push(value);
+        self.v.push(value);
     }
     pub fn pop(&mut self) -> Option<M> {
-        self.0.pop()
+        self.v.pop()
     }
     pub fn swap_remove(&mut self, index: usize) -> M {
-        self.0.swap_remove(index)
+        self.v.swap_remove(index)
     }
 }
 
@@ -505,20 +644,20 @@ impl<M: Record> IntoIterator for Objects<M> {
     type IntoIter = std::vec::IntoIter<M>;
     #[inline]
     fn into_iter(self) -> Self::IntoIter {
-        self.0.into_iter()
+        self.v.into_iter()
     }
 }
 
 impl<M: Record> Deref for Objects<M> {
     type Target = Vec<M>;
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.v
     }
 }
 
 impl<M: Record> DerefMut for Objects<M> {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
+        &mut self.v
     }
 }
 
@@ -582,13 +721,13 @@ impl RecordField {
         self
     }
     pub fn foreign_key(mut self, app_name: &'static str, other_name: &'static str, pk_name: &'static str) -> Self {
-        self.constraints.push(format!("FOREIGN KEY (\"{}\")", other_name));
-        self.constraints.push(format!("REFERENCES \"{}_{}\" (\"{}\")", app_name, other_name, pk_name));
+        self.constraints.push(format!("FOREIGN KEY ({})", quote(other_name)));
+        self.constraints.push(format!("REFERENCES {} ({})", quote(&format!("{}_{}", app_name, other_name)), quote(pk_name)));
         self.constraints.push("ON DELETE CASCADE".to_string());
         self
     }
     pub fn to_syntax(&self) -> (String, Vec<String>) {
-        let mut s = format!("{}", self.ty);
+        let mut s = DIALECT.column_type(&self.ty);
         if !self.null {
             s.push_str(" NOT NULL");
         }
@@ -622,6 +761,12 @@ impl<const N: u16> ToSql for Option<VarChar<N>> {
             "NULL".to_string()
         }
     }
+    fn to_arg(&self) -> Arg {
+        match self {
+            Some(s) => s.to_arg(),
+            None => Arg::Null,
+        }
+    }
 }
 
 impl DataType for Option<Text> {
@@ -644,24 +789,39 @@ impl ToSql for Option<Text> {
             "NULL".to_string()
         }
     }
+    fn to_arg(&self) -> Arg {
+        match self {
+            Some(s) => s.to_arg(),
+            None => Arg::Null,
+        }
+    }
 }
 
 impl<'a> ToSql for &'a str {
     fn to_sql(&self) -> String {
         escape(self)
     }
+    fn to_arg(&self) -> Arg {
+        Arg::Text(self.to_string())
+    }
 }
 
 impl ToSql for String {
     fn to_sql(&self) -> String {
         escape(self)
     }
+    fn to_arg(&self) -> Arg {
+        Arg::Text(self.to_string())
+    }
 }
 
 impl ToSql for &String {
     fn to_sql(&self) -> String {
         escape(self)
     }
+    fn to_arg(&self) -> Arg {
+        Arg::Text(self.to_string())
+    }
 }
 
 pub trait DataType: Clone + ToSql + private::Sealed {
@@ -671,6 +831,8 @@ pub trait DataType: Clone + ToSql + private::Sealed {
 
 pub trait ToSql: private::Sealed {
     fn to_sql(&self) -> String;
+    /// The value bound to a query parameter in place of the literal.
+    fn to_arg(&self) -> Arg;
 }
 
 #[async_trait]
@@ -703,6 +865,8 @@ pub trait Record: Sized {
     type Pk: DataType;
     const NAME: &'static str;
     const PK_NAME: &'static str;
+    /// The columns of the record's table.
+    const COLUMNS: &'static [&'static str];
     fn pk(&self) -> Self::Pk;
     fn pk_mut(&mut self) -> &mut Self::Pk;
     fn table_name() -> String;
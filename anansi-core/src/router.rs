@@ -1,86 +1,427 @@
 use tokio::fs;
 use std::collections::HashMap;
+use std::str::FromStr;
 use crate::db::invalid;
-use crate::web::{Route, Response, BASE_DIR, Result, View, BaseRequest};
+use crate::web::{Route, Response, Status, Method, BASE_DIR, Result, View, Guard, BaseRequest, Headers, Parameters};
+use crate::static_files::{self, StaticFile, IMMUTABLE, accepted_encodings, is_hashed, is_valid_path, mime_type};
 
 const SLASH: u8 = 47;
 const LEFT_BRACE: u8 = 123;
 const RIGHT_BRACE: u8 = 125;
 
-pub type Routes<B> = Vec<(Vec<String>, View<B>)>;
+pub type Routes<B> = RouteTree<Vec<(Method, View<B>, Vec<Guard<B>>)>>;
+
+/// Methods handled by views registered with `path`.
+const PATH_METHODS: &[Method] = &[Method::Get, Method::Post];
 
 pub struct Router<B: BaseRequest + 'static> {
     pub routes: Routes<B>,
     pub handle_404: View<B>,
     pub internal_error: Response,
     pub login_url: String,
-    files: HashMap<&'static str, &'static [u8]>,
+    files: HashMap<&'static str, StaticFile>,
+    static_root: Option<String>,
 }
 
 impl<B: BaseRequest> Router<B> {
-    pub fn new(routes: Vec<Route<B>>, handle_404: View<B>, internal_error: Response, login_url: String, files: HashMap<&'static str, &'static [u8]>) -> Result<Self> {
-        let mut router = Self {routes: vec![], handle_404, internal_error, login_url, files};
-        let mut v = vec![];
-        for route in routes {
-            match route {
-                Route::Path((url, f)) => {
-                    v.push(((*url).to_string(), f));
-                },
-                Route::Import((url, r)) => {
-                    for rt in r {
-                        match rt {
-                            Route::Path((u, f)) => {
-                                v.push((format!("{}/{}", url, *u), *f));
-                            },
-                            _ => unimplemented!(),
-                        }
-                    }
+    pub fn new(routes: Vec<Route<B>>, handle_404: View<B>, internal_error: Response, login_url: String, files: HashMap<&'static str, &'static [u8]>, static_root: Option<String>) -> Result<Self> {
+        let files = files.into_iter().map(|(name, content)| (name, StaticFile::embedded(content))).collect();
+        let mut router = Self {routes: RouteTree::new(), handle_404, internal_error, login_url, files, static_root};
+        let mut mounts: HashMap<usize, String> = HashMap::new();
+        for endpoint in flatten("", &routes, &[]) {
+            let mount = mounts.entry(endpoint.view as usize).or_insert_with(|| endpoint.url.clone());
+            if *mount != endpoint.url {
+                tracing::warn!("View mounted at both \"{}\" and \"{}\", url! will reverse to the first", mount, endpoint.url);
+            }
+            let views = router.routes.entry(&endpoint.url)?;
+            for method in endpoint.methods {
+                if views.iter().any(|(m, _, _)| m == method) {
+                    return Err(format!("duplicate route {} \"{}\"", method.as_str(), endpoint.url).into());
                 }
+                views.push((*method, endpoint.view, endpoint.guards.clone()));
             }
         }
-        for (url, f) in v {
-            let cap = get_capture(&url)?;
-            router.routes.push((cap, f));
-        }
         Ok(router)
     }
-    pub async fn serve_static(&self, url: &str) -> Result<Response> {
-        let mut period = false;
-        for c in url.chars() {
-            if (c >= '0' && c <= '9') || (c >= 'A' && c <= 'Z') || (c >= 'a' && c <= 'z') || c == '-' || c == '/' || c == '_'  {
-                if period {
-                    period = false;
+    /// Serves a file collected into the static root, embedded with `app_statics!`
+    /// or found under the project directory, preferring a precompressed variant
+    /// that the client accepts. Files with hashed names are cached indefinitely.
+    pub async fn serve_static(&self, url: &str, headers: &Headers) -> Result<Response> {
+        let mut response = self.find_and_serve(url, headers).await?;
+        if response.status() != Status::NotFound && is_hashed(url.trim_start_matches("/static/")) {
+            response.headers_mut().insert("Cache-Control".to_string(), IMMUTABLE.to_string());
+        }
+        Ok(response)
+    }
+    async fn find_and_serve(&self, url: &str, headers: &Headers) -> Result<Response> {
+        if !is_valid_path(url) {
+            return Ok(Response::not_found());
+        }
+        let path = match self.find_static(url).await {
+            Some(path) => path,
+            None => return Ok(Response::not_found()),
+        };
+        for (encoding, suffix) in accepted_encodings(headers) {
+            if let Some(variant) = self.find_static(&format!("{}{}", url, suffix)).await {
+                if let Some(file) = self.read_static(variant).await? {
+                    return static_files::respond(file, mime_type(url), Some(encoding), headers).await;
                 }
-                continue;
-            } else if c == '.' {
-                if !period {
-                    period = true;
-                    continue;
+            }
+        }
+        match self.read_static(path).await? {
+            Some(file) => static_files::respond(file, mime_type(url), None, headers).await,
+            None => Ok(Response::not_found()),
+        }
+    }
+    /// Where a static file is, if it is embedded or inside the project directory.
+    async fn find_static<'a>(&self, url: &'a str) -> Option<StaticPath<'a>> {
+        if let Some(root) = &self.static_root {
+            let full = format!("{}/{}", root, url.trim_start_matches("/static/"));
+            if let (Ok(path), Ok(root)) = (fs::canonicalize(&full).await, fs::canonicalize(root).await) {
+                if path.starts_with(root) {
+                    return Some(StaticPath::Disk(full));
                 }
             }
-            return Err(invalid());
         }
-        if let Some(f) = self.files.get(url) {
-            return self.serve_content(url, f.to_vec());
-        };
+        if self.files.contains_key(url) {
+            return Some(StaticPath::Embedded(url));
+        }
         let mut base = String::new();
         BASE_DIR.with(|b| base = b.clone());
         let full = format!("{}{}", base, url);
-        let path = fs::canonicalize(&full).await?;
-        if path.starts_with(base) {
-            self.serve_content(url, fs::read(full).await?)
-        } else {
-            Err(invalid())
+        match fs::canonicalize(&full).await {
+            Ok(path) if path.starts_with(base) => Some(StaticPath::Disk(full)),
+            _ => None,
+        }
+    }
+    async fn read_static(&self, path: StaticPath<'_>) -> Result<Option<StaticFile>> {
+        match path {
+            StaticPath::Embedded(url) => Ok(self.files.get(url).cloned()),
+            StaticPath::Disk(full) => StaticFile::read(&full).await,
+        }
+    }
+}
+
+enum StaticPath<'a> {
+    Embedded(&'a str),
+    Disk(String),
+}
+
+/// A view from `flatten` with its full pattern.
+pub struct Endpoint<B: BaseRequest + 'static> {
+    pub url: String,
+    pub methods: &'static [Method],
+    pub view: View<B>,
+    /// The guards of every import the view is nested in, outermost first.
+    pub guards: Vec<Guard<B>>,
+}
+
+/// Resolves imports at any depth, prefixing each pattern with the patterns of
+/// the imports it is nested in.
+pub fn flatten<B: BaseRequest>(prefix: &str, routes: &[Route<B>], guards: &[Guard<B>]) -> Vec<Endpoint<B>> {
+    let mut endpoints = vec![];
+    for route in routes {
+        match route {
+            Route::Path((url, view)) => {
+                endpoints.push(Endpoint {url: join(prefix, url), methods: PATH_METHODS, view: *view, guards: guards.to_vec()});
+            },
+            Route::Method((method, url, view)) => {
+                endpoints.push(Endpoint {url: join(prefix, url), methods: method_slice(*method), view: *view, guards: guards.to_vec()});
+            },
+            Route::Import((url, r, g)) => {
+                let nested: Vec<Guard<B>> = guards.iter().chain(g.iter()).copied().collect();
+                endpoints.extend(flatten(&join(prefix, url), r, &nested));
+            },
         }
     }
-    fn serve_content(&self, url: &str, content: Vec<u8>) -> Result<Response> {
-        let n = url.rfind('.').ok_or(invalid())?;
-        let ty = match &url[n+1..] {
-            "css" => "text/css",
+    endpoints
+}
+
+/// Adds the pattern of every view in `routes` to `urls`, split with `get_capture`,
+/// for `Reverse` to fill in. A view mounted more than once reverses to its first mount.
+pub fn reverse_urls<B: BaseRequest>(routes: &[Route<B>], urls: &mut HashMap<usize, Vec<String>>) -> Result<()> {
+    for endpoint in flatten("", routes, &[]) {
+        let capture = get_capture(&endpoint.url)?;
+        urls.entry(endpoint.view as usize).or_insert(capture);
+    }
+    Ok(())
+}
+
+fn join(prefix: &str, url: &str) -> String {
+    if prefix.is_empty() {
+        url.to_string()
+    } else {
+        format!("{}/{}", prefix.trim_end_matches('/'), url.trim_start_matches('/'))
+    }
+}
+
+fn method_slice(method: Method) -> &'static [Method] {
+    match method {
+        Method::Get => &[Method::Get],
+        Method::Post => &[Method::Post],
+        Method::Put => &[Method::Put],
+        Method::Patch => &[Method::Patch],
+        Method::Delete => &[Method::Delete],
+        Method::Head => &[Method::Head],
+        Method::Options => &[Method::Options],
+    }
+}
+
+/// Restricts what a `{name:converter}` capture in a route pattern matches.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Converter {
+    /// Any non-empty segment, used when no converter is given.
+    Str,
+    /// ASCII digits.
+    Int,
+    /// ASCII letters, digits, hyphens and underscores.
+    Slug,
+    /// A hyphenated UUID such as `0f8fad5b-d9cb-469f-a165-70867728950e`.
+    Uuid,
+    /// The rest of the path, including slashes. Must be the last capture.
+    Path,
+}
+
+impl Converter {
+    /// Splits a capture from `get_capture`, such as `{id:int`, into its name and converter.
+    pub fn from_capture(capture: &str) -> Result<(&str, Self)> {
+        let capture = capture.strip_prefix('{').unwrap_or(capture);
+        let (name, converter) = match capture.split_once(':') {
+            Some((name, converter)) => (name, converter),
+            None => return Ok((capture, Self::Str)),
+        };
+        let converter = match converter {
+            "str" => Self::Str,
+            "int" => Self::Int,
+            "slug" => Self::Slug,
+            "uuid" => Self::Uuid,
+            "path" => Self::Path,
             _ => return Err(invalid()),
         };
-        Ok(Response::content("HTTP/1.1 200 OK", ty, content))
+        Ok((name, converter))
+    }
+    pub fn matches(&self, value: &str) -> bool {
+        if value.is_empty() {
+            return false;
+        }
+        match self {
+            Self::Str => !value.contains('/'),
+            Self::Int => value.bytes().all(|b| b.is_ascii_digit()),
+            Self::Slug => value.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_'),
+            Self::Uuid => {
+                let groups: Vec<&str> = value.split('-').collect();
+                groups.len() == 5 && groups.iter().zip([8, 4, 4, 4, 12]).all(|(g, n)| g.len() == n && g.bytes().all(|b| b.is_ascii_hexdigit()))
+            },
+            Self::Path => true,
+        }
+    }
+    /// Whether some value is accepted by both converters.
+    fn overlaps(&self, other: &Self) -> bool {
+        !matches!((self, other), (Self::Int, Self::Uuid) | (Self::Uuid, Self::Int))
+    }
+}
+
+/// A prefix tree of route patterns with one level per path segment.
+///
+/// A path is matched by a single pattern, found by trying static segments
+/// before captures, and a capture only if the static branch has no match
+/// further down. Captures at the same position must have disjoint converters,
+/// so that at most one of them can match. The methods of other patterns that
+/// would match the path are never considered.
+pub struct RouteTree<T> {
+    root: Node<T>,
+}
+
+struct Node<T> {
+    value: Option<T>,
+    statics: HashMap<String, Node<T>>,
+    captures: Vec<Capture<T>>,
+}
+
+struct Capture<T> {
+    name: String,
+    converter: Converter,
+    pattern: String,
+    node: Node<T>,
+}
+
+impl<T> Node<T> {
+    fn new() -> Self {
+        Self {value: None, statics: HashMap::new(), captures: vec![]}
+    }
+    fn find<'a>(&'a self, dirs: &[&str], captured: &mut Vec<(&'a str, String)>) -> Option<(&'a T, Parameters)> {
+        let dir = match dirs.first() {
+            Some(dir) => dir,
+            None => {
+                let value = self.value.as_ref()?;
+                let mut params = Parameters::new();
+                for (name, value) in captured.iter() {
+                    params.insert(name.to_string(), value.clone());
+                }
+                return Some((value, params));
+            },
+        };
+        if let Some(found) = self.statics.get(*dir).and_then(|node| node.find(&dirs[1..], captured)) {
+            return Some(found);
+        }
+        for capture in &self.captures {
+            let (value, rest) = if capture.converter == Converter::Path {
+                (dirs.concat(), &dirs[dirs.len()..])
+            } else {
+                (dir.to_string(), &dirs[1..])
+            };
+            if capture.converter.matches(&value[1..]) {
+                captured.push((&capture.name, value[1..].to_string()));
+                let found = capture.node.find(rest, captured);
+                captured.pop();
+                if found.is_some() {
+                    return found;
+                }
+            }
+        }
+        None
+    }
+}
+
+impl<T> RouteTree<T> {
+    pub fn new() -> Self {
+        Self {root: Node::new()}
+    }
+    /// Whether any pattern matches `path`.
+    pub fn matches(&self, path: &str) -> bool {
+        match split_url(path) {
+            Ok(dirs) => self.find(&dirs).is_some(),
+            Err(_) => false,
+        }
+    }
+    /// The value of the pattern matching the segments from `split_url`, with
+    /// the captured parameters.
+    pub fn find<'a>(&'a self, dirs: &[&str]) -> Option<(&'a T, Parameters)> {
+        self.root.find(dirs, &mut vec![])
+    }
+}
+
+impl<T: Default> RouteTree<T> {
+    /// The value for `url`, inserting a default one if the pattern is new.
+    ///
+    /// Fails if `url` is malformed or could match the same paths as a
+    /// different pattern, such as `/post/{id}` and `/post/{slug:slug}`.
+    pub fn entry(&mut self, url: &str) -> Result<&mut T> {
+        let segments = get_capture(url)?;
+        check_converters(url, &segments)?;
+        let mut node = &mut self.root;
+        for segment in &segments {
+            node = if segment.as_bytes()[0] == LEFT_BRACE {
+                let (name, converter) = Converter::from_capture(segment)?;
+                let mut existing = None;
+                for (n, capture) in node.captures.iter().enumerate() {
+                    if capture.name == name && capture.converter == converter {
+                        existing = Some(n);
+                    } else if capture.converter.overlaps(&converter) {
+                        return Err(format!("route \"{}\" is ambiguous with \"{}\"", url, capture.pattern).into());
+                    }
+                }
+                let n = match existing {
+                    Some(n) => n,
+                    None => {
+                        node.captures.push(Capture {name: name.to_string(), converter, pattern: url.to_string(), node: Node::new()});
+                        node.captures.len() - 1
+                    },
+                };
+                &mut node.captures[n].node
+            } else {
+                node.statics.entry(segment.clone()).or_insert_with(Node::new)
+            };
+        }
+        Ok(node.value.get_or_insert_with(T::default))
+    }
+}
+
+impl<T> Default for RouteTree<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// What to do with a path that has no route, but would have one with a
+/// trailing slash added or removed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TrailingSlash {
+    /// Treat `/blog` and `/blog/` as different paths.
+    Off,
+    /// Redirect `/blog` to `/blog/`.
+    Append,
+    /// Redirect `/blog/` to `/blog`.
+    Strip,
+}
+
+impl TrailingSlash {
+    /// `path` with the slash added or removed, if the policy applies to it.
+    pub fn toggle(&self, path: &str) -> Option<String> {
+        match self {
+            Self::Append if !path.ends_with('/') => Some(format!("{}/", path)),
+            Self::Strip if path.len() > 1 && path.ends_with('/') => Some(path[..path.len() - 1].to_string()),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for TrailingSlash {
+    type Err = Box<dyn std::error::Error + Send + Sync>;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "off" => Ok(Self::Off),
+            "append" => Ok(Self::Append),
+            "strip" => Ok(Self::Strip),
+            _ => Err(format!("unknown trailing slash policy \"{}\"", s).into()),
+        }
+    }
+}
+
+/// Decodes the percent-encoded segments of a request path before matching.
+///
+/// `%2F` is left encoded so that it cannot split a segment in two. Paths with
+/// `.` or `..` segments or NUL bytes are rejected.
+pub fn normalize_path(path: &str) -> Result<String> {
+    let mut normalized = String::with_capacity(path.len());
+    for (n, segment) in path.split('/').enumerate() {
+        if n > 0 {
+            normalized.push('/');
+        }
+        let mut bytes = Vec::with_capacity(segment.len());
+        let mut iter = segment.bytes();
+        while let Some(b) = iter.next() {
+            if b == b'%' {
+                let hex = [iter.next().ok_or_else(invalid)?, iter.next().ok_or_else(invalid)?];
+                let decoded = u8::from_str_radix(std::str::from_utf8(&hex)?, 16)?;
+                if decoded == b'/' {
+                    bytes.extend_from_slice(b"%2F");
+                } else {
+                    bytes.push(decoded);
+                }
+            } else {
+                bytes.push(b);
+            }
+        }
+        let segment = String::from_utf8(bytes)?;
+        if segment == "." || segment == ".." || segment.contains('\0') {
+            return Err(invalid());
+        }
+        normalized.push_str(&segment);
     }
+    Ok(normalized)
+}
+
+fn check_converters(url: &str, captures: &[String]) -> Result<()> {
+    for (n, capture) in captures.iter().enumerate() {
+        if capture.as_bytes()[0] == LEFT_BRACE {
+            let (_, converter) = Converter::from_capture(capture).map_err(|_| format!("unknown converter in route \"{}\"", url))?;
+            if converter == Converter::Path && n + 1 != captures.len() {
+                return Err(format!("path converter must be last in route \"{}\"", url).into());
+            }
+        }
+    }
+    Ok(())
 }
 
 pub fn get_capture(url: &str) -> Result<Vec<String>> {
@@ -157,3 +498,56 @@ pub fn split_url<'a>(url: &'a str) -> Result<Vec<&'a str>> {
     }
     Ok(parts)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{RouteTree, TrailingSlash, normalize_path, split_url};
+
+    #[test]
+    fn route_tree() {
+        let mut tree: RouteTree<Vec<&str>> = RouteTree::new();
+        for url in ["/post/new", "/post/{id:int}", "/post/{id:int}/edit", "/post/{key:uuid}", "/files/{rest:path}", "/{name}/edit"] {
+            tree.entry(url).unwrap().push(url);
+        }
+        let find = |url: &str| tree.find(&split_url(url).unwrap()).map(|(v, p)| (v[0], p));
+        assert_eq!(find("/post/new").unwrap().0, "/post/new");
+        assert_eq!(find("/post/42").unwrap().1.get("id").unwrap(), "42");
+        assert!(find("/post/abc").is_none());
+        assert_eq!(find("/post/0f8fad5b-d9cb-469f-a165-70867728950e").unwrap().0, "/post/{key:uuid}");
+        assert_eq!(find("/files/a/b.txt").unwrap().1.get("rest").unwrap(), "a/b.txt");
+        assert_eq!(find("/new/edit").unwrap().0, "/{name}/edit");
+        assert_eq!(find("/post/edit").unwrap().0, "/{name}/edit");
+
+        assert!(tree.entry("/post/{slug:slug}").is_err());
+        assert!(tree.entry("/post/{pk:int}/edit").is_err());
+        assert!(tree.entry("/post/{id:number}").is_err());
+        assert!(tree.entry("/files/{rest:path}/x").is_err());
+    }
+    #[test]
+    fn path_normalization() {
+        assert_eq!(normalize_path("/caf%C3%A9/a+b").unwrap(), "/café/a+b");
+        assert_eq!(normalize_path("/files/a%2Fb").unwrap(), "/files/a%2Fb");
+        assert_eq!(normalize_path("/blog/").unwrap(), "/blog/");
+        assert!(normalize_path("/a/../etc/passwd").is_err());
+        assert!(normalize_path("/a/%2E%2E/b").is_err());
+        assert!(normalize_path("/a/./b").is_err());
+        assert!(normalize_path("/a%00b").is_err());
+        assert!(normalize_path("/a%zz").is_err());
+        assert!(normalize_path("/a%C3").is_err());
+        assert!(normalize_path("/a..b/...").is_ok());
+
+        assert_eq!(TrailingSlash::Append.toggle("/blog").unwrap(), "/blog/");
+        assert_eq!(TrailingSlash::Append.toggle("/blog/"), None);
+        assert_eq!(TrailingSlash::Strip.toggle("/blog/").unwrap(), "/blog");
+        assert_eq!(TrailingSlash::Strip.toggle("/"), None);
+        assert_eq!(TrailingSlash::Off.toggle("/blog"), None);
+
+        let mut tree: RouteTree<()> = RouteTree::new();
+        tree.entry("/blog/").unwrap();
+        tree.entry("/blog/{id:int}").unwrap();
+        assert!(tree.matches("/blog/"));
+        assert!(!tree.matches("/blog"));
+        assert!(tree.matches("/blog/7"));
+        assert!(!tree.matches("/blog/7/"));
+    }
+}
@@ -1,12 +1,15 @@
-use std::{fmt, env, str};
+use std::{fmt, env, process, str};
 use std::thread::LocalKey;
 use std::sync::{Arc, Mutex};
 use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
 use tokio::net::{TcpListener, TcpStream};
-use tokio::io::AsyncReadExt;
-use tokio::io::AsyncWriteExt;
-use tokio::sync::Semaphore;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{Semaphore, watch};
 use tokio::{fs, time};
+use tracing::Instrument;
 
 use toml;
 use toml::value::Value;
@@ -17,18 +20,31 @@ use rand::rngs::StdRng;
 use rand::SeedableRng;
 use sha2::{Digest, Sha256};
 
-use crate::db::DbPool;
+use crate::db::{DbPool, invalid};
 use crate::records::{VarChar, DateTime, DataType};
-use crate::web::{BASE_DIR, Result, Static, Route, BaseRequest, RawRequest, Response, Http404, WebError, WebErrorKind, View, route_request, path};
-use crate::router::{Router, get_capture, split_url};
+use crate::web::{BASE_DIR, Result, Static, Route, BaseRequest, RawRequest, Response, Chunks, Status, Method, Http404, WebError, WebErrorKind, View, route_request, path};
+use crate::router::{Router, TrailingSlash, get_capture, split_url, normalize_path};
 use crate::migrations::{migrate, sql_migrate, make_migrations, AppMigration};
 use crate::admin_site::AdminRef;
+use crate::reader::{RequestReader, Message, ReadError};
+use crate::middleware::{Chain, Snapshot};
+use crate::log::{self, Access, LogFormat, LogOutput};
+use crate::static_files;
+use crate::compression;
 
 type Timer = Arc<Mutex<DateTime>>;
 
 #[macro_export]
+#[allow(clippy::crate_in_macro_def)]
 macro_rules! main {
     () => {
+        anansi::main!(
+            anansi::util::sessions::middleware::SessionMiddleware,
+            anansi::util::auth::middleware::AuthMiddleware,
+            anansi::middleware::CsrfMiddleware::new(),
+        );
+    };
+    ($($mid:expr),+ $(,)?) => {
         pub mod prelude {
             pub use async_trait::async_trait;
             pub use crate::project::Request;
@@ -46,68 +62,159 @@ macro_rules! main {
             use anansi::web::Response;
             let internal_error = Response::internal_error(include_bytes!("http_errors/500.html"));
             let site = Arc::new(Mutex::new(anansi::util::admin::site::BasicAdminSite::new()));
-            anansi::server::Server::new(APP_STATICS, APP_ADMINS).run(app_url, urls::ROUTES, ErrorView::not_found, internal_error, APP_MIGRATIONS, anansi::util::auth::cmd::admin, site);
+            let middleware: anansi::middleware::Chain<crate::project::HttpRequest> = vec![$(Box::new($mid)),+];
+            anansi::server::Server::new(APP_STATICS, APP_ADMINS).middleware(middleware).run(anansi::server::App {
+                url_mapper: app_url,
+                routes: urls::ROUTES,
+                handle_404: ErrorView::not_found,
+                internal_error,
+                migrations: APP_MIGRATIONS,
+                admin: anansi::util::auth::cmd::admin,
+                site,
+            });
         }
-    }
+    };
 }
 
 pub struct Server<B: BaseRequest + 'static> {
     statics: &'static [&'static [Static]],
     admin_inits: AdminInits<B>,
+    middleware: Chain<B>,
 }
 
 pub type AdminInits<B> = &'static [fn(AdminRef<B>)];
 
+/// The command that creates an admin user, run with `admin`.
+pub type AdminCmd = fn(DbPool) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<()>> + Send>>;
+
+/// The parts of a project the server runs, set up by `main!`.
+pub struct App<B: BaseRequest + 'static> {
+    pub url_mapper: fn(&mut HashMap<usize, Vec<String>>),
+    pub routes: &'static [Route<B>],
+    pub handle_404: View<B>,
+    pub internal_error: Response,
+    pub migrations: &'static [LocalKey<AppMigration>],
+    pub admin: AdminCmd,
+    pub site: AdminRef<B>,
+}
+
 impl<B: BaseRequest + fmt::Debug + Clone> Server<B> {
     pub fn new(statics: &'static [&'static [Static]], admin_inits: AdminInits<B>) -> Self {
-        Self {statics, admin_inits}
+        Self {statics, admin_inits, middleware: vec![]}
     }
-    pub fn run(&mut self, url_mapper: fn(&mut HashMap<usize, Vec<String>>), routes: &[Route<B>], handle_404: View<B>, internal_error: Response, migrations: &'static [LocalKey<AppMigration>], admin: fn(DbPool) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<()>> + Send>>, site: AdminRef<B>) {
-        let rt = tokio::runtime::Runtime::new().unwrap();
-        rt.block_on(async {
-            let addr = "127.0.0.1:9090";
-            let listener = TcpListener::bind(addr).await.unwrap();
+    /// Sets the middleware run around every view, in order.
+    pub fn middleware(mut self, middleware: Chain<B>) -> Self {
+        self.middleware = middleware;
+        self
+    }
+    pub fn run(&mut self, app: App<B>) {
+        let App {url_mapper, routes, handle_404, internal_error, migrations, admin, site} = app;
+        let mut args: Vec<String> = env::args().collect();
 
-            let args: Vec<String> = env::args().collect();
+        let mut base = String::new();
+        BASE_DIR.with(|b| base = b.clone());
+        let dir = format!("{}/{}", base, "settings.toml");
+        let settings = std::fs::read_to_string(&dir).expect("Could not find settings.toml");
+        let settings: Map<String, Value> = toml::from_str(&settings).expect("Could not parse settings.toml");
 
-            let mut base = String::new();
-            BASE_DIR.with(|b| base = b.clone());
-            let dir = format!("{}/{}", base, "settings.toml");
-            let settings = fs::read_to_string(&dir).await.expect("Could not find settings.toml");
-            let settings: Map<String, Value> = toml::from_str(&settings).expect("Could not parse settings.toml");
+        let mut config = ServerConfig::from_settings(&settings).expect("Could not parse server settings");
+        if let Some(n) = args.iter().position(|arg| arg == "--bind") {
+            if n + 1 >= args.len() {
+                eprintln!("expected address after --bind");
+                return;
+            }
+            config.bind(&args[n + 1]).expect("Could not parse --bind address");
+            args.drain(n..n + 2);
+        }
+
+        let _log_guard = log::init(&config.log_level, config.access_log_format, &config.access_log).expect("Could not set up logging");
 
-            let pool = match DbPool::new().await {
+        let mut builder = tokio::runtime::Builder::new_multi_thread();
+        if let Some(workers) = config.worker_threads {
+            builder.worker_threads(workers);
+        }
+        let rt = builder.enable_all().build().unwrap();
+        rt.block_on(async {
+            let database_url = config.database_url.clone();
+            let pool = match connect(database_url.as_deref()).await {
                 Ok(p) => p,
-                Err(_) => {
-                    let p = DbPool::new().await.expect("Database connection reattempt failed");
-                    migrate(migrations, &p).await;
-                    p
+                Err(e) => {
+                    eprintln!("Could not open the database: {}", e);
+                    process::exit(1);
                 },
             };
+            match pool.bootstrap().await {
+                Ok(true) => {
+                    if let Err(e) = migrate(migrations, &pool).await {
+                        eprintln!("Could not apply migrations: {}", e);
+                        process::exit(1);
+                    }
+                },
+                Ok(false) => {},
+                Err(e) => {
+                    eprintln!("Could not create the database tables: {}", e);
+                    process::exit(1);
+                },
+            }
 
             if args.len() > 1 {
                 match args[1].as_str() {
                     "make-migrations" => {
-                        if args.len() >= 2 {
-                            make_migrations(&args[2], &pool).await;
-                        } else {
-                            eprintln!("expected app name");
+                        match args.get(2) {
+                            Some(app) => {
+                                if let Err(e) = make_migrations(app, &pool).await {
+                                    eprintln!("Could not make migrations: {}", e);
+                                    process::exit(1);
+                                }
+                            },
+                            None => {
+                                eprintln!("expected app name");
+                                process::exit(1);
+                            },
                         }
                     },
                     "sql-migrate" => {
-                        if args.len() >= 3 {
-                            sql_migrate(migrations, &args[2], &args[3]).await;
-                        } else{
-                            eprintln!("expected app name");
+                        match (args.get(2), args.get(3)) {
+                            (Some(app), Some(migration)) => sql_migrate(migrations, app, migration).await,
+                            _ => {
+                                eprintln!("expected app name and migration name");
+                                process::exit(1);
+                            },
                         }
                     },
                     "migrate" => {
-                        migrate(migrations, &pool).await;
+                        if let Err(e) = migrate(migrations, &pool).await {
+                            eprintln!("Could not apply migrations: {}", e);
+                            process::exit(1);
+                        }
                     },
                     "admin" => {
-                        admin(pool.clone()).await.expect("Could not create admin");
+                        if let Err(e) = admin(pool.clone()).await {
+                            eprintln!("Could not create admin: {}", e);
+                            process::exit(1);
+                        }
+                    },
+                    "collect-static" => {
+                        let out = match args.get(2).or(config.static_root.as_ref()) {
+                            Some(out) => out,
+                            None => {
+                                eprintln!("expected output directory or static_root setting");
+                                process::exit(1);
+                            },
+                        };
+                        let embedded = self.statics.iter().flat_map(|stats| stats.iter().map(|(name, file)| (*name, *file)));
+                        match static_files::collect(embedded, Path::new(&format!("{}/static", base)), Path::new(out)) {
+                            Ok(n) => println!("Collected {} static files into {}", n, out),
+                            Err(e) => {
+                                eprintln!("Could not collect static files: {}", e);
+                                process::exit(1);
+                            },
+                        }
+                    },
+                    _ => {
+                        eprintln!("Unrecognized argument");
+                        process::exit(1);
                     },
-                    _ => eprintln!("Unrecognized argument"),
                 }
             } else {
                 let mut url_map = HashMap::new();
@@ -127,7 +234,18 @@ impl<B: BaseRequest + fmt::Debug + Clone> Server<B> {
                     rv.push(path(name, *view));
                 }
                 let login_url = settings.get("login_url").expect("Could not get login url").as_str().expect("Expected string for login url").to_string();
-                let router = Arc::new(Router::new(rv, handle_404, internal_error, login_url, files).unwrap());
+                if let Some(root) = &config.static_root {
+                    if let Err(e) = static_files::load_manifest(root) {
+                        tracing::warn!("Could not load static manifest from {}, run collect-static: {}", root, e);
+                    }
+                }
+                let router = match Router::new(rv, handle_404, internal_error, login_url, files, config.static_root.clone()) {
+                    Ok(router) => router,
+                    Err(e) => {
+                        tracing::error!("Could not build the routes: {}", e);
+                        process::exit(1);
+                    },
+                };
                 let urls = Arc::new(url_map);
 
                 let mut seed = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs().to_string();
@@ -144,7 +262,11 @@ impl<B: BaseRequest + fmt::Debug + Clone> Server<B> {
                         rng
                     },
                 };
-                let sem = Arc::new(Semaphore::new(10000000));
+                let acceptor = tls_acceptor(&config);
+                let scheme = if acceptor.is_some() { "https" } else { "http" };
+                let addr = config.addr();
+                let listener = TcpListener::bind(&addr).await.expect("Could not bind address");
+                let sem = Arc::new(Semaphore::new(config.max_connections));
                 let timer = Arc::new(Mutex::new(DateTime::now()));
                 let t2 = Arc::clone(&timer);
                 tokio::spawn(async move {
@@ -154,37 +276,371 @@ impl<B: BaseRequest + fmt::Debug + Clone> Server<B> {
                         *t2 = DateTime::now();
                     }
                 });
-                println!("Server running at http://{addr}/\nPress Ctrl+C to stop");
+                let (notify, shutdown) = watch::channel(false);
+                let (cut_off, cutoff) = watch::channel(false);
+                let redirect = match config.redirect_port {
+                    Some(port) if acceptor.is_some() => {
+                        let redirect_addr = format!("{}:{}", config.host, port);
+                        let listener = TcpListener::bind(&redirect_addr).await.expect("Could not bind redirect address");
+                        tracing::info!("Redirecting http://{redirect_addr}/ to https");
+                        Some(listener)
+                    },
+                    Some(_) => {
+                        tracing::warn!("redirect_port is ignored without tls_cert and tls_key");
+                        None
+                    },
+                    None => None,
+                };
+                let shared = Arc::new(Shared {urls, pool, std_rng, router, middleware: std::mem::take(&mut self.middleware), timer, site, config, shutdown, cutoff});
+                if let Some(listener) = redirect {
+                    tokio::spawn(redirect_to_https(listener, shared.config.clone(), shared.shutdown.clone()));
+                }
+                tracing::info!("Server running at {scheme}://{addr}/, press Ctrl+C to stop");
+                let signal = shutdown_signal();
+                tokio::pin!(signal);
                 loop {
-                    let (stream, _) = listener.accept().await.unwrap();
-                    let urls = urls.clone();
-                    let pool = pool.clone();
-                    let std_rng = std_rng.clone();
-                    let router = router.clone();
+                    let accepted = tokio::select! {
+                        accepted = listener.accept() => accepted,
+                        _ = &mut signal => break,
+                    };
+                    let (stream, peer) = match accepted {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            tracing::error!("Could not accept connection: {}", e);
+                            time::sleep(time::Duration::from_millis(100)).await;
+                            continue;
+                        },
+                    };
+                    let shared = shared.clone();
+                    let acceptor = acceptor.clone();
                     let sem = Arc::clone(&sem);
-                    let timer = Arc::clone(&timer);
-                    let aq = sem.try_acquire();
-                    let site = site.clone();
-                    if aq.is_ok() {
+                    let aq = sem.try_acquire_owned();
+                    if let Ok(permit) = aq {
                         tokio::spawn(async move {
-                            handle_connection(stream, urls, pool, std_rng, router, timer, site).await.unwrap();
+                            if let Err(e) = serve(stream, peer, shared, acceptor).await {
+                                if !is_disconnect(&*e) {
+                                    tracing::warn!("Connection error: {}", e);
+                                }
+                            }
+                            drop(permit);
                         });
                     } else {
-                        eprintln!("Open socket limit reached");
+                        tracing::warn!("Open socket limit reached");
                     }
                 }
+                drop(listener);
+                tracing::info!("Shutting down, waiting for open connections");
+                notify.send_replace(true);
+                let timeout = time::Duration::from_secs(shared.config.shutdown_timeout);
+                let permits = u32::try_from(shared.config.max_connections).unwrap_or(u32::MAX);
+                if time::timeout(timeout, sem.acquire_many(permits)).await.is_err() {
+                    tracing::warn!("Shutdown timeout reached, closing remaining connections");
+                    cut_off.send_replace(true);
+                    let _ = time::timeout(time::Duration::from_secs(1), sem.acquire_many(permits)).await;
+                }
+                shared.pool.close().await;
+                tracing::info!("Server stopped");
             }
         })
     }
 }
 
+#[cfg(feature = "tls")]
+type Acceptor = tokio_rustls::TlsAcceptor;
+
+#[cfg(not(feature = "tls"))]
+#[derive(Clone)]
+enum Acceptor {}
+
+#[cfg(feature = "tls")]
+fn tls_acceptor(config: &ServerConfig) -> Option<Acceptor> {
+    match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => match crate::tls::acceptor(cert, key) {
+            Ok(acceptor) => Some(acceptor),
+            Err(e) => {
+                tracing::error!("Could not load TLS certificate: {}", e);
+                process::exit(1);
+            },
+        },
+        (None, None) => None,
+        _ => {
+            tracing::error!("Both tls_cert and tls_key must be set to enable TLS");
+            process::exit(1);
+        },
+    }
+}
+
+#[cfg(not(feature = "tls"))]
+fn tls_acceptor(config: &ServerConfig) -> Option<Acceptor> {
+    if config.tls_cert.is_some() || config.tls_key.is_some() {
+        tracing::error!("tls_cert and tls_key require the \"tls\" feature");
+        process::exit(1);
+    }
+    None
+}
+
+/// Serves a connection, performing the TLS handshake first when TLS is enabled
+/// and switching to HTTP/2 when the client asks for it.
+async fn serve<B: BaseRequest + 'static + fmt::Debug>(stream: TcpStream, peer: SocketAddr, shared: Arc<Shared<B>>, acceptor: Option<Acceptor>) -> Result<()> {
+    #[cfg(feature = "tls")]
+    if let Some(acceptor) = acceptor {
+        let handshake = time::Duration::from_secs(shared.config.read_timeout);
+        let stream = time::timeout(handshake, acceptor.accept(stream)).await??;
+        #[cfg(feature = "http2")]
+        if stream.get_ref().1.alpn_protocol() == Some(b"h2") {
+            return crate::http2::handle_connection(stream, Some(peer), shared).await;
+        }
+        return handle_connection(stream, Some(peer), shared).await;
+    }
+    #[cfg(not(feature = "tls"))]
+    let _ = acceptor;
+    #[cfg(feature = "http2")]
+    {
+        let (preface, stream) = crate::http2::read_preface(stream, time::Duration::from_secs(shared.config.read_timeout)).await;
+        if preface {
+            return crate::http2::handle_connection(stream, Some(peer), shared).await;
+        }
+        handle_connection(stream, Some(peer), shared).await
+    }
+    #[cfg(not(feature = "http2"))]
+    handle_connection(stream, Some(peer), shared).await
+}
+
+/// Answers every request on the plain HTTP listener with a redirect to the
+/// same path on the HTTPS port.
+async fn redirect_to_https(listener: TcpListener, config: ServerConfig, mut shutdown: watch::Receiver<bool>) {
+    let config = Arc::new(config);
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown.wait_for(|stop| *stop) => break,
+        };
+        let mut stream = match accepted {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                tracing::error!("Could not accept connection: {}", e);
+                time::sleep(time::Duration::from_millis(100)).await;
+                continue;
+            },
+        };
+        let config = config.clone();
+        tokio::spawn(async move {
+            let mut reader = RequestReader::new(config.max_body_size);
+            let read_timeout = time::Duration::from_secs(config.read_timeout);
+            let message = match time::timeout(read_timeout, reader.read_request(&mut stream)).await {
+                Ok(Ok(message)) => message,
+                _ => return,
+            };
+            let host = message.headers.get("Host").map(|h| strip_port(h)).unwrap_or(&config.host);
+            let location = match config.port {
+                443 => format!("https://{}{}", host, message.request_line.url),
+                port => format!("https://{}:{}{}", host, port, message.request_line.url),
+            };
+            let mut response = Response::redirect_with(permanent_redirect(message.request_line.method), &location);
+            response.headers_mut().insert("Connection".to_string(), "close".to_string());
+            if stream.write_all(&response.into_bytes()).await.is_ok() {
+                let _ = stream.shutdown().await;
+            }
+        });
+    }
+}
+
+fn strip_port(host: &str) -> &str {
+    match host.rsplit_once(':') {
+        Some((name, port)) if !port.contains(']') => name,
+        _ => host,
+    }
+}
+
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).expect("Could not listen for SIGTERM");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {},
+            _ = terminate.recv() => {},
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await.expect("Could not listen for Ctrl+C");
+}
+
+/// State shared by every connection of a running server.
+pub struct Shared<B: BaseRequest + 'static> {
+    pub urls: Arc<HashMap<usize, Vec<String>>>,
+    pub pool: DbPool,
+    pub std_rng: Rng,
+    pub router: Router<B>,
+    pub middleware: Chain<B>,
+    pub timer: Timer,
+    pub site: AdminRef<B>,
+    pub config: ServerConfig,
+    /// Set to `true` once the server stops accepting connections.
+    pub shutdown: watch::Receiver<bool>,
+    /// Set to `true` once `shutdown_timeout` has passed, cutting off the
+    /// bodies still being sent.
+    pub cutoff: watch::Receiver<bool>,
+}
+
+/// Settings for the built-in server, read from `settings.toml` and overridden by
+/// `ANANSI_*` environment variables.
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    pub host: String,
+    pub port: u16,
+    pub max_connections: usize,
+    /// Seconds to wait for a request before closing the connection.
+    pub read_timeout: u64,
+    /// Largest request body in bytes, larger requests get a 413 response.
+    pub max_body_size: usize,
+    /// Number of tokio worker threads, defaults to the number of cores.
+    pub worker_threads: Option<usize>,
+    /// Seconds to wait for open connections to finish when shutting down.
+    pub shutdown_timeout: u64,
+    /// PEM certificate chain, serving HTTPS when set together with `tls_key`.
+    pub tls_cert: Option<String>,
+    /// PEM private key for `tls_cert`.
+    pub tls_key: Option<String>,
+    /// Port of a plain HTTP listener that redirects to HTTPS.
+    pub redirect_port: Option<u16>,
+    /// Most requests a client can have in flight at once on an HTTP/2 connection.
+    pub max_concurrent_streams: u32,
+    /// Most verbose level of server messages written to stderr.
+    pub log_level: String,
+    /// `stdout`, `off` or the path of a file to append access log lines to.
+    pub access_log: LogOutput,
+    pub access_log_format: LogFormat,
+    /// Whether to redirect paths that only have a route with a trailing slash added or removed.
+    pub trailing_slash: TrailingSlash,
+    /// Directory `collect-static` writes to, served before embedded files when set.
+    pub static_root: Option<String>,
+    /// Whether to compress text responses for clients that accept gzip or brotli.
+    pub compression: bool,
+    /// Smallest body in bytes that gets compressed.
+    pub compression_min_size: usize,
+    /// Database to connect to instead of `database.db`, required by backends other than sqlite.
+    pub database_url: Option<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            host: "127.0.0.1".to_string(),
+            port: 9090,
+            max_connections: 10000,
+            read_timeout: 5,
+            max_body_size: 2 * 1024 * 1024,
+            worker_threads: None,
+            shutdown_timeout: 30,
+            tls_cert: None,
+            tls_key: None,
+            redirect_port: None,
+            max_concurrent_streams: 100,
+            log_level: "info".to_string(),
+            access_log: LogOutput::Stdout,
+            access_log_format: LogFormat::Common,
+            trailing_slash: TrailingSlash::Off,
+            static_root: None,
+            compression: false,
+            compression_min_size: 1024,
+            database_url: None,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn from_settings(settings: &Map<String, Value>) -> Result<Self> {
+        let mut config = Self::default();
+        if let Some(host) = setting(settings, "host", "ANANSI_HOST") {
+            config.host = host;
+        }
+        if let Some(port) = setting(settings, "port", "ANANSI_PORT") {
+            config.port = port.parse()?;
+        }
+        if let Some(max) = setting(settings, "max_connections", "ANANSI_MAX_CONNECTIONS") {
+            config.max_connections = max.parse()?;
+        }
+        if let Some(timeout) = setting(settings, "read_timeout", "ANANSI_READ_TIMEOUT") {
+            config.read_timeout = timeout.parse()?;
+        }
+        if let Some(size) = setting(settings, "max_body_size", "ANANSI_MAX_BODY_SIZE") {
+            config.max_body_size = size.parse()?;
+        }
+        if let Some(workers) = setting(settings, "worker_threads", "ANANSI_WORKER_THREADS") {
+            config.worker_threads = Some(workers.parse()?);
+        }
+        if let Some(timeout) = setting(settings, "shutdown_timeout", "ANANSI_SHUTDOWN_TIMEOUT") {
+            config.shutdown_timeout = timeout.parse()?;
+        }
+        config.tls_cert = setting(settings, "tls_cert", "ANANSI_TLS_CERT");
+        config.tls_key = setting(settings, "tls_key", "ANANSI_TLS_KEY");
+        if let Some(port) = setting(settings, "redirect_port", "ANANSI_REDIRECT_PORT") {
+            config.redirect_port = Some(port.parse()?);
+        }
+        if let Some(max) = setting(settings, "max_concurrent_streams", "ANANSI_MAX_CONCURRENT_STREAMS") {
+            config.max_concurrent_streams = max.parse()?;
+        }
+        if let Some(level) = setting(settings, "log_level", "ANANSI_LOG_LEVEL") {
+            config.log_level = level;
+        }
+        if let Some(output) = setting(settings, "access_log", "ANANSI_ACCESS_LOG") {
+            config.access_log = output.parse()?;
+        }
+        if let Some(format) = setting(settings, "access_log_format", "ANANSI_ACCESS_LOG_FORMAT") {
+            config.access_log_format = format.parse()?;
+        }
+        if let Some(policy) = setting(settings, "trailing_slash", "ANANSI_TRAILING_SLASH") {
+            config.trailing_slash = policy.parse()?;
+        }
+        config.static_root = setting(settings, "static_root", "ANANSI_STATIC_ROOT");
+        if let Some(compression) = setting(settings, "compression", "ANANSI_COMPRESSION") {
+            config.compression = compression.parse()?;
+        }
+        if let Some(size) = setting(settings, "compression_min_size", "ANANSI_COMPRESSION_MIN_SIZE") {
+            config.compression_min_size = size.parse()?;
+        }
+        config.database_url = setting(settings, "database_url", "ANANSI_DATABASE_URL");
+        Ok(config)
+    }
+    /// Overrides the host and port with an address such as `0.0.0.0:8000`.
+    pub fn bind(&mut self, addr: &str) -> Result<()> {
+        let (host, port) = addr.rsplit_once(':').ok_or_else(invalid)?;
+        self.port = port.parse()?;
+        if !host.is_empty() {
+            self.host = host.to_string();
+        }
+        Ok(())
+    }
+    pub fn addr(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+}
+
+async fn connect(database_url: Option<&str>) -> Result<DbPool> {
+    match database_url {
+        Some(url) => DbPool::from_url(url).await,
+        None => DbPool::new().await,
+    }
+}
+
+fn setting(settings: &Map<String, Value>, key: &str, var: &str) -> Option<String> {
+    if let Ok(val) = env::var(var) {
+        return Some(val);
+    }
+    match settings.get(key)? {
+        Value::String(s) => Some(s.clone()),
+        val => Some(val.to_string()),
+    }
+}
+
 async fn append(dir_name: &str, content: &[u8]) {
    let mut file = fs::OpenOptions::new()
       .write(true)
       .append(true)
       .open(dir_name)
       .await
-      .expect(&format!("error with {}", dir_name));
+      .unwrap_or_else(|e| panic!("error with {}: {}", dir_name, e));
    file.write_all(content).await.unwrap();
 }
 
@@ -214,85 +670,388 @@ impl Rng {
     }
 }
 
-pub async fn handle_connection<B: BaseRequest + 'static + fmt::Debug>(mut stream: TcpStream, urls: Arc<HashMap<usize, Vec<String>>>, pool: DbPool, std_rng: Rng, router: Arc<Router<B>>, timer: Timer, site: AdminRef<B>) -> Result<()> {
-    let mut buffer = vec![0; 1024];
-    while let Ok(l) = time::timeout(time::Duration::from_secs(5), stream.read(&mut buffer)).await {
-        let length = l.unwrap();
-        if length == 0 {
+pub async fn handle_connection<B: BaseRequest + 'static + fmt::Debug, S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, peer: Option<SocketAddr>, shared: Arc<Shared<B>>) -> Result<()> {
+    let mut reader = RequestReader::new(shared.config.max_body_size);
+    let read_timeout = time::Duration::from_secs(shared.config.read_timeout);
+    let mut shutdown = shared.shutdown.clone();
+    let mut cutoff = shared.cutoff.clone();
+    while let Some(message) = next_request(&mut reader, &mut stream, read_timeout, &mut shutdown).await? {
+        let start = time::Instant::now();
+        let keep_alive = message.keep_alive() && !*shutdown.borrow();
+        let method = message.request_line.method;
+        let path = message.request_line.url.clone();
+        let span = tracing::info_span!("request", method = method.as_str(), path = path.as_str());
+        let (mut response, user_id) = match tokio::spawn(respond(shared.clone(), message).instrument(span)).await {
+            Ok(reply) => reply,
+            Err(_) => (shared.router.internal_error.clone(), None),
+        };
+        {
+            let timer = shared.timer.lock().unwrap();
+            response.headers_mut().insert("Date".to_string(), timer.to_gmt());
+        }
+        if !keep_alive {
+            response.headers_mut().insert("Connection".to_string(), "close".to_string());
+        }
+        let status = response.status();
+        let sends_body = method != Method::Head && !status.is_bodiless();
+        let chunks = response.body_stream().filter(|_| sends_body).map(|body| (body.take(), body.content_length(), body.ends_on_shutdown()));
+        let mut body_len = match response.body() {
+            Some(body) if sends_body => body.as_slice().len(),
+            _ => 0,
+        };
+        let bytes = if method == Method::Head {
+            response.into_head_bytes()
+        } else {
+            response.into_bytes()
+        };
+        stream.write_all(&bytes).await?;
+        if let Some((chunks, len, ends_on_shutdown)) = chunks {
+            body_len = write_chunks(&mut stream, chunks, len, ends_on_shutdown, &mut shutdown, &mut cutoff).await?;
+        }
+        Access {peer, method, path: &path, protocol: "HTTP/1.1", status: status.code(), bytes: body_len, latency: start.elapsed(), user_id}.log();
+        if !keep_alive {
             break;
         }
-        let urls = urls.clone();
-        let pool = pool.clone();
-        let std_rng = std_rng.clone();
-        let site = site.clone();
-
-        let mut response = {
-            let (n, request_line) = RawRequest::get_request_line(&buffer[..length])?;
-            let url = request_line.url.clone();
-            let dirs = split_url(&url)?;
-            if dirs[0] == "/static" {
-                match router.serve_static(&url).await {
-                    Ok(r) => r,
-                    Err(_) => router.internal_error.clone(),
-                }
+    }
+    Ok(())
+}
+
+/// Writes a streaming body, in chunks unless its length is known, returning
+/// its length. Fails if the body does not have the length it was given.
+/// Bodies that end on shutdown stop once `shutdown` is set, and any body still
+/// being written when `cutoff` is set fails without its last chunk, so the
+/// connection is dropped and the client can tell it is incomplete.
+async fn write_chunks<S: AsyncWrite + Unpin>(stream: &mut S, chunks: Option<Chunks>, len: Option<u64>, ends_on_shutdown: bool, shutdown: &mut watch::Receiver<bool>, cutoff: &mut watch::Receiver<bool>) -> io::Result<usize> {
+    tokio::select! {
+        written = copy_chunks(stream, chunks, len, ends_on_shutdown, shutdown) => written,
+        _ = cutoff.wait_for(|stop| *stop) => Err(io::Error::new(io::ErrorKind::Interrupted, "server shut down before the body was sent")),
+    }
+}
+
+async fn copy_chunks<S: AsyncWrite + Unpin>(stream: &mut S, chunks: Option<Chunks>, len: Option<u64>, ends_on_shutdown: bool, shutdown: &mut watch::Receiver<bool>) -> io::Result<usize> {
+    let mut written = 0;
+    if let Some(mut chunks) = chunks {
+        loop {
+            let chunk = tokio::select! {
+                chunk = chunks.next() => match chunk {
+                    Some(chunk) => chunk?,
+                    None => break,
+                },
+                _ = shutdown.wait_for(|stop| *stop), if ends_on_shutdown => break,
+            };
+            if chunk.is_empty() {
+                continue;
+            }
+            if len.is_none() {
+                stream.write_all(format!("{:x}\r\n", chunk.len()).as_bytes()).await?;
+                stream.write_all(&chunk).await?;
+                stream.write_all(b"\r\n").await?;
             } else {
-                let result = B::new(&buffer[n..length], request_line, urls, pool.clone(), std_rng.clone(), site).await;
-                match result {
-                    Ok(req) => {
-                        match route_request(dirs, req, &router.routes).await {
-                            Ok(res) => {
-                                res
-                            },
-                            Err(error) => {
-                                if let Some(web_error) = error.downcast_ref::<WebError>() {
-                                    if web_error.kind() == &WebErrorKind::Unauthenticated {
-                                        Response::redirect(&router.login_url)
-                                    } else {
-                                        router.internal_error.clone()
-                                    }
-                                } else if let Ok(http_404) = error.downcast::<Http404<B>>() {
-                                    match (router.handle_404)(http_404.req()).await {
-                                        Ok(r) => r,
-                                        Err(_) => router.internal_error.clone(),
-                                    }
-                                } else {
-                                    router.internal_error.clone()
-                                }
-                            }
-                        }
-                    },
-                    Err(error) => {
-                        if let Ok(web_error) = error.downcast::<WebError>() {
-                            match web_error.kind() {
-                                WebErrorKind::NoSession => {
-                                    match B::handle_no_session(Response::redirect(&url), pool, std_rng).await {
-                                        Ok(r) => {
-                                            r
-                                        },
-                                        Err(_) => {
-                                            router.internal_error.clone()
-                                        },
-                                    }
-                                },
-                                WebErrorKind::Unauthenticated => {
-                                    Response::redirect(&router.login_url)
-                                }
-                                WebErrorKind::Invalid => {
-                                    router.internal_error.clone()
-                                }
-                            }
-                        } else {
-                            router.internal_error.clone()
-                        }
-                    }
-                }
+                stream.write_all(&chunk).await?;
+            }
+            stream.flush().await?;
+            written += chunk.len();
+        }
+    }
+    match len {
+        None => stream.write_all(b"0\r\n\r\n").await?,
+        Some(len) if len != written as u64 => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "streamed body does not match its length")),
+        Some(_) => {},
+    }
+    stream.flush().await?;
+    Ok(written)
+}
+
+/// Reads the next request from the stream, answering requests that cannot be
+/// parsed with an error response. Returns `None` when the connection should close.
+async fn next_request<S: AsyncRead + AsyncWrite + Unpin>(reader: &mut RequestReader, stream: &mut S, read_timeout: time::Duration, shutdown: &mut watch::Receiver<bool>) -> io::Result<Option<Message>> {
+    let read = tokio::select! {
+        read = time::timeout(read_timeout, reader.read_request(stream)) => read,
+        _ = shutdown.wait_for(|stop| *stop) => return Ok(None),
+    };
+    let status = match read {
+        Ok(Ok(message)) => return Ok(Some(message)),
+        Ok(Err(ReadError::Closed)) => return Ok(None),
+        Ok(Err(ReadError::Io(e))) => return Err(e),
+        Ok(Err(ReadError::Malformed)) => Status::BadRequest,
+        Ok(Err(ReadError::UnknownMethod)) => Status::NotImplemented,
+        Ok(Err(ReadError::TooLarge)) => Status::PayloadTooLarge,
+        Err(_) => {
+            if reader.is_idle() {
+                return Ok(None);
+            }
+            Status::RequestTimeout
+        },
+    };
+    let mut response = Response::from_status(status);
+    response.headers_mut().insert("Connection".to_string(), "close".to_string());
+    stream.write_all(&response.into_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(None)
+}
+
+/// Produces the response to a request, along with the id of the logged in user.
+pub(crate) async fn respond<B: BaseRequest + 'static + fmt::Debug>(shared: Arc<Shared<B>>, message: Message) -> (Response, Option<i64>) {
+    if !shared.config.compression {
+        return dispatch(shared, message).await;
+    }
+    let accepted: Vec<&str> = static_files::accepted_encodings(&message.headers).into_iter().map(|(encoding, _)| encoding).collect();
+    let min_size = shared.config.compression_min_size;
+    let (mut response, user_id) = dispatch(shared, message).await;
+    compression::compress(&mut response, &accepted, min_size);
+    (response, user_id)
+}
+
+async fn dispatch<B: BaseRequest + 'static + fmt::Debug>(shared: Arc<Shared<B>>, message: Message) -> (Response, Option<i64>) {
+    let router = &shared.router;
+    let method = message.request_line.method;
+    let urls = shared.urls.clone();
+    let pool = shared.pool.clone();
+    let std_rng = shared.std_rng.clone();
+    let site = shared.site.clone();
+    let Message {request_line, headers, body} = message;
+    let url = request_line.url.clone();
+    let (raw_path, query) = url.split_once('?').map_or((url.as_str(), None), |(path, query)| (path, Some(query)));
+    let path = match normalize_path(raw_path) {
+        Ok(path) => path,
+        Err(_) => return (Response::bad_request(), None),
+    };
+    let dirs = match split_url(&path) {
+        Ok(dirs) => dirs,
+        Err(_) => return (Response::bad_request(), None),
+    };
+    if dirs[0] == "/static" {
+        let response = if method != Method::Get && method != Method::Head {
+            Response::method_not_allowed(&[Method::Get])
+        } else {
+            match router.serve_static(&path, &headers).await {
+                Ok(r) => r,
+                Err(_) => router.internal_error.clone(),
             }
         };
-        {
-            let timer = timer.lock().unwrap();
-            response.headers_mut().insert("Date".to_string(), format!("{timer}"));
+        (response, None)
+    } else if let Some(location) = slash_redirect(router, shared.config.trailing_slash, &path, raw_path, query) {
+        (Response::redirect_with(permanent_redirect(method), &location), None)
+    } else {
+        let raw = match RawRequest::new(request_line, headers, body, pool.clone(), std_rng.clone()) {
+            Ok(raw) => raw,
+            Err(_) => return (Response::bad_request(), None),
+        };
+        let mut req = match B::new(raw, urls, site).await {
+            Ok(req) => req,
+            Err(error) => return (handle_error(router, error).await, None),
+        };
+        let mut passed = 0;
+        let mut early = None;
+        for middleware in &shared.middleware {
+            match middleware.before(&mut req).await {
+                Ok(None) => passed += 1,
+                Ok(Some(response)) => {
+                    early = Some(Ok(response));
+                    break;
+                },
+                Err(error) => {
+                    early = Some(Err(error));
+                    break;
+                },
+            }
         }
-        stream.write(&response.into_bytes()).await.unwrap();
+        let snapshot = Snapshot::record(&req);
+        let result = match early {
+            Some(result) => result,
+            None => route_request(dirs, req, &router.routes).await,
+        };
+        let mut response = match result {
+            Ok(response) => response,
+            Err(error) => handle_error(router, error).await,
+        };
+        for middleware in shared.middleware[..passed].iter().rev() {
+            response = match middleware.after(&snapshot, response).await {
+                Ok(response) => response,
+                Err(error) => handle_error(router, error).await,
+            };
+        }
+        (response, snapshot.user_id)
+    }
+}
+
+/// Where to redirect a path without a route when its trailing slash toggled has one.
+fn slash_redirect<B: BaseRequest + 'static>(router: &Router<B>, policy: TrailingSlash, path: &str, raw_path: &str, query: Option<&str>) -> Option<String> {
+    let other = policy.toggle(path)?;
+    if router.routes.matches(path) || !router.routes.matches(&other) {
+        return None;
+    }
+    let location = policy.toggle(raw_path)?;
+    Some(match query {
+        Some(query) => format!("{}?{}", location, query),
+        None => location,
+    })
+}
+
+/// Permanent redirects keep the method and body of requests other than GET and HEAD.
+fn permanent_redirect(method: Method) -> Status {
+    match method {
+        Method::Get | Method::Head => Status::MovedPermanently,
+        _ => Status::PermanentRedirect,
+    }
+}
+
+/// Turns an error from a view or middleware into a response.
+async fn handle_error<B: BaseRequest + 'static + fmt::Debug>(router: &Router<B>, error: Box<dyn std::error::Error + Send + Sync>) -> Response {
+    if let Some(web_error) = error.downcast_ref::<WebError>() {
+        if web_error.kind() == &WebErrorKind::Unauthenticated {
+            Response::redirect(&router.login_url)
+        } else {
+            router.internal_error.clone()
+        }
+    } else if let Ok(http_404) = error.downcast::<Http404<B>>() {
+        match (router.handle_404)(http_404.req()).await {
+            Ok(mut r) => {
+                if r.status() == Status::Ok {
+                    *r.status_mut() = Status::NotFound;
+                }
+                r
+            },
+            Err(_) => router.internal_error.clone(),
+        }
+    } else {
+        router.internal_error.clone()
+    }
+}
+
+/// Whether an error only means that the client went away.
+fn is_disconnect(error: &(dyn std::error::Error + Send + Sync + 'static)) -> bool {
+    match error.downcast_ref::<io::Error>() {
+        Some(e) => matches!(e.kind(), io::ErrorKind::BrokenPipe | io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted | io::ErrorKind::UnexpectedEof),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::watch;
+    use tokio::time::Duration;
+    use crate::reader::RequestReader;
+    use crate::web::BodyStream;
+    use super::{ServerConfig, next_request, redirect_to_https, write_chunks};
+
+    #[tokio::test]
+    async fn body_at_shutdown() {
+        for ends_on_shutdown in [false, true] {
+            let (mut source, reader) = tokio::io::duplex(64);
+            let body = BodyStream::from_reader(reader);
+            let (mut client, mut server) = tokio::io::duplex(1024);
+            let (notify, mut shutdown) = watch::channel(false);
+            let (cut_off, mut cutoff) = watch::channel(false);
+            let write = tokio::spawn(async move {
+                write_chunks(&mut server, body.take(), None, ends_on_shutdown, &mut shutdown, &mut cutoff).await
+            });
+            source.write_all(b"hello").await.unwrap();
+            let mut buf = [0; 10];
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"5\r\nhello\r\n");
+            notify.send_replace(true);
+            if ends_on_shutdown {
+                assert_eq!(write.await.unwrap().unwrap(), 5);
+            } else {
+                source.write_all(b"world").await.unwrap();
+                client.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"5\r\nworld\r\n");
+                cut_off.send_replace(true);
+                assert!(write.await.unwrap().is_err());
+            }
+            let mut rest = vec![];
+            client.read_to_end(&mut rest).await.unwrap();
+            if ends_on_shutdown {
+                assert_eq!(rest, b"0\r\n\r\n");
+            } else {
+                assert!(rest.is_empty());
+            }
+        }
+    }
+
+    async fn exchange(input: &[u8], half_close: bool) -> (bool, String) {
+        let (mut client, mut server) = tokio::io::duplex(64 * 1024);
+        client.write_all(input).await.unwrap();
+        if half_close {
+            client.shutdown().await.unwrap();
+        }
+        let (_notify, mut shutdown) = watch::channel(false);
+        let mut reader = RequestReader::new(1024);
+        let message = next_request(&mut reader, &mut server, Duration::from_millis(50), &mut shutdown).await.unwrap();
+        drop(server);
+        let mut output = String::new();
+        client.read_to_string(&mut output).await.unwrap();
+        (message.is_some(), output)
+    }
+
+    #[tokio::test]
+    async fn malformed_request_line() {
+        for line in [&b"BREW / HTTP/1.0\r\n\r\n"[..], b"GET /\r\n\r\n", b"GET / HTTP/1.0\r\n\r\n", b"GET  / HTTP/1.1\r\n\r\n", b"\xff\xfe\r\n\r\n"] {
+            let (message, output) = exchange(line, false).await;
+            assert!(!message);
+            assert!(output.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{:?}", output);
+        }
+        let (message, output) = exchange(b"BREW / HTTP/1.1\r\n\r\n", false).await;
+        assert!(!message);
+        assert!(output.starts_with("HTTP/1.1 501 Not Implemented\r\n"), "{:?}", output);
+    }
+
+    #[tokio::test]
+    async fn bad_headers() {
+        for request in [&b"GET / HTTP/1.1\r\nHost x\r\n\r\n"[..], b"GET / HTTP/1.1\r\nHost : x\r\n\r\n", b"POST / HTTP/1.1\r\nContent-Length: ten\r\n\r\n", b"POST / HTTP/1.1\r\nContent-Length: 1\r\nTransfer-Encoding: chunked\r\n\r\n"] {
+            let (message, output) = exchange(request, false).await;
+            assert!(!message);
+            assert!(output.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{:?}", output);
+        }
+    }
+
+    #[tokio::test]
+    async fn half_closed() {
+        let (message, output) = exchange(b"GET / HTTP/1.1\r\nHo", true).await;
+        assert!(!message);
+        assert!(output.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+
+        let (message, output) = exchange(b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nshort", true).await;
+        assert!(!message);
+        assert!(output.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+
+        let (message, output) = exchange(b"", true).await;
+        assert!(!message);
+        assert!(output.is_empty());
+    }
+
+    #[tokio::test]
+    async fn timeout() {
+        let (message, output) = exchange(b"GET / HTTP/1.1\r\n", false).await;
+        assert!(!message);
+        assert!(output.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+
+        let (message, output) = exchange(b"", false).await;
+        assert!(!message);
+        assert!(output.is_empty());
+    }
+
+    #[tokio::test]
+    async fn https_redirect() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = ServerConfig {port: 8443, ..ServerConfig::default()};
+        let (notify, shutdown) = watch::channel(false);
+        let server = tokio::spawn(redirect_to_https(listener, config, shutdown));
+
+        for (request, status) in [("GET", "301 Moved Permanently"), ("POST", "308 Permanent Redirect")] {
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            client.write_all(format!("{} /login?next=%2F HTTP/1.1\r\nHost: example.com:8080\r\nContent-Length: 0\r\n\r\n", request).as_bytes()).await.unwrap();
+            let mut output = String::new();
+            client.read_to_string(&mut output).await.unwrap();
+            assert!(output.starts_with(&format!("HTTP/1.1 {}\r\n", status)), "{:?}", output);
+            assert!(output.contains("Location: https://example.com:8443/login?next=%2F\r\n"), "{:?}", output);
+        }
+
+        notify.send_replace(true);
+        server.await.unwrap();
     }
-    Ok(())
 }
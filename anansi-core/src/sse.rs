@@ -0,0 +1,359 @@
+use std::collections::{HashMap, VecDeque};
+use std::pin::Pin;
+use std::sync::{Mutex, OnceLock};
+use std::task::{Context, Poll};
+
+use bytes::Bytes;
+use futures_core::Stream;
+use tokio::sync::{broadcast, mpsc};
+use tokio::time::{self, Duration, Instant, Interval, MissedTickBehavior};
+
+use crate::web::{Result, BodyStream, Headers, Response, Status};
+
+/// Events kept per broadcast channel, replayed to clients reconnecting with `Last-Event-ID`.
+const HISTORY: usize = 64;
+
+/// How long a broadcast channel keeps its history after its last client
+/// disconnects, so that clients reconnecting in the meantime miss nothing.
+const GRACE: Duration = Duration::from_secs(60);
+
+/// Events buffered for each client before `EventSender::send` waits.
+const BUFFER: usize = 16;
+
+/// A server-sent event.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Event {
+    event: Option<String>,
+    id: Option<String>,
+    retry: Option<u64>,
+    data: String,
+}
+
+impl Event {
+    pub fn new(data: &str) -> Self {
+        Self {data: data.to_string(), ..Self::default()}
+    }
+    /// Sets the event type, dispatched to `addEventListener` listeners of the same name.
+    pub fn event(mut self, name: &str) -> Self {
+        self.event = Some(single_line(name));
+        self
+    }
+    /// Sets the id the client sends back in `Last-Event-ID` when it reconnects.
+    pub fn id(mut self, id: &str) -> Self {
+        self.id = Some(single_line(id));
+        self
+    }
+    /// Sets the milliseconds the client waits before reconnecting.
+    pub fn retry(mut self, millis: u64) -> Self {
+        self.retry = Some(millis);
+        self
+    }
+    pub fn get_id(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
+    pub fn to_bytes(&self) -> Bytes {
+        let mut s = String::new();
+        if let Some(event) = &self.event {
+            s.push_str(&format!("event: {}\n", event));
+        }
+        if let Some(id) = &self.id {
+            s.push_str(&format!("id: {}\n", id));
+        }
+        if let Some(retry) = self.retry {
+            s.push_str(&format!("retry: {}\n", retry));
+        }
+        for line in self.data.split('\n') {
+            s.push_str(&format!("data: {}\n", line.strip_suffix('\r').unwrap_or(line)));
+        }
+        s.push('\n');
+        Bytes::from(s)
+    }
+}
+
+fn single_line(s: &str) -> String {
+    s.chars().filter(|c| *c != '\n' && *c != '\r' && *c != '\0').collect()
+}
+
+/// The id of the last event a reconnecting client received.
+pub fn last_event_id(headers: &Headers) -> Option<&str> {
+    headers.get("Last-Event-ID").map(|id| id.trim()).filter(|id| !id.is_empty())
+}
+
+/// Sends events to a single client.
+#[derive(Clone, Debug)]
+pub struct EventSender {
+    tx: mpsc::Sender<Event>,
+}
+
+impl EventSender {
+    /// Sends an event, failing once the client has disconnected.
+    pub async fn send(&self, event: Event) -> Result<()> {
+        self.tx.send(event).await.map_err(|_| "client disconnected".into())
+    }
+    /// Whether the client has disconnected.
+    pub fn is_closed(&self) -> bool {
+        self.tx.is_closed()
+    }
+    /// Waits until the client disconnects.
+    pub async fn closed(&self) {
+        self.tx.closed().await
+    }
+}
+
+/// Builds `text/event-stream` responses. Unlike other streaming responses, they
+/// end as soon as the server starts shutting down, and clients reconnect to
+/// another instance.
+///
+/// ```ignore
+/// let (sender, response) = Sse::new().channel();
+/// tokio::spawn(async move {
+///     while sender.send(Event::new("tick").event("clock")).await.is_ok() {
+///         tokio::time::sleep(Duration::from_secs(1)).await;
+///     }
+/// });
+/// Ok(response)
+/// ```
+#[derive(Clone, Debug)]
+pub struct Sse {
+    heartbeat: Duration,
+    retry: Option<u64>,
+}
+
+impl Default for Sse {
+    fn default() -> Self {
+        Self {heartbeat: Duration::from_secs(15), retry: None}
+    }
+}
+
+impl Sse {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Sets how long the stream can be idle before a comment is sent, keeping
+    /// proxies from closing it and noticing clients that went away.
+    pub fn heartbeat(mut self, interval: Duration) -> Self {
+        self.heartbeat = interval;
+        self
+    }
+    /// Sets the milliseconds clients wait before reconnecting, sent when the stream opens.
+    pub fn retry(mut self, millis: u64) -> Self {
+        self.retry = Some(millis);
+        self
+    }
+    /// A response streaming the events sent with the returned sender.
+    pub fn channel(self) -> (EventSender, Response) {
+        let (tx, rx) = mpsc::channel(BUFFER);
+        (EventSender {tx}, self.response(rx))
+    }
+    /// A response streaming the events published to a broadcast channel, starting
+    /// with the ones the client missed if it sent a `Last-Event-ID` header.
+    pub fn subscribe(self, channel: &str, headers: &Headers) -> Response {
+        let (missed, mut events) = {
+            let mut channels = channels().lock().unwrap();
+            sweep(&mut channels);
+            let channel = channels.entry(channel.to_string()).or_insert_with(Channel::new);
+            channel.idle_since = None;
+            (channel.since(last_event_id(headers)), channel.sender.subscribe())
+        };
+        let (tx, rx) = mpsc::channel(BUFFER);
+        let name = channel.to_string();
+        tokio::spawn(async move {
+            forward(missed, &mut events, &tx).await;
+            drop(events);
+            leave(&name);
+        });
+        self.response(rx)
+    }
+    fn response(self, events: mpsc::Receiver<Event>) -> Response {
+        let mut heartbeat = time::interval_at(Instant::now() + self.heartbeat, self.heartbeat);
+        heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let opening = self.retry.map(|retry| Bytes::from(format!("retry: {}\n\n", retry)));
+        let stream = EventStream {opening, events, heartbeat};
+        let mut response = Response::stream(Status::Ok, "text/event-stream", BodyStream::new(stream).end_on_shutdown());
+        let headers = response.headers_mut();
+        headers.insert("Cache-Control".to_string(), "no-cache".to_string());
+        headers.insert("X-Accel-Buffering".to_string(), "no".to_string());
+        response
+    }
+}
+
+/// Sends the missed events and then the published ones to a client until it
+/// disconnects.
+async fn forward(missed: Vec<Event>, events: &mut broadcast::Receiver<Event>, tx: &mpsc::Sender<Event>) {
+    for event in missed {
+        if tx.send(event).await.is_err() {
+            return;
+        }
+    }
+    loop {
+        let event = tokio::select! {
+            event = events.recv() => event,
+            _ = tx.closed() => return,
+        };
+        match event {
+            Ok(event) => {
+                if tx.send(event).await.is_err() {
+                    return;
+                }
+            },
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => return,
+        }
+    }
+}
+
+struct EventStream {
+    opening: Option<Bytes>,
+    events: mpsc::Receiver<Event>,
+    heartbeat: Interval,
+}
+
+impl Stream for EventStream {
+    type Item = std::io::Result<Bytes>;
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if let Some(opening) = this.opening.take() {
+            return Poll::Ready(Some(Ok(opening)));
+        }
+        match this.events.poll_recv(cx) {
+            Poll::Ready(Some(event)) => {
+                this.heartbeat.reset();
+                Poll::Ready(Some(Ok(event.to_bytes())))
+            },
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => match this.heartbeat.poll_tick(cx) {
+                Poll::Ready(_) => Poll::Ready(Some(Ok(Bytes::from_static(b": heartbeat\n\n")))),
+                Poll::Pending => Poll::Pending,
+            },
+        }
+    }
+}
+
+/// A named broadcast channel and its recent events.
+struct Channel {
+    sender: broadcast::Sender<Event>,
+    history: VecDeque<Event>,
+    /// When the last client disconnected, while none are connected.
+    idle_since: Option<Instant>,
+}
+
+impl Channel {
+    fn new() -> Self {
+        Self {sender: broadcast::channel(HISTORY).0, history: VecDeque::new(), idle_since: None}
+    }
+    fn expired(&self) -> bool {
+        self.sender.receiver_count() == 0 && self.idle_since.is_some_and(|since| since.elapsed() >= GRACE)
+    }
+    /// The events after the one with `id`, or none if it is unknown.
+    fn since(&self, id: Option<&str>) -> Vec<Event> {
+        let id = match id {
+            Some(id) => id,
+            None => return vec![],
+        };
+        match self.history.iter().rposition(|event| event.get_id() == Some(id)) {
+            Some(pos) => self.history.iter().skip(pos + 1).cloned().collect(),
+            None => vec![],
+        }
+    }
+}
+
+fn channels() -> &'static Mutex<HashMap<String, Channel>> {
+    static CHANNELS: OnceLock<Mutex<HashMap<String, Channel>>> = OnceLock::new();
+    CHANNELS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Starts the grace period of a channel once its last client has disconnected.
+fn leave(name: &str) {
+    let mut channels = channels().lock().unwrap();
+    if let Some(channel) = channels.get_mut(name).filter(|channel| channel.sender.receiver_count() == 0) {
+        channel.idle_since = Some(Instant::now());
+    }
+}
+
+/// Drops the channels whose grace period is over, along with their history.
+fn sweep(channels: &mut HashMap<String, Channel>) {
+    channels.retain(|_, channel| !channel.expired());
+}
+
+/// Publishes an event to the clients subscribed to a channel, returning how many there are.
+/// Events are still kept for a channel in its grace period, but are discarded
+/// if it never had clients or they left longer ago.
+pub fn publish(channel: &str, event: Event) -> usize {
+    let mut channels = channels().lock().unwrap();
+    sweep(&mut channels);
+    let channel = match channels.get_mut(channel) {
+        Some(channel) => channel,
+        None => return 0,
+    };
+    if event.get_id().is_some() {
+        if channel.history.len() == HISTORY {
+            channel.history.pop_front();
+        }
+        channel.history.push_back(event.clone());
+    }
+    channel.sender.send(event).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Event, Sse, channels, publish};
+    use crate::web::Headers;
+
+    #[tokio::test]
+    async fn event_stream() {
+        let event = Event::new("a\nb").event("up\ndate").id("7").retry(500);
+        assert_eq!(event.to_bytes(), "event: update\nid: 7\nretry: 500\ndata: a\ndata: b\n\n");
+
+        assert_eq!(publish("test", Event::new("x").id("0")), 0);
+        let first = Sse::new().subscribe("test", &Headers::new());
+        for id in 1..=3 {
+            publish("test", Event::new("x").id(&id.to_string()));
+        }
+        let mut headers = Headers::new();
+        headers.insert("Last-Event-ID".to_string(), "2".to_string());
+        let response = Sse::new().retry(1000).subscribe("test", &headers);
+        assert_eq!(response.headers().get("Content-Type").unwrap(), "text/event-stream");
+        let mut chunks = response.body_stream().unwrap().take().unwrap();
+        assert_eq!(chunks.next().await.unwrap().unwrap(), "retry: 1000\n\n");
+        assert_eq!(chunks.next().await.unwrap().unwrap(), "id: 3\ndata: x\n\n");
+        publish("test", Event::new("y"));
+        assert_eq!(chunks.next().await.unwrap().unwrap(), "data: y\n\n");
+
+        let (sender, response) = Sse::new().channel();
+        drop(response);
+        assert!(sender.send(Event::new("z")).await.is_err());
+
+        drop(chunks);
+        drop(first);
+        wait_until_idle("test").await;
+        assert_eq!(channels().lock().unwrap()["test"].history.len(), 3);
+    }
+
+    async fn wait_until_idle(name: &str) {
+        for _ in 0..100 {
+            if channels().lock().unwrap()[name].idle_since.is_some() {
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        panic!("channel {} still has clients", name);
+    }
+
+    #[tokio::test]
+    async fn reconnect() {
+        let response = Sse::new().subscribe("reconnect", &Headers::new());
+        let mut chunks = response.body_stream().unwrap().take().unwrap();
+        assert_eq!(publish("reconnect", Event::new("a").id("1")), 1);
+        assert_eq!(chunks.next().await.unwrap().unwrap(), "id: 1\ndata: a\n\n");
+        drop(chunks);
+        wait_until_idle("reconnect").await;
+
+        assert_eq!(publish("reconnect", Event::new("b").id("2")), 0);
+        let mut headers = Headers::new();
+        headers.insert("Last-Event-ID".to_string(), "1".to_string());
+        let response = Sse::new().subscribe("reconnect", &headers);
+        let mut chunks = response.body_stream().unwrap().take().unwrap();
+        assert_eq!(chunks.next().await.unwrap().unwrap(), "id: 2\ndata: b\n\n");
+        assert!(channels().lock().unwrap()["reconnect"].idle_since.is_none());
+    }
+}
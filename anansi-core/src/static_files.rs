@@ -0,0 +1,438 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io::SeekFrom;
+use std::path::Path;
+use std::sync::OnceLock;
+use std::time::UNIX_EPOCH;
+
+use sha2::{Digest, Sha256};
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use crate::datetime::DateTime;
+use crate::web::{Result, BodyStream, Headers, Response, Status};
+
+/// Precompressed variants looked for next to a file, in order of preference.
+const ENCODINGS: [(&str, &str); 2] = [("br", ".br"), ("gzip", ".gz")];
+
+/// File written by `collect` next to the collected files.
+pub const MANIFEST: &str = "manifest.json";
+
+/// Sent with files whose names contain a hash of their contents.
+pub const IMMUTABLE: &str = "public, max-age=31536000, immutable";
+
+/// Maps static names such as `admin/style.css` to their hashed names.
+struct Manifest {
+    names: HashMap<String, String>,
+    hashed: HashSet<String>,
+}
+
+static MANIFEST_NAMES: OnceLock<Manifest> = OnceLock::new();
+
+/// Loads the manifest written by `collect` into `root`, used by `static_url`.
+pub fn load_manifest(root: &str) -> Result<()> {
+    let content = std::fs::read(format!("{}/{}", root, MANIFEST))?;
+    let names: HashMap<String, String> = serde_json::from_slice(&content)?;
+    let hashed = names.values().cloned().collect();
+    MANIFEST_NAMES.set(Manifest {names, hashed}).map_err(|_| "static manifest already loaded")?;
+    Ok(())
+}
+
+/// The URL of a static file, using its hashed name when a manifest is loaded.
+///
+/// Templates call this through `@static "admin/style.css"`.
+pub fn static_url(name: &str) -> String {
+    let name = name.trim_start_matches('/');
+    match MANIFEST_NAMES.get().and_then(|manifest| manifest.names.get(name)) {
+        Some(hashed) => format!("/static/{}", hashed),
+        None => format!("/static/{}", name),
+    }
+}
+
+/// Whether a path under `/static/` is a hashed name from the manifest, which never changes.
+pub fn is_hashed(name: &str) -> bool {
+    MANIFEST_NAMES.get().is_some_and(|manifest| manifest.hashed.contains(name))
+}
+
+/// Writes every static file into `out` under both its own name and a name
+/// containing a hash of its contents, along with the manifest mapping one to the
+/// other. Files found in `dir` replace embedded ones with the same name.
+///
+/// Precompressed `.gz` and `.br` variants are named after their original file,
+/// so that they are found next to its hashed name. Returns the number of files.
+pub fn collect<'a>(embedded: impl IntoIterator<Item = (&'a str, &'a [u8])>, dir: &Path, out: &Path) -> Result<usize> {
+    let mut files = BTreeMap::new();
+    for (name, content) in embedded {
+        files.insert(name.trim_start_matches("/static/").to_string(), content.to_vec());
+    }
+    if dir.is_dir() {
+        read_dir(dir, dir, &mut files)?;
+    }
+    let mut manifest = BTreeMap::new();
+    for (name, content) in &files {
+        if variant_of(name, &files).is_none() {
+            manifest.insert(name.clone(), hashed_name(name, content));
+        }
+    }
+    for (name, content) in &files {
+        let hashed = match variant_of(name, &files) {
+            Some((original, suffix)) => format!("{}{}", manifest[original], suffix),
+            None => manifest[name].clone(),
+        };
+        for target in [name, &hashed] {
+            let path = out.join(target);
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(path, content)?;
+        }
+    }
+    std::fs::write(out.join(MANIFEST), serde_json::to_vec_pretty(&manifest)?)?;
+    Ok(files.len())
+}
+
+fn read_dir(root: &Path, dir: &Path, files: &mut BTreeMap<String, Vec<u8>>) -> Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.file_name().and_then(|n| n.to_str()).is_none_or(|n| n.starts_with('.')) {
+            continue;
+        }
+        if path.is_dir() {
+            read_dir(root, &path, files)?;
+        } else {
+            let name = path.strip_prefix(root)?.to_str().ok_or("static file names must be UTF-8")?.replace('\\', "/");
+            files.insert(name, std::fs::read(&path)?);
+        }
+    }
+    Ok(())
+}
+
+/// The original file and suffix of a precompressed variant.
+fn variant_of<'a>(name: &'a str, files: &BTreeMap<String, Vec<u8>>) -> Option<(&'a str, &'static str)> {
+    ENCODINGS.iter().find_map(|(_, suffix)| {
+        let original = name.strip_suffix(suffix)?;
+        files.contains_key(original).then_some((original, *suffix))
+    })
+}
+
+/// `admin/style.css` becomes `admin/style.0123456789ab.css`.
+fn hashed_name(name: &str, content: &[u8]) -> String {
+    let digest = Sha256::digest(content);
+    let hash: String = digest[..6].iter().map(|b| format!("{:02x}", b)).collect();
+    let (dir, file) = match name.rsplit_once('/') {
+        Some((dir, file)) => (format!("{}/", dir), file),
+        None => (String::new(), name),
+    };
+    match file.split_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => format!("{}{}.{}.{}", dir, stem, hash, ext),
+        _ => format!("{}{}.{}", dir, file, hash),
+    }
+}
+
+/// A static file and the validators sent with it. Files on disk are only
+/// read when they are sent, and only the requested range of them.
+#[derive(Clone)]
+pub struct StaticFile {
+    source: Source,
+    pub len: u64,
+    pub etag: String,
+    pub last_modified: Option<String>,
+}
+
+#[derive(Clone)]
+enum Source {
+    Embedded(&'static [u8]),
+    Disk(String),
+}
+
+impl StaticFile {
+    /// A file embedded in the binary, whose ETag is a hash of its contents.
+    pub fn embedded(content: &'static [u8]) -> Self {
+        let mut hasher = DefaultHasher::new();
+        content.hash(&mut hasher);
+        Self {source: Source::Embedded(content), len: content.len() as u64, etag: format!("\"{:016x}\"", hasher.finish()), last_modified: None}
+    }
+    /// Finds a file on disk, returning `None` if it does not exist.
+    pub async fn read(path: &str) -> Result<Option<Self>> {
+        let metadata = match fs::metadata(path).await {
+            Ok(metadata) if metadata.is_file() => metadata,
+            Ok(_) => return Ok(None),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let modified = metadata.modified()?.duration_since(UNIX_EPOCH)?.as_secs();
+        let etag = format!("\"{:x}-{:x}\"", modified, metadata.len());
+        Ok(Some(Self {source: Source::Disk(path.to_string()), len: metadata.len(), etag, last_modified: Some(DateTime::from_secs(modified).to_gmt())}))
+    }
+    /// A response with `len` bytes of the file from `start`.
+    async fn response(self, status: Status, ty: &str, start: u64, len: u64) -> Result<Response> {
+        match self.source {
+            Source::Embedded(content) => Ok(Response::content(status, ty, content[start as usize..(start + len) as usize].to_vec())),
+            Source::Disk(path) => {
+                let mut file = fs::File::open(path).await?;
+                file.seek(SeekFrom::Start(start)).await?;
+                Ok(Response::stream(status, ty, BodyStream::from_reader(file.take(len)).with_len(len)))
+            },
+        }
+    }
+}
+
+/// The content type of a file, from its extension.
+pub fn mime_type(path: &str) -> &'static str {
+    let ext = match path.rsplit_once('.') {
+        Some((name, ext)) if !name.ends_with('/') => ext.to_ascii_lowercase(),
+        _ => return "application/octet-stream",
+    };
+    match ext.as_str() {
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" | "map" => "application/json",
+        "html" | "htm" => "text/html; charset=utf-8",
+        "txt" => "text/plain; charset=utf-8",
+        "csv" => "text/csv; charset=utf-8",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "eot" => "application/vnd.ms-fontobject",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        "mp3" => "audio/mpeg",
+        "ogg" => "audio/ogg",
+        "wav" => "audio/wav",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "zip" => "application/zip",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Whether a static path is safe to look up, without hidden files or empty segments.
+pub fn is_valid_path(path: &str) -> bool {
+    path.starts_with('/') && path[1..].split('/').all(|segment| !segment.is_empty() && !segment.starts_with('.') && !segment.contains('\\'))
+}
+
+/// The encodings the client accepts in order of preference, with the suffix of their precompressed files.
+pub fn accepted_encodings(headers: &Headers) -> Vec<(&'static str, &'static str)> {
+    let accept = match headers.get("Accept-Encoding") {
+        Some(accept) => accept,
+        None => return vec![],
+    };
+    ENCODINGS.iter().copied().filter(|(encoding, _)| {
+        accept.split(',').any(|item| {
+            let mut parts = item.split(';').map(str::trim);
+            let name = parts.next().unwrap_or_default();
+            let refused = parts.any(|p| matches!(p.strip_prefix("q="), Some(q) if q.parse::<f32>() == Ok(0.0)));
+            name.eq_ignore_ascii_case(encoding) && !refused
+        })
+    }).collect()
+}
+
+/// Responds with a static file, answering conditional and range requests.
+pub async fn respond(file: StaticFile, ty: &str, encoding: Option<&str>, headers: &Headers) -> Result<Response> {
+    let etag = match encoding {
+        Some(encoding) => format!("{}-{}\"", &file.etag[..file.etag.len() - 1], encoding),
+        None => file.etag.clone(),
+    };
+    let mut validators = Headers::new();
+    validators.insert("ETag".to_string(), etag.clone());
+    if let Some(modified) = &file.last_modified {
+        validators.insert("Last-Modified".to_string(), modified.clone());
+    }
+    validators.insert("Vary".to_string(), "Accept-Encoding".to_string());
+    if is_not_modified(headers, &etag, file.last_modified.as_deref()) {
+        let mut response = Response::empty(Status::NotModified);
+        extend(&mut response, validators);
+        return Ok(response);
+    }
+
+    let len = file.len;
+    let range = match headers.get("Range") {
+        Some(range) if if_range(headers, &etag, file.last_modified.as_deref()) => parse_range(range, len),
+        _ => None,
+    };
+    let mut response = match range {
+        Some(Some((start, end))) => {
+            let mut response = file.response(Status::PartialContent, ty, start, end - start + 1).await?;
+            response.headers_mut().insert("Content-Range".to_string(), format!("bytes {}-{}/{}", start, end, len));
+            response
+        },
+        Some(None) => {
+            let mut response = Response::from_status(Status::RangeNotSatisfiable);
+            response.headers_mut().insert("Content-Range".to_string(), format!("bytes */{}", len));
+            return Ok(response);
+        },
+        None => file.response(Status::Ok, ty, 0, len).await?,
+    };
+    response.headers_mut().insert("Accept-Ranges".to_string(), "bytes".to_string());
+    if let Some(encoding) = encoding {
+        response.headers_mut().insert("Content-Encoding".to_string(), encoding.to_string());
+    }
+    extend(&mut response, validators);
+    Ok(response)
+}
+
+fn extend(response: &mut Response, headers: Headers) {
+    for (key, value) in headers.iter() {
+        response.headers_mut().insert(key.clone(), value.clone());
+    }
+}
+
+fn is_not_modified(headers: &Headers, etag: &str, last_modified: Option<&str>) -> bool {
+    if let Some(tags) = headers.get("If-None-Match") {
+        return tags.split(',').map(str::trim).any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag);
+    }
+    match (headers.get("If-Modified-Since").and_then(|since| parse_http_date(since)), last_modified.and_then(parse_http_date)) {
+        (Some(since), Some(modified)) => modified <= since,
+        _ => false,
+    }
+}
+
+/// Parses a date in any of the formats HTTP accepts, such as
+/// `Sun, 06 Nov 1994 08:49:37 GMT`, into seconds since the epoch.
+fn parse_http_date(date: &str) -> Option<i64> {
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let parts: Vec<&str> = date.split([' ', '-', ',']).filter(|part| !part.is_empty()).collect();
+    let (day, month, year, time) = match parts.as_slice() {
+        [_, day, month, year, time, "GMT"] => (day, month, year, time),
+        [_, month, day, time, year] => (day, month, year, time),
+        _ => return None,
+    };
+    let day: i64 = day.parse().ok().filter(|day| (1..=31).contains(day))?;
+    let month = MONTHS.iter().position(|m| m == month)? as i64 + 1;
+    let year: i64 = match year.parse().ok()? {
+        year if year < 70 => year + 2000,
+        year if year < 100 => year + 1900,
+        year => year,
+    };
+    let time: Vec<i64> = time.split(':').map(|n| n.parse().ok()).collect::<Option<_>>()?;
+    let (hour, minute, second) = match time.as_slice() {
+        [hour, minute, second] if *hour < 24 && *minute < 60 && *second < 61 => (*hour, *minute, *second),
+        _ => return None,
+    };
+    // Days from the epoch to the civil date, counting years from March.
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let days = era * 146097 + yoe * 365 + yoe / 4 - yoe / 100 + doy - 719468;
+    Some(days * 86400 + hour * 3600 + minute * 60 + second)
+}
+
+/// Whether the `Range` header applies, which it only does if `If-Range` names the current file.
+fn if_range(headers: &Headers, etag: &str, last_modified: Option<&str>) -> bool {
+    match headers.get("If-Range") {
+        Some(validator) => validator == etag || Some(validator.as_str()) == last_modified,
+        None => true,
+    }
+}
+
+/// Parses a single `bytes=` range into inclusive offsets.
+///
+/// Returns `None` if the header should be ignored, such as for multiple
+/// ranges, and `Some(None)` if the range cannot be satisfied.
+fn parse_range(range: &str, len: u64) -> Option<Option<(u64, u64)>> {
+    let spec = range.strip_prefix("bytes=")?.trim();
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = if start.is_empty() {
+        let suffix: u64 = end.parse().ok()?;
+        if suffix == 0 {
+            return Some(None);
+        }
+        (len.saturating_sub(suffix), len.checked_sub(1)?)
+    } else {
+        let start: u64 = start.parse().ok()?;
+        let end = if end.is_empty() {
+            len.saturating_sub(1)
+        } else {
+            end.parse::<u64>().ok()?.min(len.saturating_sub(1))
+        };
+        (start, end)
+    };
+    if start >= len || start > end {
+        Some(None)
+    } else {
+        Some(Some((start, end)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{StaticFile, respond, parse_range, parse_http_date, mime_type, is_valid_path, hashed_name};
+    use crate::web::{Headers, Status};
+
+    #[tokio::test]
+    async fn static_responses() {
+        assert_eq!(mime_type("/static/app.min.js"), "text/javascript; charset=utf-8");
+        assert_eq!(mime_type("/static/font.WOFF2"), "font/woff2");
+        assert_eq!(mime_type("/static/.hidden/LICENSE"), "application/octet-stream");
+        assert!(is_valid_path("/static/app.js.map"));
+        assert!(!is_valid_path("/static/.env"));
+        assert!(!is_valid_path("/static//a"));
+        assert_eq!(hashed_name("admin/style.css", b"a"), "admin/style.ca978112ca1b.css");
+        assert_eq!(hashed_name("app.min.js", b"a"), "app.ca978112ca1b.min.js");
+        assert_eq!(hashed_name("LICENSE", b"a"), "LICENSE.ca978112ca1b");
+
+        assert_eq!(parse_range("bytes=0-3", 10), Some(Some((0, 3))));
+        assert_eq!(parse_range("bytes=5-", 10), Some(Some((5, 9))));
+        assert_eq!(parse_range("bytes=-4", 10), Some(Some((6, 9))));
+        assert_eq!(parse_range("bytes=8-20", 10), Some(Some((8, 9))));
+        assert_eq!(parse_range("bytes=10-", 10), Some(None));
+        assert_eq!(parse_range("bytes=0-1,4-5", 10), None);
+        assert_eq!(parse_range("lines=1-2", 10), None);
+
+        let file = || StaticFile::embedded(b"0123456789");
+        let etag = file().etag;
+        let mut headers = Headers::new();
+        headers.insert("Range".to_string(), "bytes=2-4".to_string());
+        let response = respond(file(), "text/plain", None, &headers).await.unwrap();
+        assert_eq!(response.status(), Status::PartialContent);
+        assert_eq!(response.headers().get("Content-Range").unwrap(), "bytes 2-4/10");
+        assert_eq!(response.into_parts().2, b"234");
+
+        let path = std::env::temp_dir().join(format!("anansi-static-{}.txt", std::process::id()));
+        std::fs::write(&path, b"0123456789").unwrap();
+        let disk = StaticFile::read(path.to_str().unwrap()).await.unwrap().unwrap();
+        let response = respond(disk.clone(), "text/plain", None, &headers).await.unwrap();
+        assert_eq!(response.headers().get("Content-Range").unwrap(), "bytes 2-4/10");
+        let stream = response.body_stream().unwrap();
+        assert_eq!(stream.content_length(), Some(3));
+        let mut chunks = stream.take().unwrap();
+        assert_eq!(chunks.next().await.unwrap().unwrap(), "234");
+        assert!(chunks.next().await.is_none());
+
+        headers.insert("If-Range".to_string(), "\"stale\"".to_string());
+        assert_eq!(respond(file(), "text/plain", None, &headers).await.unwrap().status(), Status::Ok);
+
+        let mut headers = Headers::new();
+        headers.insert("If-None-Match".to_string(), format!("W/{}", etag));
+        assert_eq!(respond(file(), "text/plain", None, &headers).await.unwrap().status(), Status::NotModified);
+        assert_eq!(respond(file(), "text/plain", Some("br"), &headers).await.unwrap().status(), Status::Ok);
+
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), Some(784111777));
+        assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), Some(784111777));
+        assert_eq!(parse_http_date("Sun Nov  6 08:49:37 1994"), Some(784111777));
+        assert_eq!(parse_http_date("Thu, 29 Feb 2024 23:59:59 GMT"), Some(1709251199));
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 25:49:37 GMT"), None);
+        let mut disk = disk;
+        disk.last_modified = Some("Sun, 06 Nov 1994 08:49:37 GMT".to_string());
+        for (since, status) in [("Sunday, 06-Nov-94 08:49:37 GMT", Status::NotModified), ("Mon, 07 Nov 1994 00:00:00 GMT", Status::NotModified), ("Sat, 05 Nov 1994 08:49:37 GMT", Status::Ok)] {
+            let mut headers = Headers::new();
+            headers.insert("If-Modified-Since".to_string(), since.to_string());
+            assert_eq!(respond(disk.clone(), "text/plain", None, &headers).await.unwrap().status(), status);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,5 +1,6 @@
 use async_trait::async_trait;
-use std::sync::Arc;
+use std::any::{Any, TypeId};
+use std::sync::{Arc, Mutex};
 use std::collections::{HashMap, hash_map::Iter};
 use crate::db::{DbPool, invalid};
 use std::string::FromUtf8Error;
@@ -7,15 +8,24 @@ use std::error::Error;
 use std::result;
 use std::fmt;
 use std::pin::Pin;
-use std::future::Future;
+use std::future::{Future, poll_fn};
+use std::io;
+use std::path::Path;
+use std::task::{Context, Poll};
+use bytes::Bytes;
+use futures_core::Stream;
+use tokio::io::{AsyncRead, ReadBuf};
 
 use crate::server::Rng;
 use crate::router::Routes;
 use crate::records::{Record, FromParams, BigInt, DateTime};
 use crate::admin_site::AdminRef;
+use crate::query::Query;
 
 pub type Result<T> = result::Result<T, Box<dyn Error + Send + Sync>>;
 pub type View<B> = fn(B) -> Pin<Box<dyn Future<Output = Result<Response>> + Send>>;
+/// A check run before every view of an import, like those given to `#[check]`.
+pub type Guard<B> = for<'a> fn(&'a B) -> Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>>;
 pub type Static = (&'static str, &'static [u8]);
 
 const SPACE: u8 = 32;
@@ -28,6 +38,11 @@ pub const LEFT_BRACE: u8 = 123;
 
 pub const GET: Method = Method::Get;
 pub const POST: Method = Method::Post;
+pub const PUT: Method = Method::Put;
+pub const PATCH: Method = Method::Patch;
+pub const DELETE: Method = Method::Delete;
+pub const HEAD: Method = Method::Head;
+pub const OPTIONS: Method = Method::Options;
 
 fn find_base(dir: &str) -> String {
     let files = std::fs::read_dir(dir).unwrap();
@@ -60,7 +75,7 @@ thread_local!{
 }
 
 pub mod prelude {
-    pub use super::path;
+    pub use super::{path, get, post, put, patch, delete};
     pub use anansi::{import, routes};
 }
 
@@ -70,34 +85,9 @@ macro_rules! middleware {
         #[derive(Debug, Clone)]
         pub struct HttpRequest {
             raw: anansi::web::RawRequest,
-            mid: Middleware,
             urls: std::sync::Arc<std::collections::HashMap<usize, Vec<String>>>,
             admin: anansi::util::admin::site::AdminRef<Self>,
         }
-        #[derive(Debug, Clone)]
-        pub struct Middleware {
-            user: anansi::util::auth::records::User,
-            session: anansi::util::sessions::records::Session,
-            session_data: anansi::util::sessions::records::SessionData,
-        }
-        impl Middleware {
-            pub async fn new(raw: &mut anansi::web::RawRequest) -> $crate::web::Result<Self> {
-                use anansi::records::{Record, DataType};
-                let session = match anansi::util::sessions::records::Session::from_raw(raw).await {
-                    Ok(s) => s,
-                    Err(_) => return Err(Box::new(anansi::web::WebError::from(anansi::web::WebErrorKind::NoSession))),
-                };
-                let session_data = session.to_data()?;
-                let user_id = session_data.get(anansi::util::auth::records::User::KEY)?.as_i64().expect("could not get user id");
-                let user = if user_id == 0 {
-                    anansi::util::auth::records::User::guest()
-                } else {
-                    anansi::util::auth::records::User::find(anansi::records::BigInt::from_val(user_id)?).raw_get(raw.pool()).await?
-                };
-                Ok(Middleware {user, session, session_data})
-            }
-        }
-        impl anansi::web::BaseMiddleware for Middleware {}
         anansi::request_derive!();
         pub trait Request: anansi::web::BaseRequest + anansi::util::sessions::middleware::Sessions + anansi::util::auth::middleware::Auth + anansi::util::admin::site::HasAdmin + anansi::web::CsrfDefense  + anansi::web::Reverse + std::fmt::Debug + anansi::web::GetRecord + 'static {}
         impl Request for HttpRequest {}
@@ -111,19 +101,26 @@ macro_rules! middleware {
         impl anansi::util::auth::middleware::Auth for HttpRequest {}
         #[async_trait::async_trait]
         impl anansi::util::sessions::middleware::Sessions for HttpRequest {
-            fn session(&self) -> &anansi::util::sessions::records::Session {
-                &self.mid.session
+            fn session(&self) -> anansi::web::Result<&anansi::util::sessions::records::Session> {
+                self.raw.extensions().get().ok_or_else(|| anansi::web::WebError::from(anansi::web::WebErrorKind::NoSession).into())
             }
-            fn session_data(&self) -> &anansi::util::sessions::records::SessionData {
-                &self.mid.session_data
+            fn session_data(&self) -> anansi::web::Result<&anansi::util::sessions::records::SessionData> {
+                self.raw.extensions().get().ok_or_else(|| anansi::web::WebError::from(anansi::web::WebErrorKind::NoSession).into())
             }
-            fn session_data_mut(&mut self) -> &mut anansi::util::sessions::records::SessionData {
-                &mut self.mid.session_data
+            fn session_data_mut(&mut self) -> anansi::web::Result<&mut anansi::util::sessions::records::SessionData> {
+                self.raw.extensions_mut().get_mut().ok_or_else(|| anansi::web::WebError::from(anansi::web::WebErrorKind::NoSession).into())
+            }
+            fn set_session(&mut self, session: anansi::util::sessions::records::Session, session_data: anansi::util::sessions::records::SessionData) {
+                self.raw.extensions_mut().insert(session);
+                self.raw.extensions_mut().insert(session_data);
             }
             async fn update_session(&mut self) -> anansi::web::Result<()> {
                 use anansi::records::Record;
-                self.mid.session.data = self.mid.session_data.to_text()?;
-                self.mid.session.raw_update(self.raw.pool()).await
+                let mut session = self.session()?.clone();
+                session.data = self.session_data()?.to_text()?;
+                session.raw_update(self.raw.pool()).await?;
+                self.raw.extensions_mut().insert(session);
+                Ok(())
             }
         }
         #[async_trait::async_trait]
@@ -131,7 +128,7 @@ macro_rules! middleware {
             fn token(&self) -> anansi::web::Result<anansi::web::TokenRef> {
                 use anansi::util::sessions::middleware::Sessions;
                 use anansi::web::TokenRef;
-                Ok(TokenRef::from(self.session_data().get(TokenRef::KEY)?.as_str().unwrap()))
+                Ok(TokenRef::from(self.session_data()?.get(TokenRef::KEY)?.as_str().ok_or_else(anansi::db::invalid)?))
             }
             fn check_token(&mut self) -> anansi::web::Result<anansi::web::FormMap> {
                 if let Ok(form_map) = self.raw.to_form_map() {
@@ -193,10 +190,18 @@ macro_rules! err_box {
     };
 }
 
+/// Mounts the routes of an app under a prefix, such as `import!("/admin", blog, Group::is_admin)`.
+/// Apps can import other apps, and the checks given are run before each of their views.
 #[macro_export]
+#[allow(clippy::crate_in_macro_def)]
 macro_rules! import {
-    ($s:tt, $a:tt) => {
-        anansi::web::Route::Import(($s, $a::urls::ROUTES))
+    ($s:tt, $($a:ident)::+ $(, $guard:expr)* $(,)?) => {
+        anansi::web::Route::Import(($s, $($a)::+::urls::ROUTES, &[$({
+            fn guard(req: &crate::project::HttpRequest) -> std::pin::Pin<Box<dyn std::future::Future<Output = anansi::web::Result<()>> + Send + '_>> {
+                Box::pin($guard(req))
+            }
+            guard as anansi::web::Guard<crate::project::HttpRequest>
+        }),*]))
     };
 }
 
@@ -217,27 +222,42 @@ macro_rules! strip_plus {
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct Headers(HashMap<String, String>);
+#[derive(Debug, Clone, Default)]
+pub struct Headers(Vec<(String, String)>);
 
 impl Headers {
-    fn new() -> Self {
-        Self {0: HashMap::new()}
+    pub fn new() -> Self {
+        Self {0: vec![]}
     }
+    /// Sets a header, replacing any existing values with the same name.
     pub fn insert(&mut self, key: String, value: String) {
-        self.0.insert(key, value);
+        self.remove(&key);
+        self.0.push((key, value));
+    }
+    /// Adds a header without replacing existing values, as needed for `Set-Cookie`.
+    pub fn append(&mut self, key: String, value: String) {
+        self.0.push((key, value));
     }
     fn insert_str(&mut self, key: &str, value: &str) {
-        self.0.insert(key.to_string(), value.to_string());
+        self.insert(key.to_string(), value.to_string());
     }
-    fn get(&self, key: &str) -> Option<&String> {
-        self.0.get(key)
+    pub fn get(&self, key: &str) -> Option<&String> {
+        self.0.iter().find(|(k, _)| k.eq_ignore_ascii_case(key)).map(|(_, v)| v)
+    }
+    pub fn get_all<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a String> + 'a {
+        self.0.iter().filter(move |(k, _)| k.eq_ignore_ascii_case(key)).map(|(_, v)| v)
+    }
+    pub fn remove(&mut self, key: &str) {
+        self.0.retain(|(k, _)| !k.eq_ignore_ascii_case(key));
+    }
+    pub fn iter(&self) -> std::slice::Iter<'_, (String, String)> {
+        self.0.iter()
     }
 }
 
 impl IntoIterator for Headers {
     type Item = (String, String);
-    type IntoIter = std::collections::hash_map::IntoIter<String, String>;
+    type IntoIter = std::vec::IntoIter<(String, String)>;
     #[inline]
     fn into_iter(self) -> Self::IntoIter {
         self.0.into_iter()
@@ -266,6 +286,19 @@ impl<B: BaseRequest> fmt::Display for Http404<B> {
 
 impl<B: BaseRequest + fmt::Debug> Error for Http404<B> {}
 
+/// An error from reversing a view that is not mounted, or with arguments that
+/// do not fit its route.
+#[derive(Debug)]
+pub struct ReverseError(pub String);
+
+impl fmt::Display for ReverseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "could not reverse url: {}", self.0)
+    }
+}
+
+impl Error for ReverseError {}
+
 #[derive(Debug)]
 pub struct WebError {
     kind: WebErrorKind,
@@ -331,17 +364,69 @@ impl Parameters {
 #[derive(Clone)]
 pub enum Route<B: BaseRequest + 'static> {
     Path ((&'static str, View<B>)),
-    Import((&'static str, &'static [Route<B>])),
+    Method((Method, &'static str, View<B>)),
+    Import((&'static str, &'static [Route<B>], &'static [Guard<B>])),
 }
 
+/// Routes GET, HEAD and POST requests to a view that handles its own forms.
 pub const fn path<B: BaseRequest>(s: &'static str, f: View<B>) -> Route<B> {
     Route::Path((s, f))
 }
 
+pub const fn get<B: BaseRequest>(s: &'static str, f: View<B>) -> Route<B> {
+    Route::Method((Method::Get, s, f))
+}
+
+pub const fn post<B: BaseRequest>(s: &'static str, f: View<B>) -> Route<B> {
+    Route::Method((Method::Post, s, f))
+}
+
+pub const fn put<B: BaseRequest>(s: &'static str, f: View<B>) -> Route<B> {
+    Route::Method((Method::Put, s, f))
+}
+
+pub const fn patch<B: BaseRequest>(s: &'static str, f: View<B>) -> Route<B> {
+    Route::Method((Method::Patch, s, f))
+}
+
+pub const fn delete<B: BaseRequest>(s: &'static str, f: View<B>) -> Route<B> {
+    Route::Method((Method::Delete, s, f))
+}
+
 #[derive(PartialEq, Eq, Copy, Clone, Debug)]
 pub enum Method {
     Get,
     Post,
+    Put,
+    Patch,
+    Delete,
+    Head,
+    Options,
+}
+
+impl Method {
+    pub const ALL: [Method; 7] = [Method::Get, Method::Head, Method::Post, Method::Put, Method::Patch, Method::Delete, Method::Options];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Get => "GET",
+            Self::Post => "POST",
+            Self::Put => "PUT",
+            Self::Patch => "PATCH",
+            Self::Delete => "DELETE",
+            Self::Head => "HEAD",
+            Self::Options => "OPTIONS",
+        }
+    }
+    pub fn from_bytes(b: &[u8]) -> Option<Self> {
+        Self::ALL.into_iter().find(|m| m.as_str().as_bytes() == b)
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -350,70 +435,413 @@ pub struct Body {
 }
 
 impl Body {
+    pub fn new(body: Vec<u8>) -> Self {
+        Self {body}
+    }
     pub fn as_slice(&self) -> &[u8] {
         self.body.as_slice()
     }
 }
 
+/// Size of the chunks read by `BodyStream::from_reader`.
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// A body produced while the response is written, sent with chunked transfer
+/// encoding unless its length is known.
+///
+/// Clones share the chunks, which can only be taken once.
+#[derive(Clone)]
+pub struct BodyStream {
+    len: Option<u64>,
+    ends_on_shutdown: bool,
+    chunks: Arc<Mutex<Option<Chunks>>>,
+}
+
+impl BodyStream {
+    pub fn new<S: Stream<Item = io::Result<Bytes>> + Send + 'static>(stream: S) -> Self {
+        Self {len: None, ends_on_shutdown: false, chunks: Arc::new(Mutex::new(Some(Chunks(Box::pin(stream)))))}
+    }
+    /// Streams the contents of `reader` until it reaches the end.
+    pub fn from_reader<R: AsyncRead + Send + 'static>(reader: R) -> Self {
+        Self::new(ReaderStream {reader: Box::pin(reader), buf: vec![0; CHUNK_SIZE]})
+    }
+    /// Sets the length of the body, which is then sent with `Content-Length` instead of in chunks.
+    pub fn with_len(mut self, len: u64) -> Self {
+        self.len = Some(len);
+        self
+    }
+    pub fn content_length(&self) -> Option<u64> {
+        self.len
+    }
+    /// Ends the body as soon as the server starts shutting down, for streams
+    /// that would otherwise keep it waiting until `shutdown_timeout`. Other
+    /// bodies keep streaming until then, and are cut off if still unfinished.
+    pub fn end_on_shutdown(mut self) -> Self {
+        self.ends_on_shutdown = true;
+        self
+    }
+    pub fn ends_on_shutdown(&self) -> bool {
+        self.ends_on_shutdown
+    }
+    /// Takes the chunks to write, returning `None` if they were already taken.
+    pub fn take(&self) -> Option<Chunks> {
+        self.chunks.lock().unwrap().take()
+    }
+}
+
+impl fmt::Debug for BodyStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BodyStream").field("len", &self.len).field("ends_on_shutdown", &self.ends_on_shutdown).finish_non_exhaustive()
+    }
+}
+
+/// The chunks of a `BodyStream`.
+pub struct Chunks(Pin<Box<dyn Stream<Item = io::Result<Bytes>> + Send>>);
+
+impl Chunks {
+    pub async fn next(&mut self) -> Option<io::Result<Bytes>> {
+        poll_fn(|cx| self.0.as_mut().poll_next(cx)).await
+    }
+}
+
+struct ReaderStream<R> {
+    reader: Pin<Box<R>>,
+    buf: Vec<u8>,
+}
+
+impl<R: AsyncRead> Stream for ReaderStream<R> {
+    type Item = io::Result<Bytes>;
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let mut buf = ReadBuf::new(&mut this.buf);
+        match this.reader.as_mut().poll_read(cx, &mut buf) {
+            Poll::Ready(Ok(())) if buf.filled().is_empty() => Poll::Ready(None),
+            Poll::Ready(Ok(())) => Poll::Ready(Some(Ok(Bytes::copy_from_slice(buf.filled())))),
+            Poll::Ready(Err(e)) => Poll::Ready(Some(Err(e))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// A `Content-Disposition` value, with an ASCII fallback for clients that
+/// do not understand the encoded file name.
+pub fn content_disposition(disposition: &str, filename: &str) -> String {
+    let fallback: String = filename.chars().map(|c| {
+        if c.is_ascii() && !c.is_ascii_control() && c != '"' && c != '\\' { c } else { '_' }
+    }).collect();
+    let mut encoded = String::new();
+    for b in filename.bytes() {
+        if b.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&b) {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+    format!("{}; filename=\"{}\"; filename*=UTF-8''{}", disposition, fallback, encoded)
+}
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+#[non_exhaustive]
+pub enum Status {
+    Ok,
+    Created,
+    Accepted,
+    NoContent,
+    PartialContent,
+    MovedPermanently,
+    Found,
+    SeeOther,
+    NotModified,
+    TemporaryRedirect,
+    PermanentRedirect,
+    BadRequest,
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    MethodNotAllowed,
+    RequestTimeout,
+    Conflict,
+    Gone,
+    PayloadTooLarge,
+    RangeNotSatisfiable,
+    UnprocessableEntity,
+    TooManyRequests,
+    InternalServerError,
+    NotImplemented,
+    ServiceUnavailable,
+}
+
+impl Status {
+    pub fn code(&self) -> u16 {
+        match self {
+            Self::Ok => 200,
+            Self::Created => 201,
+            Self::Accepted => 202,
+            Self::NoContent => 204,
+            Self::PartialContent => 206,
+            Self::MovedPermanently => 301,
+            Self::Found => 302,
+            Self::SeeOther => 303,
+            Self::NotModified => 304,
+            Self::TemporaryRedirect => 307,
+            Self::PermanentRedirect => 308,
+            Self::BadRequest => 400,
+            Self::Unauthorized => 401,
+            Self::Forbidden => 403,
+            Self::NotFound => 404,
+            Self::MethodNotAllowed => 405,
+            Self::RequestTimeout => 408,
+            Self::Conflict => 409,
+            Self::Gone => 410,
+            Self::PayloadTooLarge => 413,
+            Self::RangeNotSatisfiable => 416,
+            Self::UnprocessableEntity => 422,
+            Self::TooManyRequests => 429,
+            Self::InternalServerError => 500,
+            Self::NotImplemented => 501,
+            Self::ServiceUnavailable => 503,
+        }
+    }
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Self::Ok => "OK",
+            Self::Created => "Created",
+            Self::Accepted => "Accepted",
+            Self::NoContent => "No Content",
+            Self::PartialContent => "Partial Content",
+            Self::MovedPermanently => "Moved Permanently",
+            Self::Found => "Found",
+            Self::SeeOther => "See Other",
+            Self::NotModified => "Not Modified",
+            Self::TemporaryRedirect => "Temporary Redirect",
+            Self::PermanentRedirect => "Permanent Redirect",
+            Self::BadRequest => "Bad Request",
+            Self::Unauthorized => "Unauthorized",
+            Self::Forbidden => "Forbidden",
+            Self::NotFound => "Not Found",
+            Self::MethodNotAllowed => "Method Not Allowed",
+            Self::RequestTimeout => "Request Timeout",
+            Self::Conflict => "Conflict",
+            Self::Gone => "Gone",
+            Self::PayloadTooLarge => "Payload Too Large",
+            Self::RangeNotSatisfiable => "Range Not Satisfiable",
+            Self::UnprocessableEntity => "Unprocessable Entity",
+            Self::TooManyRequests => "Too Many Requests",
+            Self::InternalServerError => "Internal Server Error",
+            Self::NotImplemented => "Not Implemented",
+            Self::ServiceUnavailable => "Service Unavailable",
+        }
+    }
+    /// Whether responses with this status must not have a body.
+    pub fn is_bodiless(&self) -> bool {
+        matches!(self, Self::NoContent | Self::NotModified)
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.code(), self.reason())
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Response {
-    status_line: String,
+    status: Status,
     headers: Headers,
     body: Option<Body>,
+    stream: Option<BodyStream>,
 }
 
 impl Response {
+    pub fn status(&self) -> Status {
+        self.status
+    }
+    pub fn status_mut(&mut self) -> &mut Status {
+        &mut self.status
+    }
     pub fn headers(&self) -> &Headers {
         &self.headers
     }
     pub fn headers_mut(&mut self) -> &mut Headers {
         &mut self.headers
     }
-    pub fn new(status_line: &'static str, contents: Vec<u8>) -> Self {
+    pub fn body(&self) -> &Option<Body> {
+        &self.body
+    }
+    pub fn body_mut(&mut self) -> &mut Option<Body> {
+        &mut self.body
+    }
+    pub fn body_stream(&self) -> Option<&BodyStream> {
+        self.stream.as_ref()
+    }
+    pub fn new(status: Status, contents: Vec<u8>) -> Self {
         let mut headers = Headers::new();
-        headers.insert_str("content-type", "text/html");
-        headers.insert_str("charset", "UTF-8");
+        headers.insert_str("Content-Type", "text/html; charset=utf-8");
         headers.insert_str("Server", "webserver");
-        headers.insert_str("content-security-policy", "default-src 'self'; script-src 'self'; connect-src 'self'; img-src 'self'; style-src 'self'; frame-ancestors 'none'; form-action 'self'; upgrade-insecure-requests;");
-        headers.insert_str("x-frame-options", "DENY");
-        headers.insert("Content-length".to_string(), contents.len().to_string());
+        headers.insert_str("Content-Security-Policy", "default-src 'self'; script-src 'self'; connect-src 'self'; img-src 'self'; style-src 'self'; frame-ancestors 'none'; form-action 'self'; upgrade-insecure-requests;");
+        headers.insert_str("X-Frame-Options", "DENY");
         let body = Some(Body {body: contents});
-        Self {status_line: status_line.to_string(), headers, body}
+        Self {status, headers, body, stream: None}
     }
-    pub fn content(status_line: &'static str, ty: &str, contents: Vec<u8>) -> Self {
+    pub fn content(status: Status, ty: &str, contents: Vec<u8>) -> Self {
         let mut headers = Headers::new();
         headers.insert_str("Content-Type", ty);
-        headers.insert("Content-Length".to_string(), contents.len().to_string());
         let body = Some(Body {body: contents});
-        Self {status_line: status_line.to_string(), headers, body}
+        Self {status, headers, body, stream: None}
+    }
+    /// A response whose body is written as it is produced.
+    pub fn stream(status: Status, ty: &str, stream: BodyStream) -> Self {
+        let mut headers = Headers::new();
+        headers.insert_str("Content-Type", ty);
+        Self {status, headers, body: None, stream: Some(stream)}
+    }
+    /// Streams a file, to be shown in the browser when it can be.
+    pub async fn file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::file_with(path.as_ref(), "inline", None).await
+    }
+    /// Streams a file to be downloaded as `filename`.
+    pub async fn attachment<P: AsRef<Path>>(path: P, filename: &str) -> Result<Self> {
+        Self::file_with(path.as_ref(), "attachment", Some(filename)).await
+    }
+    async fn file_with(path: &Path, disposition: &str, filename: Option<&str>) -> Result<Self> {
+        let file = tokio::fs::File::open(path).await?;
+        let len = file.metadata().await?.len();
+        let filename = match filename {
+            Some(filename) => filename,
+            None => path.file_name().and_then(|name| name.to_str()).ok_or_else(invalid)?,
+        };
+        let mut response = Self::stream(Status::Ok, crate::static_files::mime_type(filename), BodyStream::from_reader(file).with_len(len));
+        response.headers.insert_str("Content-Disposition", &content_disposition(disposition, filename));
+        Ok(response)
+    }
+    /// A response without a body.
+    pub fn empty(status: Status) -> Self {
+        Self {status, headers: Headers::new(), body: None, stream: None}
+    }
+    /// A plain text response containing the status, used for errors.
+    pub fn from_status(status: Status) -> Self {
+        Self::content(status, "text/plain; charset=utf-8", status.to_string().into_bytes())
+    }
+    pub fn ok(contents: Vec<u8>) -> Self {
+        Self::new(Status::Ok, contents)
+    }
+    pub fn created(contents: Vec<u8>) -> Self {
+        Self::new(Status::Created, contents)
+    }
+    pub fn no_content() -> Self {
+        Self::empty(Status::NoContent)
+    }
+    pub fn json(contents: Vec<u8>) -> Self {
+        Self::content(Status::Ok, "application/json", contents)
+    }
+    pub fn bad_request() -> Self {
+        Self::from_status(Status::BadRequest)
+    }
+    pub fn forbidden() -> Self {
+        Self::from_status(Status::Forbidden)
+    }
+    pub fn not_found() -> Self {
+        Self::from_status(Status::NotFound)
+    }
+    pub fn method_not_allowed(allow: &[Method]) -> Self {
+        let mut response = Self::from_status(Status::MethodNotAllowed);
+        response.headers.insert_str("Allow", &allow_header(allow));
+        response
+    }
+    pub fn payload_too_large() -> Self {
+        Self::from_status(Status::PayloadTooLarge)
     }
     pub fn internal_error(b: &[u8]) -> Self {
-        Self::new("HTTP/1.1 500 INTERNAL SERVER ERROR", b.to_vec())
+        Self::new(Status::InternalServerError, b.to_vec())
     }
+    /// Redirects with `303 See Other`, so the client follows up with a GET.
     pub fn redirect(location: &str) -> Self {
-        let headers = Headers::new();
-        Self {status_line: format!("HTTP/1.1 303 See Other\r\nLocation: {}", location), headers, body: None}
+        Self::redirect_with(Status::SeeOther, location)
+    }
+    pub fn moved_permanently(location: &str) -> Self {
+        Self::redirect_with(Status::MovedPermanently, location)
+    }
+    pub fn found(location: &str) -> Self {
+        Self::redirect_with(Status::Found, location)
+    }
+    pub fn temporary_redirect(location: &str) -> Self {
+        Self::redirect_with(Status::TemporaryRedirect, location)
+    }
+    pub fn redirect_with(status: Status, location: &str) -> Self {
+        let mut headers = Headers::new();
+        headers.insert_str("Location", location);
+        Self {status, headers, body: None, stream: None}
     }
     pub fn set_persistent(mut self, key: &str, value: &str, expires: &DateTime) -> Self {
-        self.headers.insert("Set-Cookie".to_string(), format!("{key}={value}; Path=/; Expires={}; Secure; HttpOnly; SameSite=Lax", expires.to_gmt()));
+        self.headers.append("Set-Cookie".to_string(), format!("{key}={value}; Path=/; Expires={}; Secure; HttpOnly; SameSite=Lax", expires.to_gmt()));
         self
     }
+    /// Serializes the response, with only the status line and headers of a streaming body.
     pub fn into_bytes(self) -> Vec<u8> {
-        let mut s = String::from(format!("{}\r\n", self.status_line));
+        self.serialize(true)
+    }
+    /// Serializes the response to a HEAD request, keeping `Content-Length` but not the body.
+    pub fn into_head_bytes(self) -> Vec<u8> {
+        self.serialize(false)
+    }
+    /// Splits the response into its status, headers and body, for protocols
+    /// other than HTTP/1.1 that frame the response themselves.
+    pub fn into_parts(self) -> (Status, Headers, Vec<u8>) {
+        let body = if self.status.is_bodiless() {
+            vec![]
+        } else {
+            self.body.map(|b| b.body).unwrap_or_default()
+        };
+        (self.status, self.headers, body)
+    }
+    fn serialize(self, with_body: bool) -> Vec<u8> {
+        let mut s = format!("HTTP/1.1 {}\r\n", self.status);
+        let body = if self.status.is_bodiless() || self.stream.is_some() {
+            None
+        } else {
+            Some(self.body.map(|b| b.body).unwrap_or_default())
+        };
         for (key, value) in self.headers {
-            s.push_str(&format!("{key}: {value};\r\n"));
+            if key.eq_ignore_ascii_case("Content-Length") || key.eq_ignore_ascii_case("Transfer-Encoding") {
+                continue;
+            }
+            s.push_str(&format!("{key}: {value}\r\n"));
+        }
+        if let Some(body) = &body {
+            s.push_str(&format!("Content-Length: {}\r\n", body.len()));
+        } else if let Some(stream) = self.stream.as_ref().filter(|_| !self.status.is_bodiless()) {
+            match stream.content_length() {
+                Some(len) => s.push_str(&format!("Content-Length: {}\r\n", len)),
+                None => s.push_str("Transfer-Encoding: chunked\r\n"),
+            }
         }
         s.push_str("\r\n");
-        
+
         let mut bytes = s.into_bytes();
-        if let Some(mut body) = self.body {
-            bytes.append(&mut body.body);
+        if let Some(mut body) = body {
+            if with_body {
+                bytes.append(&mut body);
+            }
         }
-        bytes.append(&mut b"0\r\n\r\n".to_vec());
         bytes
     }
 }
 
+fn allow_header(allow: &[Method]) -> String {
+    let mut methods: Vec<&str> = vec![];
+    for method in Method::ALL {
+        let allowed = match method {
+            Method::Head => allow.contains(&Method::Get) || allow.contains(&Method::Head),
+            Method::Options => true,
+            _ => allow.contains(&method),
+        };
+        if allowed {
+            methods.push(method.as_str());
+        }
+    }
+    methods.join(", ")
+}
+
 pub struct RequestLine {
     pub method: Method,
     pub url: String,
@@ -450,6 +878,7 @@ impl Cookies {
 pub struct RawRequest {
     pub method: Method,
     pub url: String,
+    pub query: Query,
     pub headers: Headers,
     pub body: Option<Body>,
     pub cookies: Cookies,
@@ -457,6 +886,68 @@ pub struct RawRequest {
     pool: DbPool,
     std_rng: Rng,
     valid_token: bool,
+    extensions: Extensions,
+}
+
+/// Values attached to a request by middleware, at most one of each type, such
+/// as the session and the logged in user.
+#[derive(Clone, Default)]
+pub struct Extensions {
+    map: HashMap<TypeId, Box<dyn Extension>>,
+}
+
+impl Extensions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Attaches `value`, returning the value of the same type it replaces.
+    pub fn insert<T: Clone + Send + Sync + 'static>(&mut self, value: T) -> Option<T> {
+        self.map.insert(TypeId::of::<T>(), Box::new(value)).and_then(|old| old.into_any().downcast().ok().map(|old| *old))
+    }
+    pub fn get<T: 'static>(&self) -> Option<&T> {
+        self.map.get(&TypeId::of::<T>()).and_then(|value| (**value).as_any().downcast_ref())
+    }
+    pub fn get_mut<T: 'static>(&mut self) -> Option<&mut T> {
+        self.map.get_mut(&TypeId::of::<T>()).and_then(|value| (**value).as_any_mut().downcast_mut())
+    }
+    pub fn remove<T: 'static>(&mut self) -> Option<T> {
+        self.map.remove(&TypeId::of::<T>()).and_then(|old| old.into_any().downcast().ok().map(|old| *old))
+    }
+}
+
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Extensions").finish_non_exhaustive()
+    }
+}
+
+/// A value in `Extensions`, which can be copied along with the request.
+trait Extension: Any + Send + Sync {
+    fn clone_box(&self) -> Box<dyn Extension>;
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+    fn into_any(self: Box<Self>) -> Box<dyn Any>;
+}
+
+impl<T: Clone + Send + Sync + 'static> Extension for T {
+    fn clone_box(&self) -> Box<dyn Extension> {
+        Box::new(self.clone())
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        self
+    }
+}
+
+impl Clone for Box<dyn Extension> {
+    fn clone(&self) -> Self {
+        (**self).clone_box()
+    }
 }
 
 pub fn percent_encode(s: String) -> String {
@@ -489,56 +980,22 @@ pub fn percent_encode(s: String) -> String {
     t
 }
 
+/// Decodes `%XX` escapes and `+` as a space, failing on invalid escapes or UTF-8.
 pub fn percent_decode(s: &str) -> Result<String> {
-    let mut t = String::new();
-    let mut chars = s.chars();
-    while let Some(c) = chars.next() {
-        if c == '%' {
-            let mut s = String::new();
-            s.push(chars.next().ok_or(invalid())?);
-            s.push(chars.next().ok_or(invalid())?);
-            match s.as_str() {
-                "21" => t.push('!'), 
-                "22" => t.push('"'), 
-                "23" => t.push('#'), 
-                "24" => t.push('$'), 
-                "25" => t.push('%'), 
-                "26" => t.push('&'), 
-                "27" => t.push('\''), 
-                "28" => t.push('('), 
-                "29" => t.push(')'), 
-                "2A" => t.push('*'), 
-                "2B" => t.push('+'), 
-                "2C" => t.push(','), 
-                "2D" => t.push('-'), 
-                "2E" => t.push('.'), 
-                "2F" => t.push('/'), 
-                "3A" => t.push(':'), 
-                "3B" => t.push(';'), 
-                "3C" => t.push('<'), 
-                "3D" => t.push('='), 
-                "3E" => t.push('>'), 
-                "3F" => t.push('?'), 
-                "40" => t.push('@'), 
-                "5B" => t.push('['), 
-                "5C" => t.push('\\'), 
-                "5D" => t.push(']'), 
-                "5E" => t.push('^'), 
-                "5F" => t.push('_'), 
-                "60" => t.push('`'), 
-                "7B" => t.push('{'), 
-                "7C" => t.push('|'), 
-                "7D" => t.push('}'), 
-                "7E" => t.push('~'), 
-                _ => return Err(invalid()),
-            }
-        } else if c == '+' {
-            t.push(' ');
-        } else {
-            t.push(c);
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut iter = s.bytes();
+    while let Some(b) = iter.next() {
+        match b {
+            b'%' => {
+                let hex = [iter.next().ok_or_else(invalid)?, iter.next().ok_or_else(invalid)?];
+                let hex = std::str::from_utf8(&hex)?;
+                bytes.push(u8::from_str_radix(hex, 16)?);
+            },
+            b'+' => bytes.push(b' '),
+            _ => bytes.push(b),
         }
     }
-    Ok(t)
+    Ok(String::from_utf8(bytes)?)
 }
 
 pub fn html_escape(s: &str) -> String {
@@ -572,52 +1029,12 @@ fn find_byte(buffer: &[u8], n: usize, byte: u8) -> usize {
     buffer.len()
 }
 
-fn find_bytes(buffer: &[u8], mut n: usize, bytes: &[u8]) -> usize {
-    loop {
-        let m = find_byte(buffer, n, bytes[0]) + 1;
-        if n >= buffer.len() - bytes.len() {
-            return m;
-        }
-        let o = m + bytes.len() - 1;
-        if buffer[m..o] == bytes[1..] {
-            return o;
-        } else {
-            n = m + 1;
-        }
-    }
-}
-
-fn find_space(buffer: &[u8], n: usize) -> usize {
-    find_byte(buffer, n, SPACE)
-}
-
-fn get_headers(buffer: &[u8], o: &mut usize) -> Result<Headers> {
-    let mut headers = Headers::new();
-    let mut n = *o;
-    let mut m = find_bytes(buffer, n, b"\r\n");
-    while n < buffer.len() {
-        let n2 = find_bytes(buffer, n, b": ");
-        let key = get_string(&buffer[n..n2-2])?;
-        let value = get_string(&buffer[n2..m-2])?;
-        headers.insert(key, value);
-        let m2 = find_bytes(buffer, m, b"\r\n");
-        if m2 - m <= 2 {
-            break;
-        } else {
-            n = m;
-            m = m2;
-        }
-    }
-    *o = m;
-    Ok(headers)
-}
-
 pub fn get_string(buffer: &[u8]) -> result::Result<String, FromUtf8Error> {
     String::from_utf8(buffer.to_vec())
 }
 
 pub trait Reverse {
-    fn reverse(&self, view: View<Self>, args: &[&dyn fmt::Display]) -> String;
+    fn reverse(&self, view: View<Self>, args: &[&dyn fmt::Display]) -> Result<String>;
 }
 
 #[async_trait]
@@ -629,88 +1046,60 @@ pub trait GetRecord: BaseRequest {
 
 #[async_trait]
 pub trait BaseRequest: Send + Sync {
-    type Mid: BaseMiddleware;
     type Usr: BaseUser;
 
-    async fn new(buffer: &[u8], request_line: RequestLine, url: Arc<HashMap<usize, Vec<String>>>, pool: DbPool, rng: Rng, admin: AdminRef<Self>) -> Result<Self> where Self: Sized;
+    async fn new(raw: RawRequest, url: Arc<HashMap<usize, Vec<String>>>, admin: AdminRef<Self>) -> Result<Self> where Self: Sized;
     fn method(&self) -> &Method;
     fn url(&self) -> &String;
+    fn query(&self) -> &Query;
     fn headers(&self) -> &Headers;
     fn body(&self) -> &Option<Body>;
     fn cookies(&self) -> &Cookies;
     fn params(&self) -> &Parameters;
     fn params_mut(&mut self) -> &mut Parameters;
     fn raw(&self) -> &RawRequest;
-    fn mid(&self) -> &Self::Mid;
+    fn raw_mut(&mut self) -> &mut RawRequest;
     fn to_form_map(&self) -> Result<FormMap>;
-    fn from(raw: RawRequest, mid: Self::Mid, urls: Arc<HashMap<usize, Vec<String>>>, admin: AdminRef<Self>) -> Self where Self: Sized;
+    fn from(raw: RawRequest, urls: Arc<HashMap<usize, Vec<String>>>, admin: AdminRef<Self>) -> Self where Self: Sized;
     fn user(&self) -> &Self::Usr;
     fn user_mut(&mut self) -> &mut Self::Usr;
     fn to_raw(self) -> RawRequest;
-    async fn handle_no_session(response: Response, pool: DbPool, std_rng: Rng) -> Result<Response> where Self: Sized;
-}
-
-fn parse_query_string(qs: &str) -> Option<Vec<(String, String)>> {
-    let qv: Vec<&str> = qs.split('&').collect();
-    let mut queries = vec![];
-    for q in qv {
-        if let Some((key, value)) = q.split_once('=') {
-            if let Ok(k) = percent_decode(key) {
-                if let Ok(val) = percent_decode(value) {
-                    queries.push((k, val));
-                } else {
-                    return None;
-                }
-            } else {
-                return None;
-            }
-        } else {
-            return None;
-        }
-    }
-    Some(queries)
 }
 
 pub async fn route_request<B: BaseRequest + fmt::Debug + 'static>(dirs: Vec<&str>, mut req: B, routes: &Routes<B>) -> Result<Response> {
-    for (patterns, view) in routes {
-        if patterns.len() == dirs.len() {
-            let mut b = true;
-            let mut params = Parameters::new();
-            for (pattern, dir) in patterns.iter().zip(dirs.iter()) {
-                match pattern.as_bytes()[0] {
-                    SLASH => if pattern == dir {
-                        continue;
-                    } else if let Some((d, qs)) = dir.split_once('?') {
-                        if pattern == d {
-                            if let Some(qv) = parse_query_string(qs) {
-                                for (k, v) in qv {
-                                    params.insert(k, v);
-                                }
-                                break;
-                            }
-                        }
-                        b = false;
-                        break;
-                    } else {
-                        b = false;
-                        break;
-                    },
-                    LEFT_BRACE => {
-                        params.insert(pattern[1..].to_string(), dir[1..].to_string());
-                    },
-                    _ => return Err(invalid()),
-                }
-            }
-            if b {
-                *req.params_mut() = params;
-                return view(req).await;
+    let method = *req.method();
+    let (views, mut params) = match routes.find(&dirs) {
+        Some(found) => found,
+        None => return Err(Box::new(anansi::web::Http404 {req})),
+    };
+    let found = views.iter().find(|(m, _, _)| *m == method).or_else(|| if method == Method::Head {
+        views.iter().find(|(m, _, _)| *m == Method::Get)
+    } else {
+        None
+    });
+    if let Some((_, view, guards)) = found {
+        for (key, value) in req.raw().query().iter() {
+            if params.get(key).is_err() {
+                params.insert(key.clone(), value.clone());
             }
         }
+        *req.params_mut() = params;
+        for guard in guards {
+            guard(&req).await?;
+        }
+        return view(req).await;
+    }
+    let allowed: Vec<Method> = views.iter().map(|(m, _, _)| *m).collect();
+    if method == Method::Options {
+        let mut response = Response::no_content();
+        response.headers_mut().insert("Allow".to_string(), allow_header(&allowed));
+        Ok(response)
+    } else {
+        Ok(Response::method_not_allowed(&allowed))
     }
-    Err(Box::new(anansi::web::Http404 {req}))
 }
 
-impl<M, U> fmt::Debug for dyn BaseRequest<Mid = M, Usr = U> {
+impl<U> fmt::Debug for dyn BaseRequest<Usr = U> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "BaseRequest")
     }
@@ -720,9 +1109,9 @@ impl<M, U> fmt::Debug for dyn BaseRequest<Mid = M, Usr = U> {
 macro_rules! request_derive {
     () => {
         impl anansi::web::Reverse for HttpRequest {
-            fn reverse(&self, view: anansi::web::View<Self>, disps: &[&dyn std::fmt::Display]) -> String {
+            fn reverse(&self, view: anansi::web::View<Self>, disps: &[&dyn std::fmt::Display]) -> anansi::web::Result<String> {
                 let mut disp = disps.iter();
-                let patterns = self.urls.get(&(view as anansi::web::View<Self> as usize)).expect("could not get url");
+                let patterns = self.urls.get(&(view as anansi::web::View<Self> as usize)).ok_or_else(|| anansi::web::ReverseError("view is not mounted".to_string()))?;
                 let mut s = String::new();
                 for pattern in patterns {
                     match pattern.as_bytes()[0] {
@@ -730,26 +1119,28 @@ macro_rules! request_derive {
                             s.push_str(pattern);
                         },
                         anansi::web::LEFT_BRACE => {
-                            let d = format!("/{}", disp.next().expect("too few arguments to reverse url").to_string());
-                            s.push_str(&d);
+                            let arg = disp.next().ok_or_else(|| anansi::web::ReverseError("too few arguments".to_string()))?.to_string();
+                            let (_, converter) = anansi::router::Converter::from_capture(pattern)?;
+                            if !converter.matches(&arg) {
+                                return Err(Box::new(anansi::web::ReverseError(format!("argument \"{}\" does not match {{{}}}", arg, &pattern[1..]))));
+                            }
+                            s.push('/');
+                            s.push_str(&arg);
                         },
-                        _ => panic!("could not reverse url"),
+                        _ => return Err(Box::new(anansi::web::ReverseError(format!("unexpected pattern \"{}\"", pattern)))),
                     }
                 }
-                s
+                Ok(s)
             }
         }
         #[async_trait::async_trait]
         impl anansi::web::GetRecord for HttpRequest {}
         #[async_trait::async_trait]
         impl anansi::web::BaseRequest for HttpRequest {
-            type Mid = Middleware;
             type Usr = anansi::util::auth::records::User;
 
-            async fn new(buffer: &[u8], request_line: $crate::web::RequestLine, urls: std::sync::Arc<std::collections::HashMap<usize, Vec<String>>>, pool: $crate::db::DbPool, std_rng: $crate::server::Rng, admin: $crate::admin_site::AdminRef<Self>) -> $crate::web::Result<Self> where Self: Sized {
-                let mut raw = $crate::web::RawRequest::new(buffer, request_line, pool, std_rng).await?;
-                let mid = Middleware::new(&mut raw).await?;
-                Ok(Self {raw, mid, urls, admin})
+            async fn new(raw: $crate::web::RawRequest, urls: std::sync::Arc<std::collections::HashMap<usize, Vec<String>>>, admin: $crate::admin_site::AdminRef<Self>) -> $crate::web::Result<Self> where Self: Sized {
+                Ok(<Self as anansi::web::BaseRequest>::from(raw, urls, admin))
             }
             fn method(&self) -> &anansi::web::Method {
                 &self.raw.method
@@ -757,6 +1148,9 @@ macro_rules! request_derive {
             fn url(&self) -> &String {
                 &self.raw.url
             }
+            fn query(&self) -> &anansi::query::Query {
+                &self.raw.query
+            }
             fn headers(&self) -> &anansi::web::Headers {
                 &self.raw.headers
             }
@@ -772,53 +1166,44 @@ macro_rules! request_derive {
             fn params_mut(&mut self) -> &mut anansi::web::Parameters {
                 &mut self.raw.params
             }
-            fn mid(&self) -> &Self::Mid {
-                &self.mid
-            }
             fn raw(&self) -> &anansi::web::RawRequest {
                 &self.raw
             }
+            fn raw_mut(&mut self) -> &mut anansi::web::RawRequest {
+                &mut self.raw
+            }
             fn to_form_map(&self) -> anansi::web::Result<anansi::web::FormMap> {
                 self.raw().to_form_map()
             }
-            fn from(raw: anansi::web::RawRequest, mid: Middleware, urls: std::sync::Arc<std::collections::HashMap<usize, Vec<String>>>, admin: $crate::admin_site::AdminRef<Self>) -> Self {
-                Self{raw, mid, urls, admin}
+            fn from(mut raw: anansi::web::RawRequest, urls: std::sync::Arc<std::collections::HashMap<usize, Vec<String>>>, admin: $crate::admin_site::AdminRef<Self>) -> Self {
+                if raw.extensions().get::<Self::Usr>().is_none() {
+                    raw.extensions_mut().insert(anansi::util::auth::records::User::guest());
+                }
+                Self {raw, urls, admin}
             }
             fn to_raw(self) -> anansi::web::RawRequest {
                 self.raw
             }
             fn user(&self) -> &Self::Usr {
-                &self.mid.user
+                self.raw.extensions().get().expect("a user is set when the request is made")
             }
             fn user_mut(&mut self) -> &mut Self::Usr {
-                &mut self.mid.user
-            }
-            async fn handle_no_session(response: anansi::web::Response, pool: anansi::db::DbPool, std_rng: anansi::server::Rng) -> anansi::web::Result<anansi::web::Response> {
-                let session = anansi::util::sessions::records::Session::gen(&pool, &std_rng).await?;
-                Ok(response.set_persistent("st", &session.secret, &session.expires))
+                self.raw.extensions_mut().get_mut().expect("a user is set when the request is made")
             }
         }
     }
 }
 
 impl RawRequest {
-    pub fn get_request_line(buffer: &[u8]) -> Result<(usize, RequestLine)> {
-        let mut m = find_space(buffer, 0);
-        let method = match &buffer[..m] {
-            b"GET" => Method::Get,
-            b"POST" => Method::Post,
-            _ => return Err(invalid())
-        };
-        let mut n = m + 1;
-        m = find_space(buffer, n);
-        let url = get_string(&buffer[n..m])?;
-        n = m + 1;
-        m = find_bytes(buffer, n, b"\r\n");
-        let version = get_string(&buffer[n..m-2])?;
-        if version != "HTTP/1.1" {
+    pub fn get_request_line(line: &[u8]) -> Result<RequestLine> {
+        let mut parts = line.split(|b| *b == SPACE);
+        let method = Method::from_bytes(parts.next().ok_or_else(invalid)?).ok_or_else(invalid)?;
+        let url = get_string(parts.next().ok_or_else(invalid)?)?;
+        let version = parts.next().ok_or_else(invalid)?;
+        if version != b"HTTP/1.1" || parts.next().is_some() || !url.starts_with('/') {
             return Err(invalid());
         }
-        Ok((m, RequestLine{method, url}))
+        Ok(RequestLine{method, url})
     }
     fn get_cookies(headers: &Headers) -> Result<Cookies> {
         let mut cookies = HashMap::new();
@@ -827,24 +1212,33 @@ impl RawRequest {
             let mut n = 0;
             while n < buffer.len() {
                 let mut m = find_byte(buffer, n, EQUAL);
+                if m == buffer.len() {
+                    return Err(invalid());
+                }
                 let key = get_string(&buffer[n..m])?;
                 n = m + 1;
                 m = find_byte(buffer, n, SEMICOLON);
                 let value = get_string(&buffer[n..m])?;
                 cookies.insert(key, value);
-                n = m + 2;
+                n = m + 1;
+                while n < buffer.len() && buffer[n] == SPACE {
+                    n += 1;
+                }
             }
         }
         Ok(Cookies {cookies})
     }
     pub fn to_form_map(&self) -> Result<FormMap> {
-        if self.method == Method::Post {
+        if matches!(self.method, Method::Post | Method::Put | Method::Patch) {
             if let Some(body) = &self.body {
                 let buffer = body.as_slice();
                 let mut n = 0;
                 let mut map = HashMap::new();
                 while n < buffer.len() {
                     let mut m = find_byte(buffer, n, EQUAL);
+                    if m == buffer.len() {
+                        return Err(invalid());
+                    }
                     let key = get_string(&buffer[n..m])?;
                     n = m + 1;
                     m = find_byte(buffer, n, AMPERSAND);
@@ -858,20 +1252,17 @@ impl RawRequest {
         }
         Err(invalid())
     }
-    pub async fn new(buffer: &[u8], request_line: RequestLine, pool: DbPool, std_rng: Rng) -> Result<Self> {
-        let mut n = 0;
-        let headers = get_headers(buffer, &mut n)?;
-        n += 2;
-        let body = if n == buffer.len() {
-            None
-        } else {
-            Some(Body {body: buffer[n..].to_vec()})
-        };
+    pub fn new(request_line: RequestLine, headers: Headers, body: Option<Body>, pool: DbPool, std_rng: Rng) -> Result<Self> {
         let cookies = Self::get_cookies(&headers)?;
         let params = Parameters::new();
+        let query = match request_line.url.split_once('?') {
+            Some((_, qs)) => Query::parse(qs)?,
+            None => Query::new(),
+        };
         Ok(Self {
             method: request_line.method,
             url: request_line.url,
+            query,
             headers,
             body,
             cookies,
@@ -879,6 +1270,7 @@ impl RawRequest {
             pool,
             std_rng,
             valid_token: false,
+            extensions: Extensions::new(),
         })
     }
     pub fn cookies_mut(&mut self) -> &mut Cookies {
@@ -890,12 +1282,25 @@ impl RawRequest {
     pub fn rng(&self) -> &Rng {
         &self.std_rng
     }
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+    pub fn extensions_mut(&mut self) -> &mut Extensions {
+        &mut self.extensions
+    }
     pub fn valid_token(&self) -> bool {
         self.valid_token
     }
     pub fn valid_token_mut(&mut self) -> &mut bool {
         &mut self.valid_token
     }
+    /// The URL without its query string.
+    pub fn path(&self) -> &str {
+        self.url.split_once('?').map_or(&self.url, |(path, _)| path)
+    }
+    pub fn query(&self) -> &Query {
+        &self.query
+    }
     pub fn url(&self) -> &String {
         &self.url
     }
@@ -910,8 +1315,6 @@ pub trait BaseUser: Record<Pk = BigInt> + Send + Sync {
     fn is_auth(&self) -> bool;
 }
 
-pub trait BaseMiddleware: Sync + Send {}
-
 pub struct TokenRef<'a> {
     secret: &'a str,
 }
@@ -937,3 +1340,47 @@ pub trait CsrfDefense {
     fn token(&self) -> Result<TokenRef>;
     fn check_token(&mut self) -> Result<FormMap>;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Response, Status, BodyStream, Extensions, content_disposition};
+
+    #[test]
+    fn extensions() {
+        #[derive(Clone, Debug, PartialEq)]
+        struct Started(u64);
+
+        let mut extensions = Extensions::new();
+        assert_eq!(extensions.insert(Started(1)), None);
+        assert_eq!(extensions.insert(Started(2)), Some(Started(1)));
+        extensions.get_mut::<Started>().unwrap().0 += 1;
+        let copy = extensions.clone();
+        assert_eq!(extensions.remove::<Started>(), Some(Started(3)));
+        assert_eq!(extensions.get::<Started>(), None);
+        assert_eq!(copy.get::<Started>(), Some(&Started(3)));
+        assert_eq!(copy.get::<u64>(), None);
+    }
+
+    #[test]
+    fn response_framing() {
+        let mut response = Response::redirect("/login");
+        response.headers_mut().append("Set-Cookie".to_string(), "a=1".to_string());
+        response.headers_mut().append("Set-Cookie".to_string(), "b=2".to_string());
+        let bytes = String::from_utf8(response.into_bytes()).unwrap();
+        assert_eq!(bytes, "HTTP/1.1 303 See Other\r\nLocation: /login\r\nSet-Cookie: a=1\r\nSet-Cookie: b=2\r\nContent-Length: 0\r\n\r\n");
+
+        let bytes = Response::content(Status::NoContent, "text/plain", b"ignored".to_vec()).into_bytes();
+        assert_eq!(bytes, b"HTTP/1.1 204 No Content\r\nContent-Type: text/plain\r\n\r\n");
+
+        let body = BodyStream::from_reader(&b"a,b\n"[..]);
+        let bytes = Response::stream(Status::Ok, "text/csv", body.clone()).into_bytes();
+        assert_eq!(bytes, b"HTTP/1.1 200 OK\r\nContent-Type: text/csv\r\nTransfer-Encoding: chunked\r\n\r\n");
+        let bytes = Response::stream(Status::Ok, "text/csv", body.clone().with_len(4)).into_bytes();
+        assert_eq!(bytes, b"HTTP/1.1 200 OK\r\nContent-Type: text/csv\r\nContent-Length: 4\r\n\r\n");
+        assert!(body.take().is_some());
+        assert!(body.take().is_none());
+
+        assert_eq!(content_disposition("attachment", "report.csv"), "attachment; filename=\"report.csv\"; filename*=UTF-8''report.csv");
+        assert_eq!(content_disposition("inline", "naïve \"q\".txt"), "inline; filename=\"na_ve _q_.txt\"; filename*=UTF-8''na%C3%AFve%20%22q%22.txt");
+    }
+}
@@ -0,0 +1,207 @@
+//! Runs against an in-memory sqlite database, or the database in
+//! `ANANSI_TEST_DATABASE_URL`, such as `postgres://postgres@localhost/postgres`:
+//!
+//! `cargo test -p anansi-core --features postgres --test database`
+//!
+//! Without the variable, the postgres and mysql backends skip these tests.
+
+extern crate anansi_core as anansi;
+
+mod init {
+    pub const APP_NAME: &str = "dbtest";
+}
+
+mod records {
+    use anansi::records::{BigInt, Boolean, DateTime, ForeignKey, ManyToMany, Text, VarChar};
+    use anansi::Record;
+
+    #[derive(Clone, Debug, Record)]
+    pub struct Note {
+        #[field(primary_key = "true", default_fn = "anansi::records::generate_id")]
+        pub id: BigInt,
+        pub user: VarChar<40>,
+        pub body: Text,
+        pub done: Boolean,
+        pub created: DateTime,
+    }
+
+    impl<B: anansi::web::BaseRequest> anansi::records::Relate<B> for Note {}
+
+    #[derive(Clone, Debug, Record)]
+    pub struct Author {
+        #[field(primary_key = "true", default_fn = "anansi::records::generate_id")]
+        pub id: BigInt,
+        pub name: VarChar<40>,
+    }
+
+    impl<B: anansi::web::BaseRequest> anansi::records::Relate<B> for Author {}
+
+    #[derive(Clone, Debug, Record)]
+    pub struct Tag {
+        #[field(primary_key = "true", default_fn = "anansi::records::generate_id")]
+        pub id: BigInt,
+        pub name: VarChar<40>,
+    }
+
+    impl<B: anansi::web::BaseRequest> anansi::records::Relate<B> for Tag {}
+
+    #[derive(Clone, Debug, Record)]
+    pub struct Series {
+        #[field(primary_key = "true", default_fn = "anansi::records::generate_id")]
+        pub id: BigInt,
+        pub name: VarChar<40>,
+    }
+
+    impl<B: anansi::web::BaseRequest> anansi::records::Relate<B> for Series {}
+
+    #[derive(Record)]
+    pub struct Book {
+        #[field(primary_key = "true", default_fn = "anansi::records::generate_id")]
+        pub id: BigInt,
+        pub author: ForeignKey<Author>,
+        pub series: Option<ForeignKey<Series>>,
+        pub title: VarChar<40>,
+        pub tags: ManyToMany<Tag>,
+    }
+
+    impl<B: anansi::web::BaseRequest> anansi::records::Relate<B> for Book {}
+}
+
+use anansi::db::{DbPool, Insert};
+use anansi::dialect::{Dialect, DIALECT};
+use anansi::migrations::CreateRecord;
+use anansi::records::{generate_id, BigInt, Boolean, DataType, DateTime, ForeignKey, Record, Text, VarChar};
+use records::{book, note, Author, Book, Note, Series, Tag};
+
+async fn pool(name: &str) -> Option<DbPool> {
+    let url = match std::env::var("ANANSI_TEST_DATABASE_URL") {
+        Ok(url) => url,
+        Err(_) if DIALECT == Dialect::Sqlite => format!("sqlite:file:{}?mode=memory&cache=shared", name),
+        Err(_) => {
+            eprintln!("ANANSI_TEST_DATABASE_URL is not set, skipping");
+            return None;
+        },
+    };
+    let pool = DbPool::from_url(&url).await.expect("could not connect");
+    pool.bootstrap().await.expect("could not create the bootstrap tables");
+    Some(pool)
+}
+
+#[tokio::test]
+async fn queries() {
+    let pool = match pool("queries").await {
+        Some(pool) => pool,
+        None => return,
+    };
+    pool.query("DROP TABLE IF EXISTS dbtest_note").await.unwrap();
+    let create = CreateRecord {
+        prefix: "dbtest",
+        name: "note",
+        fields: vec![
+            ("id", BigInt::field().primary_key()),
+            ("user", VarChar::<40>::field()),
+            ("body", Text::field()),
+            ("done", Boolean::field()),
+            ("created", DateTime::field()),
+        ],
+    };
+    pool.query(&create.to_string()).await.unwrap();
+
+    let created = DateTime::from_val("2022-09-01 12:30:00".to_string()).unwrap();
+    let first = Note::new(VarChar::from("ann".to_string()).unwrap(), Text::from("Hello, 100% O'Brien".to_string()), Boolean::new(false), created);
+    first.clone().raw_save(&pool).await.unwrap();
+    Note::new(VarChar::from("bob".to_string()).unwrap(), Text::from("hello_world".to_string()), Boolean::new(false), created).raw_save(&pool).await.unwrap();
+
+    let mut found = Note::whose(note::user().eq("ann")).raw_get(&pool).await.unwrap();
+    assert_eq!(found.id, first.id);
+    assert_eq!(found.body.as_str(), "Hello, 100% O'Brien");
+    assert_eq!(found.created.to_string(), "2022-09-01 12:30:00");
+
+    assert_eq!(Note::count().whose(note::body().icontains("HELLO")).raw_get(&pool).await.unwrap(), 2);
+    assert_eq!(Note::count().whose(note::body().contains("100%")).raw_get(&pool).await.unwrap(), 1);
+    assert_eq!(Note::count().whose(note::body().contains("o_w")).raw_get(&pool).await.unwrap(), 1);
+    if DIALECT == Dialect::Postgres {
+        assert_eq!(Note::count().whose(note::body().starts_with("hello")).raw_get(&pool).await.unwrap(), 1);
+    }
+    assert_eq!(Note::count().whose(note::body().ends_with("O'Brien")).raw_get(&pool).await.unwrap(), 1);
+
+    let tricky = "back\\slash ' $1 ?";
+    Note::new(VarChar::from("dan".to_string()).unwrap(), Text::from(tricky.to_string()), Boolean::new(false), created).raw_save(&pool).await.unwrap();
+    assert_eq!(Note::whose(note::body().eq(tricky)).raw_get(&pool).await.unwrap().user.as_str(), "dan");
+    assert_eq!(Note::count().whose(note::body().contains("\\slash")).raw_get(&pool).await.unwrap(), 1);
+    assert_eq!(Note::count().whose(note::user().is_in(&vec!["ann", "dan"])).raw_get(&pool).await.unwrap(), 2);
+    assert_eq!(Note::count().whose(note::user().iexact("DAN")).and(note::done().eq(Boolean::new(false))).raw_get(&pool).await.unwrap(), 1);
+
+    found.done = Boolean::new(true);
+    found.raw_update(&pool).await.unwrap();
+    assert_eq!(Note::count().whose(note::done().eq(Boolean::new(true))).raw_get(&pool).await.unwrap(), 1);
+
+    let id = generate_id();
+    let row = Insert::<Note>::new("dbtest_note", &["id", "user", "body", "done", "created"])
+        .value(&id).value(&VarChar::<40>::from("cat".to_string()).unwrap()).value(&Text::from("x".to_string())).value(&Boolean::new(true)).value(&created)
+        .raw_save_returning(&["id", "user"], &pool).await.unwrap();
+    assert_eq!(row.try_get::<i64>("id").unwrap(), id.as_i64());
+    assert_eq!(row.try_get::<String>("user").unwrap(), "cat");
+
+    pool.query("DROP TABLE dbtest_note").await.unwrap();
+}
+
+#[tokio::test]
+async fn eager_loading() {
+    let pool = match pool("eager_loading").await {
+        Some(pool) => pool,
+        None => return,
+    };
+    for table in ["dbtest_book_tag", "dbtest_book", "dbtest_author", "dbtest_series", "dbtest_tag"] {
+        pool.query(&format!("DROP TABLE IF EXISTS {}", table)).await.unwrap();
+    }
+    for (name, fields) in [
+        ("author", vec![("id", BigInt::field().primary_key()), ("name", VarChar::<40>::field())]),
+        ("series", vec![("id", BigInt::field().primary_key()), ("name", VarChar::<40>::field())]),
+        ("tag", vec![("id", BigInt::field().primary_key()), ("name", VarChar::<40>::field())]),
+        ("book", vec![("id", BigInt::field().primary_key()), ("author", BigInt::field().foreign_key("dbtest", "author", "id")), ("series", BigInt::field().null().foreign_key("dbtest", "series", "id")), ("title", VarChar::<40>::field())]),
+        ("book_tag", vec![("book", BigInt::field().foreign_key("dbtest", "book", "id")), ("tag", BigInt::field().foreign_key("dbtest", "tag", "id"))]),
+    ] {
+        pool.query(&CreateRecord {prefix: "dbtest", name, fields}.to_string()).await.unwrap();
+    }
+
+    let name = |s: &str| VarChar::<40>::from(s.to_string()).unwrap();
+    let ann = Author::new(name("ann")).raw_save(&pool).await.unwrap();
+    let bob = Author::new(name("bob")).raw_save(&pool).await.unwrap();
+    let rust = Tag::new(name("rust")).raw_save(&pool).await.unwrap();
+    let web = Tag::new(name("web")).raw_save(&pool).await.unwrap();
+    let saga = Series::new(name("saga")).raw_save(&pool).await.unwrap();
+    let first = Book::new(ForeignKey::new(&ann), Some(ForeignKey::new(&saga)), name("first")).raw_save(&pool).await.unwrap();
+    Book::new(ForeignKey::new(&ann), None, name("second")).raw_save(&pool).await.unwrap();
+    let third = Book::new(ForeignKey::new(&bob), Some(ForeignKey::new(&saga)), name("third")).raw_save(&pool).await.unwrap();
+    for (b, t) in [(&first, &rust), (&first, &web), (&third, &web)] {
+        Insert::<Book>::new("dbtest_book_tag", &["book", "tag"]).value(&b.id).value(&t.id).raw_save(&pool).await.unwrap();
+    }
+
+    let mut books = Book::order_by(book::title().asc()).limit(10).select_related(book::author(), |b| &mut b.author).raw_query(&pool).await.unwrap();
+    let authors: Vec<&str> = books.iter().map(|b| b.author.cached().unwrap().name.as_str()).collect();
+    assert_eq!(authors, ["ann", "ann", "bob"]);
+
+    let in_series = Book::order_by(book::title().asc()).select_related(book::series(), |b| &mut b.series).limit(10).raw_query(&pool).await.unwrap();
+    let series: Vec<Option<&str>> = in_series.iter().map(|b| b.series.as_ref().and_then(|s| s.cached()).map(|s| s.name.as_str())).collect();
+    assert_eq!(series, [Some("saga"), None, Some("saga")]);
+    let second = Book::whose(book::title().eq("second")).select_related(book::author(), |b| &mut b.author).raw_get(&pool).await.unwrap();
+    assert_eq!(second.author.cached().unwrap().name.as_str(), "ann");
+    assert!(second.series.is_none());
+
+    books.raw_prefetch_related(&pool, |b| &mut b.tags).await.unwrap();
+    let tags: Vec<Vec<&str>> = books.iter().map(|b| b.tags.cached().unwrap().iter().map(|t| t.name.as_str()).collect()).collect();
+    assert_eq!(tags.iter().map(|t| t.len()).collect::<Vec<_>>(), [2, 0, 1]);
+    assert_eq!(tags[2], ["web"]);
+
+    let mut authors = Author::order_by(records::author::name().asc()).limit(10).raw_query(&pool).await.unwrap();
+    authors.raw_prefetch_children(&pool, book::author(), |b| &b.author).await.unwrap();
+    let counts: Vec<usize> = authors.iter().map(|a| authors.children(book::author(), a).len()).collect();
+    assert_eq!(counts, [2, 1]);
+    assert_eq!(authors.children(book::author(), &authors[1])[0].title.as_str(), "third");
+    assert!(authors.children(book::series(), &authors[0]).is_empty());
+
+    for table in ["dbtest_book_tag", "dbtest_book", "dbtest_author", "dbtest_series", "dbtest_tag"] {
+        pool.query(&format!("DROP TABLE {}", table)).await.unwrap();
+    }
+}
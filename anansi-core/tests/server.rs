@@ -0,0 +1,295 @@
+//! Drives `handle_connection` with requests written to an in-memory stream.
+
+extern crate anansi_core as anansi;
+
+mod init {
+    pub const APP_NAME: &str = "servertest";
+}
+
+mod records {
+    use anansi::records::{BigInt, VarChar};
+    use anansi::Record;
+
+    #[derive(Clone, Debug, Record)]
+    pub struct Visitor {
+        #[field(primary_key = "true", default_fn = "anansi::records::generate_id")]
+        pub id: BigInt,
+        pub name: VarChar<40>,
+    }
+
+    impl<B: anansi::web::BaseRequest> anansi::records::Relate<B> for Visitor {}
+
+    impl anansi::web::BaseUser for Visitor {
+        type Name = VarChar<40>;
+        fn username(&self) -> &VarChar<40> {
+            &self.name
+        }
+        fn is_auth(&self) -> bool {
+            false
+        }
+    }
+}
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::watch;
+
+use anansi::admin_site::{AdminEntry, AdminRef, AdminSite, RecordEntry};
+use anansi::db::DbPool;
+use anansi::dialect::{Dialect, DIALECT};
+use anansi::query::Query;
+use anansi::records::{BigInt, DateTime, VarChar};
+use anansi::router::{Router, flatten, get_capture, reverse_urls};
+use anansi::server::{Rng, ServerConfig, Shared, handle_connection};
+use anansi::web::{BaseRequest, Body, BodyStream, Cookies, FormMap, Headers, Method, Parameters, RawRequest, Response, Result, Route, Status, View, get, post};
+use records::Visitor;
+
+#[derive(Clone, Debug)]
+struct Request {
+    raw: RawRequest,
+}
+
+#[async_trait::async_trait]
+impl BaseRequest for Request {
+    type Usr = Visitor;
+
+    async fn new(raw: RawRequest, urls: Arc<HashMap<usize, Vec<String>>>, admin: AdminRef<Self>) -> Result<Self> {
+        Ok(<Self as BaseRequest>::from(raw, urls, admin))
+    }
+    fn method(&self) -> &Method {
+        &self.raw.method
+    }
+    fn url(&self) -> &String {
+        &self.raw.url
+    }
+    fn query(&self) -> &Query {
+        &self.raw.query
+    }
+    fn headers(&self) -> &Headers {
+        &self.raw.headers
+    }
+    fn body(&self) -> &Option<Body> {
+        &self.raw.body
+    }
+    fn cookies(&self) -> &Cookies {
+        &self.raw.cookies
+    }
+    fn params(&self) -> &Parameters {
+        &self.raw.params
+    }
+    fn params_mut(&mut self) -> &mut Parameters {
+        &mut self.raw.params
+    }
+    fn raw(&self) -> &RawRequest {
+        &self.raw
+    }
+    fn raw_mut(&mut self) -> &mut RawRequest {
+        &mut self.raw
+    }
+    fn to_form_map(&self) -> Result<FormMap> {
+        self.raw.to_form_map()
+    }
+    fn from(mut raw: RawRequest, _urls: Arc<HashMap<usize, Vec<String>>>, _admin: AdminRef<Self>) -> Self {
+        raw.extensions_mut().insert(Visitor {id: BigInt::new(0), name: VarChar::from("guest".to_string()).unwrap()});
+        Self {raw}
+    }
+    fn user(&self) -> &Visitor {
+        self.raw.extensions().get().unwrap()
+    }
+    fn user_mut(&mut self) -> &mut Visitor {
+        self.raw.extensions_mut().get_mut().unwrap()
+    }
+    fn to_raw(self) -> RawRequest {
+        self.raw
+    }
+}
+
+struct Site {
+    entries: Vec<AdminEntry<Request>>,
+    urls: Vec<(&'static str, View<Request>)>,
+}
+
+impl AdminSite<Request> for Site {
+    fn new() -> Self {
+        Self {entries: vec![], urls: vec![]}
+    }
+    fn register(&mut self, app_name: String, entry: RecordEntry<Request>) {
+        self.entries.push(AdminEntry::new(app_name, vec![entry]));
+    }
+    fn admin_entries(&self) -> &Vec<AdminEntry<Request>> {
+        &self.entries
+    }
+    fn urls(&self) -> &Vec<(&'static str, View<Request>)> {
+        &self.urls
+    }
+    fn urls_mut(&mut self) -> &mut Vec<(&'static str, View<Request>)> {
+        &mut self.urls
+    }
+}
+
+type Reply = Pin<Box<dyn Future<Output = Result<Response>> + Send>>;
+
+fn reply(body: &'static str) -> Reply {
+    Box::pin(async move { Ok(Response::new(Status::Ok, body.as_bytes().to_vec())) })
+}
+
+fn not_found(_req: Request) -> Reply {
+    Box::pin(async { Ok(Response::from_status(Status::NotFound)) })
+}
+
+fn new_post(_req: Request) -> Reply {
+    reply("new post")
+}
+
+fn update_post(_req: Request) -> Reply {
+    reply("updated post")
+}
+
+fn edit_post(_req: Request) -> Reply {
+    reply("edit post")
+}
+
+fn blog_index(_req: Request) -> Reply {
+    reply("blog")
+}
+
+/// The guards run by `nested_imports`, in order.
+static GUARDS: Mutex<Vec<&str>> = Mutex::new(vec![]);
+
+fn api_guard(_req: &Request) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+    Box::pin(async { GUARDS.lock().unwrap().push("api"); Ok(()) })
+}
+
+fn blog_guard(_req: &Request) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+    Box::pin(async { GUARDS.lock().unwrap().push("blog"); Ok(()) })
+}
+
+fn posts_guard(_req: &Request) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+    Box::pin(async { GUARDS.lock().unwrap().push("posts"); Ok(()) })
+}
+
+static POSTS: &[Route<Request>] = &[get("/{id:int}/edit", edit_post)];
+static BLOG: &[Route<Request>] = &[get("/", blog_index), Route::Import(("/posts/", POSTS, &[posts_guard]))];
+static API: &[Route<Request>] = &[Route::Import(("blog", BLOG, &[blog_guard]))];
+
+fn broken(_req: Request) -> Reply {
+    Box::pin(async { panic!("view failed") })
+}
+
+fn endless(_req: Request) -> Reply {
+    Box::pin(async { Ok(Response::stream(Status::Ok, "text/plain", BodyStream::from_reader(tokio::io::repeat(b'x')))) })
+}
+
+/// Server state for `routes`, along with the senders of its shutdown and cutoff
+/// signals, or `None` if the tests have no database to run against.
+async fn shared(routes: Vec<Route<Request>>) -> Option<(Arc<Shared<Request>>, [watch::Sender<bool>; 2])> {
+    if DIALECT != Dialect::Sqlite {
+        eprintln!("the server tests run on sqlite, skipping");
+        return None;
+    }
+    let pool = DbPool::from_url("sqlite::memory:").await.expect("could not connect");
+    let router = Router::new(routes, not_found, Response::from_status(Status::InternalServerError), "/login".to_string(), HashMap::new(), None).unwrap();
+    let site: AdminRef<Request> = Arc::new(Mutex::new(Site::new()));
+    let (notify, shutdown) = watch::channel(false);
+    let (cut_off, cutoff) = watch::channel(false);
+    let shared = Arc::new(Shared {
+        urls: Arc::new(HashMap::new()),
+        pool,
+        std_rng: Rng::new("servertest"),
+        router,
+        middleware: vec![],
+        timer: Arc::new(Mutex::new(DateTime::now())),
+        site,
+        config: ServerConfig::default(),
+        shutdown,
+        cutoff,
+    });
+    Some((shared, [notify, cut_off]))
+}
+
+/// Sends `request` on a new connection, returning everything written back
+/// and the result of `handle_connection`.
+async fn exchange(shared: Arc<Shared<Request>>, request: &str) -> (String, Result<()>) {
+    let (mut client, server) = tokio::io::duplex(64 * 1024);
+    let connection = tokio::spawn(handle_connection(server, None, shared));
+    client.write_all(request.as_bytes()).await.unwrap();
+    let mut output = String::new();
+    client.read_to_string(&mut output).await.unwrap();
+    (output, connection.await.unwrap())
+}
+
+#[tokio::test]
+async fn method_of_best_match() {
+    let (shared, _signals) = match shared(vec![get("/post/new", new_post), post("/post/{id}", update_post)]).await {
+        Some(shared) => shared,
+        None => return,
+    };
+    let (output, _) = exchange(shared.clone(), "POST /post/new HTTP/1.1\r\nHost: x\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").await;
+    assert!(output.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"), "{:?}", output);
+    assert!(output.contains("Allow: GET, HEAD, OPTIONS\r\n"), "{:?}", output);
+
+    let (output, _) = exchange(shared, "POST /post/7 HTTP/1.1\r\nHost: x\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").await;
+    assert!(output.starts_with("HTTP/1.1 200 OK\r\n"), "{:?}", output);
+    assert!(output.ends_with("updated post"), "{:?}", output);
+}
+
+#[tokio::test]
+async fn view_panics() {
+    let (shared, _signals) = match shared(vec![get("/broken", broken), get("/post/new", new_post)]).await {
+        Some(shared) => shared,
+        None => return,
+    };
+    let (output, result) = exchange(shared, "GET /broken HTTP/1.1\r\nHost: x\r\n\r\nGET /post/new HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n").await;
+    assert!(result.is_ok());
+    assert!(output.starts_with("HTTP/1.1 500 Internal Server Error\r\n"), "{:?}", output);
+    assert!(output.contains("HTTP/1.1 200 OK\r\n"), "{:?}", output);
+    assert!(output.ends_with("new post"), "{:?}", output);
+}
+
+#[tokio::test]
+async fn write_failures() {
+    let (shared, _signals) = match shared(vec![get("/post/new", new_post), get("/endless", endless)]).await {
+        Some(shared) => shared,
+        None => return,
+    };
+    let (mut client, server) = tokio::io::duplex(1024);
+    let connection = tokio::spawn(handle_connection(server, None, shared.clone()));
+    client.write_all(b"GET /post/new HTTP/1.1\r\nHost: x\r\n\r\n").await.unwrap();
+    drop(client);
+    let error = connection.await.unwrap().unwrap_err();
+    assert_eq!(error.downcast_ref::<std::io::Error>().unwrap().kind(), std::io::ErrorKind::BrokenPipe);
+
+    let (mut client, server) = tokio::io::duplex(1024);
+    let connection = tokio::spawn(handle_connection(server, None, shared));
+    client.write_all(b"GET /endless HTTP/1.1\r\nHost: x\r\n\r\n").await.unwrap();
+    let mut head = [0; 17];
+    client.read_exact(&mut head).await.unwrap();
+    assert_eq!(&head, b"HTTP/1.1 200 OK\r\n");
+    drop(client);
+    let error = connection.await.unwrap().unwrap_err();
+    assert_eq!(error.downcast_ref::<std::io::Error>().unwrap().kind(), std::io::ErrorKind::BrokenPipe);
+}
+
+#[tokio::test]
+async fn nested_imports() {
+    let routes = vec![Route::Import(("/api/", API, &[api_guard])), get("/old/{id:int}/edit", edit_post)];
+    let urls: Vec<String> = flatten("", &routes, &[]).into_iter().map(|endpoint| endpoint.url).collect();
+    assert_eq!(urls, ["/api/blog/", "/api/blog/posts/{id:int}/edit", "/old/{id:int}/edit"]);
+
+    let mut reverse = HashMap::new();
+    reverse_urls(&routes, &mut reverse).unwrap();
+    assert_eq!(reverse[&(edit_post as View<Request> as usize)], get_capture("/api/blog/posts/{id:int}/edit").unwrap());
+    assert_eq!(reverse[&(blog_index as View<Request> as usize)], get_capture("/api/blog/").unwrap());
+
+    let (shared, _signals) = match shared(routes).await {
+        Some(shared) => shared,
+        None => return,
+    };
+    let (output, _) = exchange(shared, "GET /api/blog/posts/7/edit HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n").await;
+    assert!(output.ends_with("edit post"), "{:?}", output);
+    assert_eq!(*GUARDS.lock().unwrap(), ["api", "blog", "posts"]);
+}
@@ -95,6 +95,7 @@ pub fn record_macro_derive(input: proc_macro::TokenStream) -> proc_macro::TokenS
             type Pk = #pt;
             const NAME: &'static str = #name_string;
             const PK_NAME: &'static str = #pk_name;
+            const COLUMNS: &'static [&'static str] = &[#(#members),*];
             fn pk(&self) -> #pt {
                 self.#pk_id.clone()
             }
@@ -131,7 +132,7 @@ pub fn record_macro_derive(input: proc_macro::TokenStream) -> proc_macro::TokenS
                 Ok(mv)
             }
             fn order_by(w: anansi::db::OrderByArg<Self>) -> anansi::db::OrderBy<Self> {
-                anansi::db::OrderBy::from(anansi::db::Builder::select(&[#(#members),*], #table).order_by().push_str(&w.builder().val()))
+                anansi::db::OrderBy::from(anansi::db::Builder::select(&[#(#members),*], #table).order_by().append(w.builder()))
             }
             fn table_name() -> String {
                 #table_name
@@ -771,7 +772,7 @@ fn record_init(mname: &Ident, fname: &str, data: &Data, pkd: &mut PkData, member
                                     let (u, _) = u.rsplit_once('>').unwrap();
                                     let v: syn::Type = syn::parse_str(&format!("{}{}", t, u)).unwrap();
                                     fty = v;
-                                } else {
+                                } else if segment != "ManyToMany" {
                                     pkd.member_type.push((false, name.as_ref().unwrap().clone(), quote! {#fty}));
                                 }
                                 match segment.as_str() {
@@ -791,7 +792,7 @@ fn record_init(mname: &Ident, fname: &str, data: &Data, pkd: &mut PkData, member
                                     },
                                     "BigInt" => {
                                         let q = quote! {pub fn #name() -> anansi::db::Column<#mname, anansi::records::BigInt> {anansi::db::Column::new(#lowcolumn)}};
-                                        let q2 = quote! {pub fn #name(self) -> anansi::db::Column<F, anansi::records::BigInt> {anansi::db::Column::from(self.b.push_str(#column))}};
+                                        let q2 = quote! {pub fn #name(self) -> anansi::db::Column<F, anansi::records::BigInt> {anansi::db::Column::from(self.b.push_str(&anansi::db::quote(#column)))}};
                                         fv.push(q);
                                         if is_pk {
                                             let q3 = quote! {pub fn pk() -> anansi::db::Column<#mname, anansi::records::BigInt> {anansi::db::Column::new(#lowcolumn)}};
@@ -806,7 +807,7 @@ fn record_init(mname: &Ident, fname: &str, data: &Data, pkd: &mut PkData, member
                                     "ForeignKey" => {
                                         if pkd.ty == "BigInt" {
                                             let q = quote! {pub fn #name() -> anansi::db::Column<#mname, anansi::records::BigInt> {anansi::db::Column::new(#lowcolumn)}};
-                                            let q2 = quote! {pub fn #name(self) -> anansi::db::Column<F, anansi::records::BigInt> {anansi::db::Column::from(self.b.push_str(#column))}};
+                                            let q2 = quote! {pub fn #name(self) -> anansi::db::Column<F, anansi::records::BigInt> {anansi::db::Column::from(self.b.push_str(&anansi::db::quote(#column)))}};
 
                                             fv.push(q);
                                             fv2.push(q2);
@@ -830,6 +831,7 @@ fn record_init(mname: &Ident, fname: &str, data: &Data, pkd: &mut PkData, member
                                                             None
                                                         }
                                                     }
+                                                    ,
                                                 }
                                             };
                                             qs
@@ -839,7 +841,7 @@ fn record_init(mname: &Ident, fname: &str, data: &Data, pkd: &mut PkData, member
                                     },
                                     "DateTime" => {
                                         let q = quote! {pub fn #name<'a>() -> anansi::db::Column<#mname, anansi::records::DateTime> {anansi::db::Column::new(#lowcolumn)}};
-                                        let q2 = quote! {pub fn #name<'a>(self) -> anansi::db::Column<F, anansi::records::DateTime> {anansi::db::Column::from(self.b.push_str(#column))}};
+                                        let q2 = quote! {pub fn #name<'a>(self) -> anansi::db::Column<F, anansi::records::DateTime> {anansi::db::Column::from(self.b.push_str(&anansi::db::quote(#column)))}};
                                         fv.push(q);
                                         fv2.push(q2);
                                         members.push(member);
@@ -849,7 +851,7 @@ fn record_init(mname: &Ident, fname: &str, data: &Data, pkd: &mut PkData, member
                                     },
                                     "Boolean" => {
                                         let q = quote! {pub fn #name() -> anansi::db::Column<#mname, anansi::records::Boolean> {anansi::db::Column::new(#lowcolumn)}};
-                                        let q2 = quote! {pub fn #name(self) -> anansi::db::Column<F, anansi::records::Boolean> {anansi::db::Column::from(self.b.push_str(#column))}};
+                                        let q2 = quote! {pub fn #name(self) -> anansi::db::Column<F, anansi::records::Boolean> {anansi::db::Column::from(self.b.push_str(&anansi::db::quote(#column)))}};
                                         fv.push(q);
                                         fv2.push(q2);
                                         members.push(member);
@@ -859,7 +861,7 @@ fn record_init(mname: &Ident, fname: &str, data: &Data, pkd: &mut PkData, member
                                     },
                                     "VarChar" => {
                                         let q = quote! {pub fn #name<'a>() -> anansi::db::Column<#mname, anansi::records::#fty> {anansi::db::Column::new(#lowcolumn)}};
-                                        let q2 = quote! {pub fn #name<'a>(self) -> anansi::db::Column<F, anansi::records::#fty> {anansi::db::Column::from(self.b.push_str(#column))}};
+                                        let q2 = quote! {pub fn #name<'a>(self) -> anansi::db::Column<F, anansi::records::#fty> {anansi::db::Column::from(self.b.push_str(&anansi::db::quote(#column)))}};
                                         fv.push(q);
                                         fv2.push(q2);
                                         members.push(member);
@@ -883,7 +885,7 @@ fn record_init(mname: &Ident, fname: &str, data: &Data, pkd: &mut PkData, member
                                     },
                                     "Text" => {
                                         let q = quote! {pub fn #name<'a>() -> anansi::db::Column<#mname, anansi::records::Text> {anansi::db::Column::new(#lowcolumn)}};
-                                        let q2 = quote! {pub fn #name<'a>(self) -> anansi::db::Column<F, anansi::records::Text> {anansi::db::Column::from(self.b.push_str(#column))}};
+                                        let q2 = quote! {pub fn #name<'a>(self) -> anansi::db::Column<F, anansi::records::Text> {anansi::db::Column::from(self.b.push_str(&anansi::db::quote(#column)))}};
                                         fv.push(q);
                                         fv2.push(q2);
                                         members.push(member);
@@ -921,7 +923,11 @@ fn record_init(mname: &Ident, fname: &str, data: &Data, pkd: &mut PkData, member
 
 #[proc_macro_attribute]
 pub fn base_view(_args: proc_macro::TokenStream, input: proc_macro::TokenStream) -> proc_macro::TokenStream {
-    let input = parse_macro_input!(input as ItemFn);
+    let mut input = parse_macro_input!(input as ItemFn);
+    if let Some(Typed(pat)) = input.sig.inputs.first_mut() {
+        let ty = &pat.ty;
+        *pat.ty = syn::parse_quote! {&#ty};
+    }
     let sig = &input.sig;
     let generics = &sig.generics;
     let fname = &sig.ident;
@@ -1445,7 +1451,7 @@ pub fn url(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
     let first = &input.first;
     let exprs = &input.exprs;
     let q = quote! {
-        #req.reverse(#first, &[#(&anansi::records::ToUrl::to_url(&#exprs)),*])
+        #req.reverse(#first, &[#(&anansi::records::ToUrl::to_url(&#exprs)),*])?
     };
     q.into()
 }
@@ -1469,29 +1475,7 @@ pub fn routes(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
     let q = quote! {
         pub const ROUTES: &[anansi::web::Route<crate::project::HttpRequest>] = &[#(#vars),*];
         pub fn app_url(hm: &mut std::collections::HashMap<usize, Vec<String>>) {
-            use crate::project::HttpRequest;
-            let mut v = vec![];
-            for route in ROUTES {
-                match route {
-                    anansi::web::Route::Path((url, f)) => {
-                        v.push(((*url).to_string(), *f));
-                    },
-                    anansi::web::Route::Import((url, r)) => {
-                        for rt in *r {
-                            match rt {
-                                anansi::web::Route::Path((u, f)) => {
-                                    v.push((format!("{}/{}", url, *u), *f));
-                                },
-                                _ => unimplemented!(),
-                            }
-                        }
-                    }
-                }
-            }
-            for (url, f) in v {
-                let cap = anansi::router::get_capture(&url).unwrap();
-                hm.insert(f as usize, cap);
-            }
+            anansi::router::reverse_urls::<crate::project::HttpRequest>(ROUTES, hm).unwrap();
         }
     };
     q.into()
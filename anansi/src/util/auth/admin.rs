@@ -60,7 +60,7 @@ impl<R: Request> AuthAdminView<R> {
     pub async fn login(mut req: R) -> Result<Response> {
         let form = handle!(UserLogin, ToRecord<R>, req, user, {
             req.auth_admin(&user).await?;
-            req.session().set_and_redirect(&req, BasicAdminSite::index)
+            req.session()?.set_and_redirect(&req, BasicAdminSite::index)
         })?.class("cred");
         render!("login")
     }
@@ -69,7 +69,7 @@ impl<R: Request> AuthAdminView<R> {
     pub async fn logout(mut req: R) -> Result<Response> {
         let title = "Log out";
         let form = handle!(req, R, {
-            req.session().delete(&req).await?;
+            req.session()?.delete(&req).await?;
             Ok(redirect!())
         })?;
         render!("logout")
@@ -85,7 +85,7 @@ where <<M as RecordAdmin<R>>::AdminForm as HasRecord>::Item: FromParams, <M as R
         let m_edit = Self::record_edit::<M>;
         let filters = Filter::whose(filter::table_name().eq(M::table_name())).limit(25).query(&req).await?;
         let search = if M::searchable() {
-            Some(AdminSearch::new().action(&req, Self::record_search::<M>))
+            Some(AdminSearch::new().action(&req, Self::record_search::<M>)?)
         } else {
             None
         };
@@ -124,7 +124,7 @@ where <<M as RecordAdmin<R>>::AdminForm as HasRecord>::Item: FromParams, <M as R
         let field_names = <M as RecordAdmin<R>>::field_names();
         let m_edit = Self::record_edit::<M>;
         let filters = Filter::whose(filter::table_name().eq(M::table_name())).limit(25).query(&req).await?;
-        let search = Some(search.action(&req, Self::record_search::<M>));
+        let search = Some(search.action(&req, Self::record_search::<M>)?);
         render!("record_index")
     }
 
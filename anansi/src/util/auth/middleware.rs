@@ -1,6 +1,7 @@
 use super::records::{User, BaseRelation};
 use super::super::sessions::middleware::Sessions;
-use anansi::web::{Result, BaseRequest};
+use anansi::web::{Result, BaseRequest, Response};
+use anansi::middleware::Middleware;
 
 #[async_trait::async_trait]
 pub trait Auth: Sessions + BaseRequest {
@@ -8,7 +9,7 @@ pub trait Auth: Sessions + BaseRequest {
         use anansi::records::Record;
         *self.user_mut().pk_mut() = user.pk();
         if BaseRelation::check("auth_group", 1, "member", self).await.is_ok() {
-            *self.session_data_mut().get_mut(User::KEY)? = serde_json::json!(user.pk().as_i64());
+            *self.session_data_mut()?.get_mut(User::KEY)? = serde_json::json!(user.pk().as_i64());
             self.update_session().await?;
             Ok(())
         } else {
@@ -19,7 +20,7 @@ pub trait Auth: Sessions + BaseRequest {
         use anansi::web::BaseUser;
         if user.is_auth() {
             use anansi::records::Record;
-            *self.session_data_mut().get_mut(User::KEY)? = serde_json::json!(user.pk().as_i64());
+            *self.session_data_mut()?.get_mut(User::KEY)? = serde_json::json!(user.pk().as_i64());
             self.update_session().await?;
             Ok(())
         } else {
@@ -27,3 +28,19 @@ pub trait Auth: Sessions + BaseRequest {
         }
     }
 }
+
+/// Loads the logged in user from the session, leaving guests as `User::guest()`.
+/// Must run after the session middleware.
+pub struct AuthMiddleware;
+
+#[async_trait::async_trait]
+impl<B: BaseRequest<Usr = User> + Sessions + 'static> Middleware<B> for AuthMiddleware {
+    async fn before(&self, req: &mut B) -> Result<Option<Response>> {
+        use anansi::records::{Record, BigInt, DataType};
+        let user_id = req.session_data()?.get(User::KEY)?.as_i64().ok_or_else(anansi::db::invalid)?;
+        if user_id != 0 {
+            *req.user_mut() = User::find(BigInt::from_val(user_id)?).raw_get(req.raw().pool()).await?;
+        }
+        Ok(None)
+    }
+}
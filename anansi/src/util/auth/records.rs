@@ -11,7 +11,7 @@ use pbkdf2::{
 use async_recursion::async_recursion;
 
 use anansi::web::{Result, BaseUser, BaseRequest, WebError, WebErrorKind};
-use anansi::db::{DbPool, DbRowVec, invalid};
+use anansi::db::{Arg, DbPool, DbRowVec, invalid};
 use anansi::records::{Record, BigInt, VarChar, Text, DataType};
 use anansi::{record, FromParams, ToUrl, Relate};
 
@@ -134,14 +134,15 @@ impl BaseRelation {
         }
         Ok(v)
     }
-    pub fn search(object_namespace: &str, object_key: i64, object_predicate: &str) -> String {
-        use anansi::records::ToSql;
-        let q = format!("SELECT * FROM {} WHERE object_key = {} AND object_predicate = {};", format!("{}tuple", object_namespace).to_sql(), object_key, object_predicate.to_sql());
-        q
+    pub fn search(object_namespace: &str, object_key: i64, object_predicate: &str) -> (String, Vec<Arg>) {
+        use anansi::dialect::DIALECT;
+        let q = format!("SELECT * FROM {} WHERE object_key = {} AND object_predicate = {};", anansi::db::quote(&format!("{}tuple", object_namespace)), DIALECT.placeholder(1), DIALECT.placeholder(2));
+        (q, vec![Arg::Int(object_key), Arg::Text(object_predicate.to_string())])
     }
     #[async_recursion]
     pub async fn check<B: BaseRequest>(object_namespace: &str, object_key: i64, object_predicate: &str, req: &B) -> anansi::web::Result<()> {
-        let rels = Self::from(req.raw().pool().query(&Self::search(object_namespace, object_key, object_predicate)).await?)?;
+        let (q, args) = Self::search(object_namespace, object_key, object_predicate);
+        let rels = Self::from(req.raw().pool().query_with(&q, args).await?)?;
         for rel in rels {
             match rel.subject_predicate {
                 None => {
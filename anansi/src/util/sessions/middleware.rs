@@ -1,10 +1,35 @@
-use anansi::web::Result;
+use anansi::web::{Result, BaseRequest, Response};
+use anansi::middleware::Middleware;
 use super::records::{Session, SessionData};
 
 #[async_trait::async_trait]
 pub trait Sessions {
-    fn session(&self) -> &Session;
-    fn session_data(&self) -> &SessionData;
-    fn session_data_mut(&mut self) -> &mut SessionData;
+    /// The session loaded by `SessionMiddleware`, failing if it has not run.
+    fn session(&self) -> Result<&Session>;
+    fn session_data(&self) -> Result<&SessionData>;
+    fn session_data_mut(&mut self) -> Result<&mut SessionData>;
+    fn set_session(&mut self, session: Session, session_data: SessionData);
     async fn update_session(&mut self) -> Result<()>;
 }
+
+/// Loads the session from the `st` cookie, starting a new one and redirecting
+/// back to the same URL when the cookie is missing or expired.
+pub struct SessionMiddleware;
+
+#[async_trait::async_trait]
+impl<B: BaseRequest + Sessions + 'static> Middleware<B> for SessionMiddleware {
+    async fn before(&self, req: &mut B) -> Result<Option<Response>> {
+        match Session::from_raw(req.raw_mut()).await {
+            Ok(session) => {
+                let session_data = session.to_data()?;
+                req.set_session(session, session_data);
+                Ok(None)
+            },
+            Err(_) => {
+                let raw = req.raw();
+                let session = Session::gen(raw.pool(), raw.rng()).await?;
+                Ok(Some(Response::redirect(&raw.url).set_persistent("st", &session.secret, &session.expires)))
+            },
+        }
+    }
+}